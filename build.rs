@@ -1,3 +1,49 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const MIGRATIONS_DIR: &str = "src/db/migrations";
+
 fn main() {
     minijinja_embed::embed_templates!("src/templates");
+    embed_migrations();
+}
+
+// Migrations are named like 3-add-media.sql, and are embedded in order of their number
+fn embed_migrations() {
+    println!("cargo:rerun-if-changed={}", MIGRATIONS_DIR);
+
+    let mut migrations: Vec<(usize, String)> = fs::read_dir(MIGRATIONS_DIR)
+        .expect("Failed to read the migrations directory")
+        .map(|entry| entry.expect("Failed to read migration").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let number = name
+                .split_once('-')
+                .and_then(|(number, _)| number.parse().ok())
+                .unwrap_or_else(|| panic!("Migration {} must start with its number, e.g. 1-name.sql", name));
+            (number, name)
+        })
+        .collect();
+    migrations.sort();
+
+    for (index, (number, name)) in migrations.iter().enumerate() {
+        if *number != index {
+            panic!("Migration {} is out of sequence; expected number {}", name, index);
+        }
+    }
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let entries: String = migrations
+        .iter()
+        .map(|(_, name)| {
+            let path = Path::new(&manifest_dir).join(MIGRATIONS_DIR).join(name);
+            format!("    ({:?}, include_str!({:?})),\n", name, path.to_string_lossy())
+        })
+        .collect();
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("migrations.rs");
+    let source = format!("pub static MIGRATIONS: &[(&str, &str)] = &[\n{}];\n", entries);
+    fs::write(out_path, source).expect("Failed to write the migrations list");
 }
@@ -1,6 +1,9 @@
 use crate::server::error::{bad_request, ServerError};
 use std::fmt::Display;
 
+pub mod actor_cache;
+pub mod content;
+pub mod delivery;
 pub mod objects;
 pub mod requests;
 pub mod signature;
@@ -41,10 +44,6 @@ impl FullHandle {
 
 impl Display for FullHandle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            format!("@{}@{}", self.preferred_username, self.host)
-        )
+        write!(f, "@{}@{}", self.preferred_username, self.host)
     }
 }
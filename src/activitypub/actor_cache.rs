@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use hyper::Uri;
+use rusqlite::{Connection, OptionalExtension, Params};
+use serde_json::json;
+use tracing::{debug, error, warn};
+
+use crate::activitypub::content::sanitize_html;
+use crate::activitypub::delivery::get_domain;
+use crate::activitypub::objects::actor::{Actor, LinkType};
+use crate::activitypub::requests::{get_actor, get_webfinger};
+use crate::activitypub::FullHandle;
+use crate::server::error::{bad_gateway, bad_request, map_bad_gateway};
+use crate::server::server_request::CurrentProfile;
+use crate::server::server_response::InternalResult;
+use crate::shutdown::Shutdown;
+use crate::sqlite::pool::Pool;
+
+// How long a fetched actor is trusted before we go back to their server for a fresh copy
+const ACTOR_TTL_SECS: i64 = 60 * 60 * 24;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 10);
+// Keeps each pass short, so a big following list gets spread out over several passes
+const REFRESH_BATCH_SIZE: i64 = 20;
+
+enum CachedActor {
+    Fresh(Actor),
+    Stale(Actor),
+    Missing,
+}
+
+impl CachedActor {
+    fn into_stale(self) -> Option<Actor> {
+        match self {
+            CachedActor::Fresh(actor) | CachedActor::Stale(actor) => Some(actor),
+            CachedActor::Missing => None,
+        }
+    }
+}
+
+/// Look an actor up by handle, only going out to webfinger when our copy is missing or stale
+pub async fn get_or_search_for_actor(
+    pool: &Pool,
+    handle: &FullHandle,
+    current_profile: &CurrentProfile,
+) -> InternalResult<Option<Actor>> {
+    let (preferred_username, host) = (handle.preferred_username.clone(), handle.host.clone());
+    let cached = pool.run(move |db| get_cached_actor(
+        db,
+        "WHERE preferred_username = ?2 AND host = ?3",
+        (get_ttl_modifier(), &preferred_username, &host),
+    )).await?;
+    if let CachedActor::Fresh(actor) = cached {
+        return Ok(Some(actor));
+    }
+
+    match search_for_actor(handle, current_profile).await {
+        Ok(Some(actor)) => {
+            let host = handle.host.clone();
+            let actor = pool.run(move |db| {
+                save_known_actor(db, &actor, &host)?;
+                Ok(actor)
+            }).await?;
+            Ok(Some(actor))
+        }
+        Ok(None) => Ok(None),
+        Err(e) => match cached.into_stale() {
+            Some(actor) => {
+                warn!("Failed to refresh {}@{}, using our last copy", handle.preferred_username, handle.host);
+                Ok(Some(actor))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Look an actor up by their id, only fetching it when our copy is missing or stale
+pub async fn get_or_fetch_actor(pool: &Pool, uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<Actor> {
+    let actor_id = uri.to_string();
+    let cached_id = actor_id.clone();
+    let cached = pool.run(move |db| {
+        get_cached_actor(db, "WHERE actor_id = ?2", (get_ttl_modifier(), &cached_id))
+    }).await?;
+    if let CachedActor::Fresh(actor) = cached {
+        return Ok(actor);
+    }
+
+    match fetch_actor(uri, current_profile).await {
+        Ok(actor) => pool.run(move |db| {
+            update_known_actor(db, &actor)?;
+            Ok(actor)
+        }).await,
+        Err(e) => match cached.into_stale() {
+            Some(actor) => {
+                warn!("Failed to refresh {}, using our last copy", actor_id);
+                Ok(actor)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Insert or refresh our copy of a remote actor
+pub fn save_known_actor(db: &Connection, actor: &Actor, host: &str) -> InternalResult<()> {
+    let icon_url = actor.icon.as_ref().map(|i| &i.url);
+    // Whether it was fetched or pushed to our inbox, the summary is only ever stored sanitized
+    let summary = actor.summary.as_deref().map(sanitize_html);
+    let mut actor_json = json!(actor);
+    actor_json["summary"] = json!(summary);
+    let actor_json = actor_json.to_string();
+    db.execute(
+        "INSERT INTO known_actors
+            (actor_id, name, preferred_username, url, inbox, outbox, summary, icon_url, host, actor_json)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        ON CONFLICT (actor_id) DO UPDATE SET
+            name = excluded.name,
+            preferred_username = excluded.preferred_username,
+            url = excluded.url,
+            inbox = excluded.inbox,
+            outbox = excluded.outbox,
+            summary = excluded.summary,
+            icon_url = excluded.icon_url,
+            host = excluded.host,
+            actor_json = excluded.actor_json,
+            fetched_at = excluded.fetched_at",
+        (&actor.id, &actor.name, &actor.preferred_username, &actor.url, &actor.inbox, &actor.outbox,
+         &summary, icon_url, host, actor_json),
+    )?;
+    Ok(())
+}
+
+/// Refresh our copy of an actor, keeping the host we learned from webfinger if there was one
+pub fn update_known_actor(db: &Connection, actor: &Actor) -> InternalResult<()> {
+    let host = match get_known_host(db, &actor.id) {
+        Some(host) => host,
+        None => get_actor_host(actor)?,
+    };
+    save_known_actor(db, actor, &host)
+}
+
+/// Without a webfinger lookup, the best guess for the handle's host is the host of the id
+pub fn get_actor_host(actor: &Actor) -> InternalResult<String> {
+    actor.id.parse::<Uri>().ok()
+        .and_then(|uri| uri.host().map(|h| h.to_owned()))
+        .ok_or_else(|| bad_request(&format!("{} is not a valid actor id", actor.id)))
+}
+
+fn get_ttl_modifier() -> String {
+    format!("-{} seconds", ACTOR_TTL_SECS)
+}
+
+// The query's conditions start at ?2, since ?1 is the TTL modifier
+fn get_cached_actor<P: Params>(db: &Connection, conditions: &str, params: P) -> InternalResult<CachedActor> {
+    let query = format!(
+        "SELECT actor_json, fetched_at > strftime('%FT%TZ', 'now', ?1)
+        FROM known_actors
+        {} AND actor_json IS NOT NULL",
+        conditions
+    );
+    let row: Option<(String, bool)> = db.query_row(&query, params, |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    let cached = match row {
+        None => CachedActor::Missing,
+        Some((actor_json, is_fresh)) => match serde_json::from_str(&actor_json) {
+            Ok(actor) if is_fresh => CachedActor::Fresh(actor),
+            Ok(actor) => CachedActor::Stale(actor),
+            Err(_) => CachedActor::Missing,
+        },
+    };
+    Ok(cached)
+}
+
+async fn search_for_actor(handle: &FullHandle, current_profile: &CurrentProfile) -> InternalResult<Option<Actor>> {
+    let FullHandle {
+        preferred_username,
+        host,
+    } = handle;
+
+    let web_finger = get_webfinger(host, preferred_username).await?;
+    let actor_link = web_finger
+        .links
+        .as_ref()
+        .and_then(|links| {
+            links
+                .iter()
+                .find(|l| l.rel == "self" && l.link_type == Some(LinkType::ActivityJson))
+        })
+        .and_then(|link| link.href.clone());
+
+    let uri = match actor_link.as_ref() {
+        None => return Ok(None),
+        Some(link) => link.clone().parse::<Uri>(),
+    }
+    .map_err(|e| {
+        warn!(
+            "Invalid URI provided for self by {}@{}: {:?}",
+            preferred_username, host, &actor_link
+        );
+        map_bad_gateway(e)
+    })?;
+
+    let actor = fetch_actor(&uri, current_profile).await?;
+    Ok(Some(actor))
+}
+
+// Whoever serves the document could claim to be anyone, so only take it if it's the actor we asked for
+async fn fetch_actor(uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<Actor> {
+    let mut actor = get_actor(uri, current_profile).await?;
+    if actor.id != uri.to_string() {
+        warn!("Fetching {} returned a different actor, {}", uri, actor.id);
+        return Err(bad_gateway(&format!("{} did not return the actor it was fetched for", uri)));
+    }
+
+    // Summaries are HTML that gets shown as is, like remote posts
+    actor.summary = actor.summary.as_deref().map(sanitize_html);
+    Ok(actor)
+}
+
+/// Periodically refetch the actors our profiles follow, so their names and avatars stay current
+pub fn start_refresher(shutdown: &Shutdown, pool: &Pool, domain: Option<String>) {
+    shutdown.spawn(run_refresher(shutdown.clone(), pool.clone(), domain));
+}
+
+async fn run_refresher(shutdown: Shutdown, pool: Pool, domain: Option<String>) {
+    while shutdown.sleep(REFRESH_INTERVAL).await {
+        let stale = match pool.run_background(|db| Ok(get_stale_followed_actors(db)?)).await {
+            Ok(stale) => stale,
+            Err(e) => {
+                error!("Failed to find actors to refresh: {}", e);
+                continue;
+            }
+        };
+
+        for (actor_id, profile_id) in stale {
+            // The rest of the batch will still be stale next time
+            if shutdown.is_requested() {
+                break;
+            }
+
+            let profile_domain = domain.clone();
+            let profile = pool.run_background(move |db| {
+                let profile = get_domain(db, &profile_domain)
+                    .ok()
+                    .and_then(|domain| CurrentProfile::new(db, profile_id, &domain));
+                Ok(profile)
+            }).await;
+            let (Ok(Some(profile)), Ok(uri)) = (profile, actor_id.parse::<Uri>()) else {
+                continue;
+            };
+
+            let fetched = fetch_actor(&uri, &profile).await;
+            let refreshed_id = actor_id.clone();
+            let result = pool.run_background(move |db| match fetched {
+                Ok(actor) => update_known_actor(db, &actor),
+                Err(e) => {
+                    // Wait a full TTL before trying again, rather than retrying every pass
+                    debug!("Failed to refresh {}: {:?}", refreshed_id, e);
+                    touch_known_actor(db, &refreshed_id)
+                }
+            }).await;
+
+            if let Err(e) = result {
+                error!("Failed to save refreshed actor {}: {:?}", actor_id, e);
+            }
+        }
+    }
+}
+
+// Each stale actor comes with one of the profiles following them, to sign the fetch with
+fn get_stale_followed_actors(db: &Connection) -> rusqlite::Result<Vec<(String, i64)>> {
+    let mut query = db.prepare(
+        "SELECT actor_id, MIN(profile_id)
+        FROM following
+        INNER JOIN known_actors USING (actor_id)
+        WHERE fetched_at <= strftime('%FT%TZ', 'now', ?1)
+        GROUP BY actor_id
+        ORDER BY fetched_at ASC
+        LIMIT ?2",
+    )?;
+    let rows = query.query_map((get_ttl_modifier(), REFRESH_BATCH_SIZE), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+fn get_known_host(db: &Connection, actor_id: &str) -> Option<String> {
+    db.query_row("SELECT host FROM known_actors WHERE actor_id = ?1", [actor_id], |row| row.get(0))
+        .ok()
+        .flatten()
+}
+
+fn touch_known_actor(db: &Connection, actor_id: &str) -> InternalResult<()> {
+    db.execute(
+        "UPDATE known_actors SET fetched_at = strftime('%FT%TZ', CURRENT_TIMESTAMP) WHERE actor_id = ?1",
+        [actor_id],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+/// A piece of a post's plain text source, split up so that mentions and hashtags can be linked
+#[derive(Debug, PartialEq)]
+pub enum Token<'a> {
+    Text(&'a str),
+    Mention { preferred_username: &'a str, host: Option<&'a str> },
+    Hashtag(&'a str),
+}
+
+impl Token<'_> {
+    /// The exact text that the token was parsed from
+    pub fn source(&self) -> String {
+        match self {
+            Token::Text(text) => text.to_string(),
+            Token::Mention { preferred_username, host: Some(host) } => format!("@{}@{}", preferred_username, host),
+            Token::Mention { preferred_username, host: None } => format!("@{}", preferred_username),
+            Token::Hashtag(name) => format!("#{}", name),
+        }
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The formatting that remote posts are allowed to keep; everything else is reduced to its text
+const ALLOWED_TAGS: &[&str] = &[
+    "p", "br", "a", "span", "strong", "b", "em", "i", "u", "s", "del", "code", "pre", "blockquote", "ul", "ol", "li",
+];
+// Elements whose contents aren't text to show, so they're dropped along with the tags
+const DROPPED_TAGS: &[&str] = &[
+    "script", "style", "iframe", "object", "embed", "template", "noscript", "textarea", "title", "svg", "math",
+];
+// Microformat and Mastodon classes, which style mentions, hashtags and shortened links
+const ALLOWED_CLASSES: &[&str] = &["mention", "hashtag", "h-card", "u-url", "ellipsis", "invisible"];
+
+struct HtmlTag {
+    name: String,
+    is_closing: bool,
+    attributes: Vec<(String, String)>,
+}
+
+/// Reduce HTML from another server to an allowlist of tags and attributes, so that it's safe to
+/// show as-is. Links have to be http(s), and anything that doesn't parse as a tag is escaped
+pub fn sanitize_html(html: &str) -> String {
+    let mut sanitized = String::with_capacity(html.len());
+    let mut open_tags: Vec<&'static str> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        push_text(&mut sanitized, &rest[..start]);
+        rest = &rest[start..];
+
+        // Comments, doctypes and the like are dropped
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+            continue;
+        }
+
+        let Some((tag, length)) = parse_tag(rest) else {
+            sanitized.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[length..];
+
+        if !tag.is_closing && DROPPED_TAGS.contains(&tag.name.as_str()) {
+            rest = skip_past_closing_tag(rest, &tag.name);
+            continue;
+        }
+        let Some(name) = ALLOWED_TAGS.iter().find(|allowed| **allowed == tag.name) else {
+            continue;
+        };
+
+        if tag.is_closing {
+            // Closing a tag closes anything left open inside it, and stray ones are dropped
+            if let Some(position) = open_tags.iter().rposition(|open| open == name) {
+                for open in open_tags.drain(position..).rev() {
+                    sanitized.push_str(&format!("</{}>", open));
+                }
+            }
+        } else if *name == "br" {
+            sanitized.push_str("<br>");
+        } else {
+            sanitized.push_str(&format!("<{}{}>", name, get_allowed_attributes(name, &tag.attributes)));
+            open_tags.push(name);
+        }
+    }
+    push_text(&mut sanitized, rest);
+
+    for open in open_tags.into_iter().rev() {
+        sanitized.push_str(&format!("</{}>", open));
+    }
+    sanitized
+}
+
+// Entities are left alone, since they can only ever decode to text here
+fn push_text(sanitized: &mut String, text: &str) {
+    sanitized.push_str(&text.replace('>', "&gt;"));
+}
+
+fn parse_tag(html: &str) -> Option<(HtmlTag, usize)> {
+    let mut chars = html.char_indices().skip(1).peekable();
+    let is_closing = chars.next_if(|(_, c)| *c == '/').is_some();
+
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric()) {
+        name.push(c.to_ascii_lowercase());
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut attributes = Vec::new();
+    loop {
+        while chars.next_if(|(_, c)| c.is_whitespace() || *c == '/').is_some() {}
+        let (i, c) = chars.next()?;
+        if c == '>' {
+            return Some((HtmlTag { name, is_closing, attributes }, i + 1));
+        }
+
+        let mut attribute = c.to_ascii_lowercase().to_string();
+        while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace() && !matches!(c, '=' | '>' | '/')) {
+            attribute.push(c.to_ascii_lowercase());
+        }
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        if chars.next_if(|(_, c)| *c == '=').is_none() {
+            attributes.push((attribute, String::new()));
+            continue;
+        }
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        match chars.next_if(|(_, c)| *c == '"' || *c == '\'') {
+            Some((_, quote)) => loop {
+                let (_, c) = chars.next()?;
+                if c == quote {
+                    break;
+                }
+                value.push(c);
+            },
+            None => {
+                while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace() && *c != '>') {
+                    value.push(c);
+                }
+            }
+        }
+        attributes.push((attribute, value));
+    }
+}
+
+fn skip_past_closing_tag<'a>(html: &'a str, name: &str) -> &'a str {
+    let closing_tag = format!("</{}", name);
+    let Some(start) = html.to_ascii_lowercase().find(&closing_tag) else {
+        return "";
+    };
+    let rest = &html[start..];
+    rest.find('>').map_or("", |end| &rest[end + 1..])
+}
+
+fn get_allowed_attributes(name: &str, attributes: &[(String, String)]) -> String {
+    let mut allowed = String::new();
+    for (attribute, value) in attributes {
+        match attribute.as_str() {
+            "href" if name == "a" && is_http_url(value) => {
+                let href = value.replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;");
+                allowed.push_str(&format!(" href=\"{}\"", href));
+            }
+            "class" if name == "a" || name == "span" => {
+                let classes: Vec<&str> = value.split_whitespace().filter(|c| ALLOWED_CLASSES.contains(c)).collect();
+                if !classes.is_empty() {
+                    allowed.push_str(&format!(" class=\"{}\"", classes.join(" ")));
+                }
+            }
+            _ => {}
+        }
+    }
+    if name == "a" {
+        allowed.push_str(" rel=\"nofollow noopener noreferrer\"");
+    }
+    allowed
+}
+
+// The scheme has to be spelled out, so entities like &colon; can't sneak javascript: through
+pub fn is_http_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+/// Split text into plain text, @user@host (or just @user) mentions, and #hashtags
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut pos = 0;
+
+    while let Some(c) = text[pos..].chars().next() {
+        // Mentions and hashtags have to start a word, so that emails and anchors aren't picked up
+        let starts_word = text[..pos].chars().next_back().is_none_or(|prev| !is_word_char(prev));
+        let token = match c {
+            '@' if starts_word => parse_mention(&text[pos..]),
+            '#' if starts_word => parse_hashtag(&text[pos..]),
+            _ => None,
+        };
+
+        match token {
+            Some((token, len)) => {
+                if text_start < pos {
+                    tokens.push(Token::Text(&text[text_start..pos]));
+                }
+                tokens.push(token);
+                pos += len;
+                text_start = pos;
+            }
+            None => pos += c.len_utf8(),
+        }
+    }
+
+    if text_start < text.len() {
+        tokens.push(Token::Text(&text[text_start..]));
+    }
+
+    tokens
+}
+
+/// Render tokens as HTML, linking each mention found in `mentions` (keyed by source text) to the
+/// given URL; unresolved mentions are left as text
+pub fn render_html(tokens: &[Token], mentions: &HashMap<String, String>, domain: &str) -> String {
+    let html: String = tokens.iter()
+        .map(|token| match token {
+            Token::Text(text) => escape_html(text),
+            Token::Mention { preferred_username, .. } => match mentions.get(&token.source()) {
+                Some(url) => format!(
+                    "<span class=\"h-card\"><a href=\"{}\" class=\"u-url mention\">@<span>{}</span></a></span>",
+                    escape_html(url), escape_html(preferred_username)),
+                None => escape_html(&token.source()),
+            },
+            Token::Hashtag(name) => format!(
+                "<a href=\"{}\" class=\"mention hashtag\" rel=\"tag\">#<span>{}</span></a>",
+                get_hashtag_url(name, domain), escape_html(name)),
+        })
+        .collect();
+
+    html.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| format!("<p>{}</p>", paragraph.replace('\n', "<br>")))
+        .collect()
+}
+
+pub fn get_hashtag_url(name: &str, domain: &str) -> String {
+    format!("https://{}/tags/{}", domain, name.to_lowercase())
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_username_char(c: char) -> bool {
+    is_word_char(c) || c == '.' || c == '-'
+}
+
+fn is_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '.' || c == '-'
+}
+
+// Take the longest run of matching characters, minus any trailing punctuation
+fn take_while_trimmed(text: &str, matches: fn(char) -> bool) -> &str {
+    let end = text.find(|c: char| !matches(c)).unwrap_or(text.len());
+    text[..end].trim_end_matches(['.', '-'])
+}
+
+fn parse_mention(text: &str) -> Option<(Token<'_>, usize)> {
+    let preferred_username = take_while_trimmed(&text[1..], is_username_char);
+    if preferred_username.is_empty() {
+        return None;
+    }
+
+    let mut len = 1 + preferred_username.len();
+    let host = text[len..]
+        .strip_prefix('@')
+        .map(|rest| take_while_trimmed(rest, is_host_char))
+        .filter(|host| host.contains('.') || *host == "localhost");
+
+    if let Some(host) = host {
+        len += 1 + host.len();
+    }
+
+    Some((Token::Mention { preferred_username, host }, len))
+}
+
+fn parse_hashtag(text: &str) -> Option<(Token<'_>, usize)> {
+    let name = take_while_trimmed(&text[1..], is_word_char);
+    // "#1" is much more likely to be a number than a hashtag
+    if name.is_empty() || name.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((Token::Hashtag(name), 1 + name.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_mentions_and_hashtags() {
+        let tokens = tokenize("hi @alice@example.com and @bob, see #Sailing.");
+        assert_eq!(tokens, vec![
+            Token::Text("hi "),
+            Token::Mention { preferred_username: "alice", host: Some("example.com") },
+            Token::Text(" and "),
+            Token::Mention { preferred_username: "bob", host: None },
+            Token::Text(", see "),
+            Token::Hashtag("Sailing"),
+            Token::Text("."),
+        ]);
+    }
+
+    #[test]
+    fn ignores_emails_and_numbers() {
+        let tokens = tokenize("mail me@example.com about issue #12");
+        assert_eq!(tokens, vec![Token::Text("mail me@example.com about issue #12")]);
+    }
+
+    #[test]
+    fn sanitizing_strips_scripts_and_event_handlers() {
+        let html = sanitize_html(concat!(
+            "<p>hi<script>alert(1)</script></p>",
+            "<img src=x onerror=alert(1)>",
+            "<a href=\"https://example.com/\" onclick=\"alert(1)\">link</a>",
+            "<SCRIPT type=text/javascript>alert(2)</SCRIPT >",
+        ));
+        assert_eq!(html, concat!(
+            "<p>hi</p>",
+            "<a href=\"https://example.com/\" rel=\"nofollow noopener noreferrer\">link</a>",
+        ));
+        assert!(!html.contains("script") && !html.contains("onerror") && !html.contains("onclick"));
+    }
+
+    #[test]
+    fn sanitizing_drops_unsafe_links() {
+        let html = sanitize_html("<a href=\"javascript:alert(1)\">a</a><a href='javascript&colon;alert(1)'>b</a>");
+        assert_eq!(html, "<a rel=\"nofollow noopener noreferrer\">a</a><a rel=\"nofollow noopener noreferrer\">b</a>");
+    }
+
+    #[test]
+    fn sanitizing_keeps_mentions_and_closes_tags() {
+        let html = sanitize_html(concat!(
+            "<p><span class=\"h-card\"><a href=\"https://example.com/@alex\" class=\"u-url mention evil\">",
+            "@<span>alex</span></a></span> 1 < 2 & <b>bold<!-- hidden --></p><em>open",
+        ));
+        assert_eq!(html, concat!(
+            "<p><span class=\"h-card\"><a href=\"https://example.com/@alex\" class=\"u-url mention\" ",
+            "rel=\"nofollow noopener noreferrer\">@<span>alex</span></a></span> 1 &lt; 2 & <b>bold</b></p><em>open</em>",
+        ));
+    }
+
+    #[test]
+    fn renders_links_and_escapes_text() {
+        let tokens = tokenize("<b>hi</b> @alice@example.com @nobody@example.com\n\n#Boats");
+        let mentions = HashMap::from([
+            ("@alice@example.com".to_owned(), "https://example.com/@alice".to_owned()),
+        ]);
+        let html = render_html(&tokens, &mentions, "sailboat.test");
+        assert_eq!(html, concat!(
+            "<p>&lt;b&gt;hi&lt;/b&gt; ",
+            "<span class=\"h-card\"><a href=\"https://example.com/@alice\" class=\"u-url mention\">@<span>alice</span></a></span>",
+            " @nobody@example.com</p>",
+            "<p><a href=\"https://sailboat.test/tags/boats\" class=\"mention hashtag\" rel=\"tag\">#<span>Boats</span></a></p>",
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use hyper::{StatusCode, Uri};
+use rusqlite::{Connection, OptionalExtension};
+use tracing::{debug, error, warn};
+
+use crate::activitypub::requests::send_as;
+use crate::server::server_request::CurrentProfile;
+use crate::server::server_response::InternalResult;
+use crate::shutdown::Shutdown;
+use crate::sqlite::pool::Pool;
+
+const MAX_ATTEMPTS: i64 = 10;
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 60 * 60 * 6;
+// How long a worker has to finish a delivery before it's considered abandoned and retried
+const LEASE_SECS: i64 = 60 * 5;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Delivery {
+    delivery_id: i64,
+    profile_id: i64,
+    inbox: String,
+    body: String,
+    attempts: i64,
+}
+
+enum Outcome {
+    Delivered,
+    Retry(String),
+    Failed(String),
+}
+
+/// Queue an activity to be sent to a single inbox, signed as the given profile
+pub fn enqueue(db: &Connection, profile_id: i64, inbox: &str, body: &str) -> InternalResult<()> {
+    db.execute(
+        "INSERT INTO deliveries (profile_id, inbox, body) VALUES (?1, ?2, ?3)",
+        (profile_id, inbox, body),
+    )?;
+    Ok(())
+}
+
+/// Queue an activity to be sent to the inbox of every one of the profile's followers
+pub fn enqueue_for_followers(db: &Connection, profile_id: i64, body: &str) -> InternalResult<()> {
+    db.execute(
+        "INSERT INTO deliveries (profile_id, inbox, body)
+        SELECT DISTINCT ?1, inbox, ?2
+        FROM followers
+        LEFT JOIN known_actors USING (actor_id)
+        WHERE profile_id = ?1 AND inbox IS NOT NULL",
+        (profile_id, body),
+    )?;
+    Ok(())
+}
+
+/// Queue an activity for specific actors (e.g. the ones it mentions), skipping any inbox that
+/// already gets it as a follower's; local actors aren't known_actors, so they're skipped too
+pub fn enqueue_for_actors(db: &Connection, profile_id: i64, actor_ids: &[String], body: &str) -> InternalResult<()> {
+    for actor_id in actor_ids {
+        db.execute(
+            "INSERT INTO deliveries (profile_id, inbox, body)
+            SELECT ?1, inbox, ?2
+            FROM known_actors
+            WHERE actor_id = ?3 AND inbox IS NOT NULL AND inbox NOT IN (
+                SELECT inbox
+                FROM followers
+                LEFT JOIN known_actors USING (actor_id)
+                WHERE profile_id = ?1 AND inbox IS NOT NULL
+            )",
+            (profile_id, body, actor_id),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn start_workers(shutdown: &Shutdown, worker_count: usize, pool: &Pool, domain: Option<String>) {
+    for _ in 0..worker_count {
+        shutdown.spawn(run_worker(shutdown.clone(), pool.clone(), domain.clone()));
+    }
+}
+
+// On shutdown, a worker finishes the delivery it's sending and then stops; anything cut off by the
+// shutdown deadline is still claimed, so it gets retried once the lease runs out
+async fn run_worker(shutdown: Shutdown, pool: Pool, domain: Option<String>) {
+    while !shutdown.is_requested() {
+        let domain = domain.clone();
+        let claimed = pool.run_background(move |db| {
+            let Some(delivery) = claim_next(db)? else {
+                return Ok(None);
+            };
+            let profile = get_domain(db, &domain)
+                .ok()
+                .and_then(|domain| CurrentProfile::new(db, delivery.profile_id, &domain));
+            Ok(Some((delivery, profile)))
+        }).await;
+
+        let (delivery, profile) = match claimed {
+            Ok(Some(claimed)) => claimed,
+            Ok(None) => {
+                shutdown.sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to claim delivery: {}", e);
+                shutdown.sleep(POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let outcome = match profile {
+            Some(profile) => attempt(&delivery, &profile).await,
+            None => Outcome::Failed(format!("Profile {} not found", delivery.profile_id)),
+        };
+
+        let delivery_id = delivery.delivery_id;
+        let finished = pool.run_background(move |db| Ok(finish(db, &delivery, outcome)?)).await;
+        if let Err(e) = finished {
+            error!("Failed to update delivery {}: {}", delivery_id, e);
+        }
+    }
+}
+
+pub fn get_domain(db: &Connection, domain: &Option<String>) -> rusqlite::Result<String> {
+    match domain {
+        Some(d) => Ok(d.clone()),
+        None => db.query_row("SELECT value FROM globals WHERE key = 'domain'", (), |row| row.get(0)),
+    }
+}
+
+// Claiming a delivery pushes its next attempt past the lease, so if we crash mid-send (or the
+// process restarts) it will be picked up again once the lease runs out
+fn claim_next(db: &Connection) -> rusqlite::Result<Option<Delivery>> {
+    db.query_row(
+        "UPDATE deliveries
+        SET attempts = attempts + 1, next_attempt_at = strftime('%FT%TZ', 'now', ?1)
+        WHERE delivery_id = (
+            SELECT delivery_id FROM deliveries
+            WHERE next_attempt_at <= strftime('%FT%TZ', 'now')
+            ORDER BY next_attempt_at
+            LIMIT 1
+        )
+        RETURNING delivery_id, profile_id, inbox, body, attempts",
+        [format!("+{} seconds", LEASE_SECS)],
+        |row| {
+            let delivery = Delivery {
+                delivery_id: row.get(0)?,
+                profile_id: row.get(1)?,
+                inbox: row.get(2)?,
+                body: row.get(3)?,
+                attempts: row.get(4)?,
+            };
+            Ok(delivery)
+        },
+    )
+    .optional()
+}
+
+async fn attempt(delivery: &Delivery, profile: &CurrentProfile) -> Outcome {
+    let inbox_uri: Uri = match delivery.inbox.parse() {
+        Ok(uri) => uri,
+        Err(_) => return Outcome::Failed("Invalid inbox URI".to_owned()),
+    };
+
+    let res = match send_as(&inbox_uri, profile, delivery.body.clone()).await {
+        Ok(res) => res,
+        Err(e) => return Outcome::Retry(e.to_string()),
+    };
+
+    let status = res.status();
+    let res_body = res.text().await.unwrap_or_default();
+    debug!("Received {} from {}: {}", status, inbox_uri, res_body);
+
+    match status {
+        s if s.is_success() => Outcome::Delivered,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Outcome::Retry(status.to_string()),
+        // The remote server understood us and said no, so trying again won't help
+        s if s.is_client_error() => Outcome::Failed(status.to_string()),
+        _ => Outcome::Retry(status.to_string()),
+    }
+}
+
+fn finish(db: &Connection, delivery: &Delivery, outcome: Outcome) -> rusqlite::Result<()> {
+    let Delivery { delivery_id, attempts, inbox, .. } = delivery;
+    match outcome {
+        Outcome::Delivered => {
+            db.execute("DELETE FROM deliveries WHERE delivery_id = ?1", [delivery_id])?;
+        }
+        Outcome::Failed(reason) => {
+            warn!("Dropping delivery {} to {}: {}", delivery_id, inbox, reason);
+            db.execute("DELETE FROM deliveries WHERE delivery_id = ?1", [delivery_id])?;
+        }
+        Outcome::Retry(reason) if *attempts >= MAX_ATTEMPTS => {
+            warn!("Giving up on delivery {} to {} after {} attempts: {}", delivery_id, inbox, attempts, reason);
+            db.execute("DELETE FROM deliveries WHERE delivery_id = ?1", [delivery_id])?;
+        }
+        Outcome::Retry(reason) => {
+            let backoff = format!("+{} seconds", get_backoff_secs(*attempts));
+            debug!("Retrying delivery {} to {} ({}), attempt {}", delivery_id, inbox, reason, attempts);
+            db.execute(
+                "UPDATE deliveries
+                SET next_attempt_at = strftime('%FT%TZ', 'now', ?2), last_error = ?3
+                WHERE delivery_id = ?1",
+                (delivery_id, backoff, reason),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn get_backoff_secs(attempts: i64) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    (BASE_BACKOFF_SECS * 2_i64.pow(exponent)).min(MAX_BACKOFF_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_each_attempt() {
+        assert_eq!(get_backoff_secs(1), 30);
+        assert_eq!(get_backoff_secs(2), 60);
+        assert_eq!(get_backoff_secs(5), 480);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(get_backoff_secs(MAX_ATTEMPTS * 10), MAX_BACKOFF_SECS)
+    }
+}
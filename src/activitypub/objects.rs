@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
 pub mod actor;
+pub mod collection;
+pub mod nodeinfo;
 pub mod outbox;
 pub mod webfinger;
 pub mod note;
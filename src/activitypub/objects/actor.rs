@@ -1,6 +1,13 @@
-use crate::activitypub::objects::Context;
+use crate::activitypub::objects::outbox::{ActivityType, UpdateActivity};
+use crate::activitypub::objects::{AtContext, Context};
+use crate::activitypub::content::escape_html;
+use crate::activitypub::PUBLIC_STREAM;
+use crate::query_row;
+use crate::server::server_response::InternalResult;
 use minijinja::Value;
 use openssl::pkey::PKey;
+use rand::random;
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,6 +24,27 @@ pub struct Icon {
     pub url: String,
 }
 
+impl Icon {
+    pub fn new(url: &str) -> Icon {
+        Icon {
+            icon_type: "Image".to_owned(),
+            media_type: guess_media_type(url).to_owned(),
+            url: url.to_owned(),
+        }
+    }
+}
+
+fn guess_media_type(url: &str) -> &'static str {
+    let extension = url.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "image/jpeg",
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PublicKey {
     pub id: String,
@@ -56,6 +84,21 @@ pub struct Actor {
     #[serde(rename = "publicKey")]
     pub public_key: PublicKey,
     pub icon: Option<Icon>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<Icon>,
+}
+
+impl Actor {
+    pub fn into_update(self) -> UpdateActivity<Actor> {
+        UpdateActivity {
+            context: Some(AtContext::Context(Context::ActivityStreams)),
+            id: format!("{}#updates/{}", self.id, random::<u64>()),
+            activity_type: ActivityType::Update,
+            actor: self.id.clone(),
+            to: vec![PUBLIC_STREAM.to_owned()],
+            object: self,
+        }
+    }
 }
 
 // TODO seems like minijinja could figure this out on its own via the serde::Serialize derivation
@@ -73,3 +116,80 @@ pub enum LinkType {
     #[serde(untagged)]
     Unknown(serde_json::Value),
 }
+
+pub fn get_local_actor(db: &Connection, profile_id: i64, domain: &str) -> InternalResult<Actor> {
+    let profile = query_row!(
+        db,
+        Profile {
+            display_name: String,
+            preferred_username: String,
+            summary: Option<String>,
+            avatar_url: Option<String>,
+            header_url: Option<String>,
+            private_key_pem: String
+        },
+        "FROM profiles WHERE profile_id = ?1",
+        [profile_id]
+    )?;
+
+    let id = format!("https://{}/profiles/{}", domain, profile_id);
+    let avatar_url = profile.avatar_url
+        .unwrap_or_else(|| format!("https://{}/static/images/pineapple.svg", domain));
+
+    let inbox = format!("https://{}/inbox", domain);
+    let outbox = format!("https://{}/profiles/{}/outbox", domain, profile_id);
+    let following = format!("https://{}/profiles/{}/following", domain, profile_id);
+    let followers = format!("https://{}/profiles/{}/followers", domain, profile_id);
+    let public_key = PublicKey::new(&id, &profile.private_key_pem);
+
+    let actor = Actor {
+        context: vec![Context::ActivityStreams, Context::SecurityV1],
+        id: id.to_owned(),
+        url: id,
+        name: profile.display_name,
+        actor_type: ActorType::Person,
+        summary: profile.summary.as_deref().map(summary_to_html),
+        preferred_username: profile.preferred_username,
+        icon: Some(Icon::new(&avatar_url)),
+        image: profile.header_url.as_deref().map(Icon::new),
+        inbox,
+        outbox,
+        followers: Some(followers),
+        following: Some(following),
+        public_key,
+    };
+
+    Ok(actor)
+}
+
+// Bios are stored as plain text, but ActivityPub expects HTML
+pub fn summary_to_html(summary: &str) -> String {
+    escape_html(summary)
+        .split("\n\n")
+        .map(|paragraph| format!("<p>{}</p>", paragraph.trim().replace('\n', "<br>")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_is_escaped() {
+        let html = summary_to_html("<script>alert('hi')</script>");
+        assert_eq!(html, "<p>&lt;script&gt;alert('hi')&lt;/script&gt;</p>")
+    }
+
+    #[test]
+    fn summary_keeps_line_breaks() {
+        let html = summary_to_html("Sailing\nmostly\n\nSometimes rowing");
+        assert_eq!(html, "<p>Sailing<br>mostly</p><p>Sometimes rowing</p>")
+    }
+
+    #[test]
+    fn guesses_icon_media_type() {
+        assert_eq!(Icon::new("https://example.com/avatar.PNG").media_type, "image/png");
+        assert_eq!(Icon::new("https://example.com/pineapple.svg").media_type, "image/svg+xml");
+        assert_eq!(Icon::new("https://example.com/avatar").media_type, "image/jpeg");
+    }
+}
@@ -0,0 +1,114 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::server::server_response::InternalResult;
+
+use super::outbox::{get_collection_page, get_page_count, OrderedCollectionPage, OrderedCollectionType};
+use super::{AtContext, Context};
+
+pub const COLLECTION_PAGE_SIZE: i64 = 40;
+
+// https://www.w3.org/TR/activitystreams-core/#collection
+// Unlike the Outbox, first and last are left out when a profile hides its network
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderedCollection {
+    #[serde(rename = "@context")]
+    pub context: AtContext,
+    #[serde(rename = "type")]
+    pub _type: OrderedCollectionType,
+    pub id: String,
+    #[serde(rename = "totalItems")]
+    pub total_items: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<String>,
+}
+
+/// The two collections of actor ids that make up a profile's social graph
+#[derive(Debug, Clone, Copy)]
+pub enum Network {
+    Followers,
+    Following,
+}
+
+impl Network {
+    fn path(&self) -> &'static str {
+        match self {
+            Network::Followers => "followers",
+            Network::Following => "following",
+        }
+    }
+
+    // Only accepted follows are public; pending requests stay between us and the remote server
+    fn count_query(&self) -> &'static str {
+        match self {
+            Network::Followers => "SELECT count(*) FROM followers WHERE profile_id = ?1",
+            Network::Following => "SELECT count(*) FROM following WHERE profile_id = ?1 AND is_accepted = TRUE",
+        }
+    }
+
+    fn page_query(&self) -> &'static str {
+        match self {
+            Network::Followers => "SELECT actor_id FROM followers WHERE profile_id = ?1
+                ORDER BY rowid DESC LIMIT ?2 OFFSET ?3",
+            Network::Following => "SELECT actor_id FROM following WHERE profile_id = ?1 AND is_accepted = TRUE
+                ORDER BY rowid DESC LIMIT ?2 OFFSET ?3",
+        }
+    }
+}
+
+pub fn is_network_hidden(db: &Connection, profile_id: i64) -> rusqlite::Result<bool> {
+    db.query_row("SELECT hide_network FROM profiles WHERE profile_id = ?1", [profile_id], |row| row.get(0))
+}
+
+pub fn get_network_collection(
+    db: &Connection,
+    profile_id: i64,
+    domain: &str,
+    network: Network,
+    is_hidden: bool
+) -> InternalResult<OrderedCollection> {
+    let total_items: i64 = db.query_row(network.count_query(), [profile_id], |row| row.get(0))?;
+    let collection_url = format!("https://{}/profiles/{}/{}", domain, profile_id, network.path());
+
+    let (first, last) = match is_hidden {
+        true => (None, None),
+        false => {
+            let last_page_num = get_page_count(total_items, COLLECTION_PAGE_SIZE);
+            let first = format!("{}?page=1", collection_url);
+            let last = format!("{}?page={}", collection_url, last_page_num);
+            (Some(first), Some(last))
+        }
+    };
+
+    let collection = OrderedCollection {
+        context: AtContext::Context(Context::ActivityStreams),
+        _type: OrderedCollectionType::OrderedCollection,
+        id: collection_url,
+        total_items,
+        first,
+        last,
+    };
+
+    Ok(collection)
+}
+
+pub fn get_network_page(
+    db: &Connection,
+    profile_id: i64,
+    domain: &str,
+    network: Network,
+    page_num: i64
+) -> InternalResult<OrderedCollectionPage<String>> {
+    let collection_url = format!("https://{}/profiles/{}/{}", domain, profile_id, network.path());
+    let fetch_actor_ids = |limit: i64, offset: i64| {
+        let mut query = db.prepare(network.page_query())?;
+        let actor_ids = query
+            .query_map((profile_id, limit, offset), |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(actor_ids)
+    };
+
+    get_collection_page(&collection_url, page_num, COLLECTION_PAGE_SIZE, fetch_actor_ids, Ok)
+}
@@ -0,0 +1,128 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::server::server_response::InternalResult;
+
+// https://github.com/jhass/nodeinfo/blob/main/PROTOCOL.md
+pub const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.1";
+pub const NODEINFO_CONTENT_TYPE: &str =
+    "application/json; profile=\"http://nodeinfo.diaspora.software/ns/schema/2.1#\"";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeInfoLink {
+    pub rel: String,
+    pub href: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeInfoDiscovery {
+    pub links: Vec<NodeInfoLink>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Software {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Services {
+    pub inbound: Vec<String>,
+    pub outbound: Vec<String>,
+}
+
+// Every count is optional in the schema, which is how the stats are hidden
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserUsage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+    #[serde(rename = "activeMonth", skip_serializing_if = "Option::is_none")]
+    pub active_month: Option<i64>,
+    #[serde(rename = "activeHalfyear", skip_serializing_if = "Option::is_none")]
+    pub active_halfyear: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub users: UserUsage,
+    #[serde(rename = "localPosts", skip_serializing_if = "Option::is_none")]
+    pub local_posts: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub version: String,
+    pub software: Software,
+    pub protocols: Vec<String>,
+    pub services: Services,
+    #[serde(rename = "openRegistrations")]
+    pub open_registrations: bool,
+    pub usage: Usage,
+    pub metadata: serde_json::Value,
+}
+
+pub fn get_discovery(domain: &str) -> NodeInfoDiscovery {
+    NodeInfoDiscovery {
+        links: vec![NodeInfoLink {
+            rel: NODEINFO_SCHEMA.to_owned(),
+            href: format!("https://{}/nodeinfo/2.1", domain),
+        }],
+    }
+}
+
+pub fn get_nodeinfo(db: &Connection, hide_usage_stats: bool) -> InternalResult<NodeInfo> {
+    let usage = if hide_usage_stats {
+        Usage::default()
+    } else {
+        get_usage(db)?
+    };
+
+    Ok(NodeInfo {
+        version: "2.1".to_owned(),
+        software: Software {
+            name: env!("CARGO_PKG_NAME").to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        },
+        protocols: vec!["activitypub".to_owned()],
+        services: Services { inbound: vec![], outbound: vec![] },
+        // Profiles are only ever created by the instance owner
+        open_registrations: false,
+        usage,
+        metadata: serde_json::json!({}),
+    })
+}
+
+// A profile counts as active if it has posted within the window
+fn get_usage(db: &Connection) -> InternalResult<Usage> {
+    let (total, active_month, active_halfyear, local_posts) = db.query_row(
+        "SELECT
+            (SELECT COUNT(*) FROM profiles),
+            (SELECT COUNT(DISTINCT profile_id) FROM posts
+                WHERE created_at > strftime('%FT%TZ', 'now', '-30 days')),
+            (SELECT COUNT(DISTINCT profile_id) FROM posts
+                WHERE created_at > strftime('%FT%TZ', 'now', '-180 days')),
+            (SELECT COUNT(*) FROM posts)",
+        (),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+
+    Ok(Usage {
+        users: UserUsage {
+            total: Some(total),
+            active_month: Some(active_month),
+            active_halfyear: Some(active_halfyear),
+        },
+        local_posts: Some(local_posts),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hidden_usage_has_no_counts() {
+        assert_eq!(json!(Usage::default()), json!({ "users": {} }));
+    }
+}
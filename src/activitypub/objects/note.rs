@@ -1,10 +1,12 @@
+use hyper::Uri;
 use minijinja::Value;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 
-use crate::{activitypub::PUBLIC_STREAM, server::server_response::InternalResult};
+use crate::{activitypub::{content::get_hashtag_url, PUBLIC_STREAM}, server::server_response::InternalResult};
 
-use super::outbox::CreateActivity;
+use super::outbox::{ActivityType, CreateActivity, DeleteActivity};
+use super::AtContext;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Post {
@@ -12,7 +14,10 @@ pub struct Post {
     pub content: String,
     pub created_at: String,
     pub url: String,
-    pub actor_id: String
+    pub actor_id: String,
+    pub in_reply_to: Option<String>,
+    pub tags: Vec<Tag>,
+    pub attachments: Vec<Attachment>
 }
 
 impl Post {
@@ -38,6 +43,8 @@ pub struct Note {
     pub url: String,
     pub summary: Option<String>,
     pub published: Option<String>,
+    #[serde(rename = "inReplyTo", default)]
+    pub in_reply_to: Option<String>,
     #[serde(rename = "attributedTo")]
     pub attributed_to: String,
     #[serde(default)]
@@ -48,7 +55,101 @@ pub struct Note {
     pub sensitive: bool,
     pub content: String,
     #[serde(default)]
-    pub tag: Vec<String>
+    pub tag: Vec<Tag>,
+    #[serde(default)]
+    pub attachment: Vec<Attachment>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AttachmentType {
+    Document,
+    Image,
+    Video,
+    Audio,
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
+}
+
+// https://www.w3.org/TR/activitystreams-vocabulary/#dfn-attachment
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(rename = "type")]
+    pub attachment_type: AttachmentType,
+    #[serde(rename = "mediaType", skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub url: String,
+    // This is where the alt text goes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// https://www.w3.org/TR/activitystreams-vocabulary/#microsyntaxes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Tag {
+    Mention {
+        href: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    Hashtag {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        href: Option<String>,
+        name: String,
+    },
+    // Emoji and the like, which we don't support yet
+    #[serde(other)]
+    Unknown,
+}
+
+impl Tag {
+    pub fn mention(actor_id: &str, handle: &str) -> Self {
+        Tag::Mention { href: actor_id.to_owned(), name: Some(handle.to_owned()) }
+    }
+
+    pub fn hashtag(name: &str, domain: &str) -> Self {
+        Tag::Hashtag { href: Some(get_hashtag_url(name, domain)), name: format!("#{}", name) }
+    }
+
+    /// The actor that a mention refers to
+    pub fn mentioned_actor(&self) -> Option<&str> {
+        match self {
+            Tag::Mention { href, .. } => Some(href),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TombstoneType {
+    Tombstone
+}
+
+// https://www.w3.org/TR/activitystreams-vocabulary/#dfn-tombstone
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<AtContext>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub _type: TombstoneType,
+    #[serde(rename = "formerType", skip_serializing_if = "Option::is_none")]
+    pub former_type: Option<NoteType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<String>,
+}
+
+impl Tombstone {
+    pub fn into_delete(self, actor_id: &str) -> DeleteActivity<Tombstone> {
+        DeleteActivity {
+            context: Some(AtContext::Context(super::Context::ActivityStreams)),
+            id: format!("{}#delete", self.id),
+            activity_type: ActivityType::Delete,
+            actor: actor_id.to_owned(),
+            to: vec![PUBLIC_STREAM.to_owned()],
+            object: self,
+        }
+    }
 }
 
 impl From<Note> for minijinja::Value {
@@ -59,27 +160,31 @@ impl From<Note> for minijinja::Value {
 
 impl From<Post> for Note {
     fn from(post: Post) -> Self {
-        let cc = vec![format!("{}/followers", &post.actor_id)];
+        // Everyone mentioned gets a copy, on top of the usual followers
+        let mut cc = vec![format!("{}/followers", &post.actor_id)];
+        cc.extend(post.tags.iter().filter_map(|tag| tag.mentioned_actor().map(str::to_owned)));
         Note {
             id: post.url.to_owned(),
             _type: NoteType::Note,
             url: post.url,
             summary: None,
             published: Some(post.created_at),
+            in_reply_to: post.in_reply_to,
             attributed_to: post.actor_id,
             to: vec![PUBLIC_STREAM.to_owned()],
             cc,
             sensitive: false,
             content: post.content,
-            tag: vec![]
+            tag: post.tags,
+            attachment: post.attachments
         }
     }
 }
 
 pub fn get_post(db: &Connection, post_id: &str, domain: &str) -> InternalResult<Post>{
-    let post = db.query_row(
+    let mut post = db.query_row(
         "
-        SELECT post_id, profile_id, content, created_at
+        SELECT post_id, profile_id, content, created_at, in_reply_to
         FROM posts
         LEFT JOIN profiles USING (profile_id)
         WHERE post_id = ?1
@@ -93,10 +198,140 @@ pub fn get_post(db: &Connection, post_id: &str, domain: &str) -> InternalResult<
                 content: row.get(2)?,
                 created_at: row.get(3)?,
                 url: format!("https://{}/posts/{}", domain, post_id),
-                actor_id: format!("https://{}/profiles/{}", domain, profile_id)
+                actor_id: format!("https://{}/profiles/{}", domain, profile_id),
+                in_reply_to: row.get(4)?,
+                tags: vec![],
+                attachments: vec![]
             };
             Ok(post)
         })?;
 
+    post.tags = get_post_tags(db, post.post_id)?;
+    post.attachments = get_post_attachments(db, post.post_id, domain)?;
     Ok(post)
 }
+
+pub fn get_post_tags(db: &Connection, post_id: i64) -> InternalResult<Vec<Tag>> {
+    let mut query = db.prepare("SELECT tag_type, href, name FROM post_tags WHERE post_id = ?1 ORDER BY rowid")?;
+    let rows = query.query_map([post_id], |row| {
+        let tag_type: String = row.get(0)?;
+        let href: String = row.get(1)?;
+        let name: String = row.get(2)?;
+        let tag = match tag_type.as_str() {
+            "Mention" => Tag::Mention { href, name: Some(name) },
+            "Hashtag" => Tag::Hashtag { href: Some(href), name },
+            _ => Tag::Unknown,
+        };
+        Ok(tag)
+    })?;
+
+    let tags: Vec<Tag> = rows.collect::<Result<_, _>>()?;
+    Ok(tags)
+}
+
+pub fn get_post_attachments(db: &Connection, post_id: i64, domain: &str) -> InternalResult<Vec<Attachment>> {
+    let mut query = db.prepare("SELECT media_id, media_type, alt_text FROM media WHERE post_id = ?1 ORDER BY rowid")?;
+    let rows = query.query_map([post_id], |row| {
+        let media_id: String = row.get(0)?;
+        let media_type: String = row.get(1)?;
+        let attachment_type = match media_type.starts_with("image/") {
+            true => AttachmentType::Image,
+            false => AttachmentType::Document,
+        };
+
+        let attachment = Attachment {
+            attachment_type,
+            media_type: Some(media_type),
+            url: format!("https://{}/media/{}", domain, media_id),
+            name: row.get(2)?,
+        };
+        Ok(attachment)
+    })?;
+
+    let attachments: Vec<Attachment> = rows.collect::<Result<_, _>>()?;
+    Ok(attachments)
+}
+
+pub fn save_post_tags(db: &Connection, post_id: i64, tags: &[Tag]) -> InternalResult<()> {
+    for tag in tags {
+        let (tag_type, href, name) = match tag {
+            Tag::Mention { href, name } => ("Mention", href.as_str(), name.as_deref().unwrap_or(href)),
+            Tag::Hashtag { href: Some(href), name } => ("Hashtag", href.as_str(), name.as_str()),
+            _ => continue,
+        };
+        db.execute(
+            "INSERT OR IGNORE INTO post_tags (post_id, tag_type, href, name) VALUES (?1, ?2, ?3, ?4)",
+            (post_id, tag_type, href, name))?;
+    }
+    Ok(())
+}
+
+/// Get the local post id from one of our own post URLs
+pub fn get_local_post_id(url: &str, domain: &str) -> Option<i64> {
+    let prefix = format!("https://{}/posts/", domain);
+    url.strip_prefix(&prefix)?.parse().ok()
+}
+
+/// Replies mention the author of the post they're replying to, so that it shows up in their
+/// notifications; returns None if we don't know about the parent post
+pub fn get_reply_mention(db: &Connection, in_reply_to: &str, domain: &str) -> InternalResult<Option<Tag>> {
+    let author: Option<(String, String)> = match get_local_post_id(in_reply_to, domain) {
+        Some(post_id) => db.query_row(
+            "SELECT profile_id, preferred_username FROM posts
+            INNER JOIN profiles USING (profile_id)
+            WHERE post_id = ?1",
+            [post_id],
+            |row| {
+                let profile_id: i64 = row.get(0)?;
+                Ok((format!("https://{}/profiles/{}", domain, profile_id), row.get(1)?))
+            })
+            .optional()?,
+        None => db.query_row(
+            "SELECT actor_id, preferred_username FROM remote_posts
+            INNER JOIN known_actors USING (actor_id)
+            WHERE object_id = ?1",
+            [in_reply_to],
+            |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?,
+    };
+
+    let mention = author.map(|(actor_id, preferred_username)| {
+        let host = actor_id.parse::<Uri>().ok()
+            .and_then(|uri| uri.host().map(str::to_owned))
+            .unwrap_or_else(|| domain.to_owned());
+        Tag::mention(&actor_id, &format!("@{}@{}", preferred_username, host))
+    });
+
+    Ok(mention)
+}
+
+pub fn get_tombstone(db: &Connection, post_id: &str, domain: &str) -> InternalResult<Option<Tombstone>> {
+    let deleted_at: Option<String> = db.query_row(
+        "SELECT deleted_at FROM tombstones WHERE post_id = ?1",
+        [post_id],
+        |row| row.get(0))
+        .optional()?;
+
+    let tombstone = deleted_at.map(|deleted_at| Tombstone {
+        context: None,
+        id: format!("https://{}/posts/{}", domain, post_id),
+        _type: TombstoneType::Tombstone,
+        former_type: Some(NoteType::Note),
+        deleted: Some(deleted_at),
+    });
+
+    Ok(tombstone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_local_post_ids() {
+        assert_eq!(get_local_post_id("https://example.com/posts/12", "example.com"), Some(12));
+        assert_eq!(get_local_post_id("https://example.com/posts/abc", "example.com"), None);
+        assert_eq!(get_local_post_id("https://elsewhere.com/posts/12", "example.com"), None);
+        assert_eq!(get_local_post_id("https://example.com/profiles/12", "example.com"), None);
+    }
+}
@@ -1,6 +1,6 @@
-use crate::{activitypub::PUBLIC_STREAM, query_row_custom, server::server_response::InternalResult};
+use crate::{query_map, query_row_custom, server::server_response::InternalResult};
 
-use super::{note::{Note, Post}, AtContext, Context};
+use super::{note::{self, get_post_attachments, get_post_tags, Note, Tombstone}, AtContext, Context};
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -10,26 +10,36 @@ pub enum OrderedCollectionType {
     OrderedCollection,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum OrderedCollectionPageType {
+    OrderedCollectionPage,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
-pub enum PageOrLink {
+pub enum PageOrLink<T = CreateActivity> {
     Link(String),
-    Page(OrderedCollectionPage),
+    Page(OrderedCollectionPage<T>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct OrderedCollectionPage {
+pub struct OrderedCollectionPage<T = CreateActivity> {
     #[serde(rename = "@context")]
     pub context: AtContext,
-    pub next: Option<Box<PageOrLink>>,
-    pub prev: Option<Box<PageOrLink>>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub _type: OrderedCollectionPageType,
+    #[serde(rename = "partOf", skip_serializing_if = "Option::is_none")]
+    pub part_of: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<Box<PageOrLink<T>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Box<PageOrLink<T>>>,
     #[serde(rename = "orderedItems")]
-    pub ordered_items: Vec<CreateActivity>,
+    pub ordered_items: Vec<T>,
 }
 pub type OutboxPage = OrderedCollectionPage;
 
-// type OutboxPage = OrderedCollectionPage;
-
 // https://www.w3.org/TR/activitystreams-core/#collection
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Outbox {
@@ -51,8 +61,11 @@ pub struct Outbox {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ActivityType {
     Accept,
+    Reject,
     Follow,
     Create,
+    Delete,
+    Update,
     Undo,
     #[serde(untagged)]
     Unknown(serde_json::Value),
@@ -91,6 +104,66 @@ pub struct FollowActivity {
     pub object: String
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteActivity<A> {
+    #[serde(rename = "@context")]
+    pub context: Option<AtContext>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub activity_type: ActivityType,
+    pub actor: String,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub object: A
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateActivity<A> {
+    #[serde(rename = "@context")]
+    pub context: Option<AtContext>,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub activity_type: ActivityType,
+    pub actor: String,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub object: A
+}
+
+// Deletes of posts usually embed a Tombstone, but some servers just send the deleted object's id
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TombstoneOrLink {
+    Link(String),
+    Tombstone(Tombstone),
+}
+
+impl TombstoneOrLink {
+    pub fn id(&self) -> &str {
+        match self {
+            TombstoneOrLink::Link(id) => id,
+            TombstoneOrLink::Tombstone(tombstone) => &tombstone.id,
+        }
+    }
+}
+
+// Accept and Reject can either embed the original Follow or just refer to it by id
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FollowOrLink {
+    Link(String),
+    Follow(FollowActivity),
+}
+
+impl FollowOrLink {
+    pub fn id(&self) -> &str {
+        match self {
+            FollowOrLink::Link(id) => id,
+            FollowOrLink::Follow(follow) => &follow.id,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateActivity {
     #[serde(rename = "@context")]
@@ -115,8 +188,8 @@ impl From<Note> for CreateActivity {
             id: note.url.to_owned(),
             actor: note.attributed_to.to_owned(),
             published: note.published.to_owned(),
-            to: vec![PUBLIC_STREAM.to_owned()],
-            cc: vec![format!("{}/followers", note.attributed_to)],
+            to: note.to.clone(),
+            cc: note.cc.clone(),
             object: Object::Note(note)
         }
     }
@@ -138,6 +211,8 @@ pub enum Object {
     Unknown(serde_json::Value),
 }
 
+pub const OUTBOX_PAGE_SIZE: i64 = 20;
+
 pub fn get_outbox(db: &Connection, profile_id: i64, domain: &str) -> InternalResult<Outbox> {
     let profile = query_row_custom!(
         db,
@@ -146,55 +221,120 @@ pub fn get_outbox(db: &Connection, profile_id: i64, domain: &str) -> InternalRes
         [profile_id]
     )?;
 
-    let outbox_url = format!("{}/profiles/{}/outbox", domain, profile_id);
-    // TODO: Pagination
-    let first_page = format!("{}/profiles/{}/outbox?page=1", domain, profile_id);
-    let last_page = format!("{}/profiles/{}/outbox?page=1", domain, profile_id);
+    let outbox_url = format!("https://{}/profiles/{}/outbox", domain, profile_id);
+    let last_page_num = get_page_count(profile.total_items, OUTBOX_PAGE_SIZE);
 
     let outbox = Outbox {
         context: AtContext::Context(Context::ActivityStreams),
-        id: outbox_url,
+        id: outbox_url.to_owned(),
         _type: OrderedCollectionType::OrderedCollection,
         total_items: profile.total_items,
         items: None,
-        first: PageOrLink::Link(first_page),
-        last: PageOrLink::Link(last_page),
+        first: PageOrLink::Link(format!("{}?page=1", outbox_url)),
+        last: PageOrLink::Link(format!("{}?page={}", outbox_url, last_page_num)),
         current: None
     };
 
     Ok(outbox)
 }
 
-pub fn get_outbox_page(db: &Connection, profile_id: i64, domain: &str, _page_num: usize) -> InternalResult<OutboxPage> {
-    let posts = db.query_row(
-        "SELECT post_id, profile_id, content, created_at FROM posts WHERE profile_id = ?1",
-        [ profile_id ],
-        |row| {
-            let post_id = row.get(0)?;
-            let profile_id: i64 = row.get(1)?;
-            let post = Post {
-                post_id,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-                url: format!("{}/posts/{}", domain, post_id),
-                actor_id: format!("{}/profiles/{}", domain, profile_id)
-            };
-            Ok(post)
+/// Pages are numbered from 1 and run newest-first, so page 1 always holds the most recent posts
+pub fn get_outbox_page(db: &Connection, profile_id: i64, domain: &str, page_num: i64) -> InternalResult<OutboxPage> {
+    let actor_id = format!("https://{}/profiles/{}", domain, profile_id);
+    let outbox_url = format!("{}/outbox", actor_id);
+
+    let fetch_posts = |limit: i64, offset: i64| {
+        let posts = query_map!(
+            db,
+            Post { post_id: i64, content: String, created_at: String, in_reply_to: Option<String> },
+            "FROM posts WHERE profile_id = ?1 ORDER BY created_at DESC, post_id DESC LIMIT ?2 OFFSET ?3",
+            (profile_id, limit, offset)
+        );
+        let posts = posts.into_iter().map(|post| note::Post {
+            post_id: post.post_id,
+            content: post.content,
+            created_at: post.created_at,
+            url: format!("https://{}/posts/{}", domain, post.post_id),
+            actor_id: actor_id.to_owned(),
+            in_reply_to: post.in_reply_to,
+            tags: Vec::new(),
+            attachments: Vec::new(),
         });
+        Ok(posts.collect())
+    };
+    // Tags and attachments are only looked up for the posts that make it onto the page
+    let into_create = |mut post: note::Post| {
+        post.tags = get_post_tags(db, post.post_id)?;
+        post.attachments = get_post_attachments(db, post.post_id, domain)?;
+        Ok(post.into_create())
+    };
 
-    let page_url = format!("{}/profiles/{}/outbox?page=1", domain, profile_id);
-    let items: Vec<CreateActivity>  = posts.into_iter()
-        .map(|post| { post.into_note() })
-        .map(|note| { note.into_create() })
-        .collect();
+    get_collection_page(&outbox_url, page_num, OUTBOX_PAGE_SIZE, fetch_posts, into_create)
+}
+
+/// Build page `page_num` of a collection, linking to its neighbours
+///
+/// `fetch` gets a limit and offset for the rows; one more row than the page holds is asked for,
+/// so that we know whether there is a next page without counting the whole collection.
+pub fn get_collection_page<R, T>(
+    collection_url: &str,
+    page_num: i64,
+    page_size: i64,
+    fetch: impl FnOnce(i64, i64) -> InternalResult<Vec<R>>,
+    into_item: impl FnMut(R) -> InternalResult<T>
+) -> InternalResult<OrderedCollectionPage<T>> {
+    let offset = (page_num - 1) * page_size;
+    let mut rows = fetch(page_size + 1, offset)?;
 
+    let has_next = rows.len() as i64 > page_size;
+    rows.truncate(page_size as usize);
+    let ordered_items = rows.into_iter().map(into_item).collect::<InternalResult<Vec<T>>>()?;
 
+    let page_link = |num: i64| Box::new(PageOrLink::Link(format!("{}?page={}", collection_url, num)));
     let page = OrderedCollectionPage {
         context: AtContext::Context(Context::ActivityStreams),
-        next: None,
-        prev: Some(Box::new(PageOrLink::Link(page_url.to_owned()))),
-        ordered_items: items
+        id: format!("{}?page={}", collection_url, page_num),
+        _type: OrderedCollectionPageType::OrderedCollectionPage,
+        part_of: Some(collection_url.to_owned()),
+        next: if has_next { Some(page_link(page_num + 1)) } else { None },
+        prev: if page_num > 1 { Some(page_link(page_num - 1)) } else { None },
+        ordered_items
     };
 
     Ok(page)
 }
+
+/// An empty collection still has a (blank) first page
+pub fn get_page_count(total_items: i64, page_size: i64) -> i64 {
+    ((total_items + page_size - 1) / page_size).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_pages() {
+        assert_eq!(get_page_count(0, 20), 1);
+        assert_eq!(get_page_count(1, 20), 1);
+        assert_eq!(get_page_count(20, 20), 1);
+        assert_eq!(get_page_count(21, 20), 2);
+        assert_eq!(get_page_count(60, 20), 3);
+    }
+
+    #[test]
+    fn links_collection_pages() {
+        let items: Vec<i64> = (1..=5).collect();
+        let fetch = |limit: i64, offset: i64| Ok(items.iter().copied().skip(offset as usize).take(limit as usize).collect());
+
+        let page = get_collection_page("https://example.com/c", 1, 2, fetch, Ok).unwrap();
+        assert_eq!(page.ordered_items, vec![1, 2]);
+        assert!(page.prev.is_none());
+        assert!(matches!(page.next.as_deref(), Some(PageOrLink::Link(l)) if l == "https://example.com/c?page=2"));
+
+        let page = get_collection_page("https://example.com/c", 3, 2, fetch, Ok).unwrap();
+        assert_eq!(page.ordered_items, vec![5]);
+        assert!(page.next.is_none());
+        assert!(matches!(page.prev.as_deref(), Some(PageOrLink::Link(l)) if l == "https://example.com/c?page=2"));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::activitypub::objects::actor::Actor;
+use crate::activitypub::objects::actor::{Actor, PublicKey};
 use crate::activitypub::objects::outbox::{OrderedCollectionPage, Outbox};
 use crate::activitypub::objects::webfinger::WebFinger;
 use crate::activitypub::signature::get_signature_header;
 use crate::server::error::{map_bad_gateway, ServerError};
-use crate::server::server_request::{CurrentProfile, SHORT_ACCEPT_HEADER};
+use crate::server::accept::ACTIVITY_JSON;
+use crate::server::server_request::CurrentProfile;
 use crate::server::server_response::InternalResult;
 use crate::server::utils;
 use chrono::Utc;
@@ -13,6 +14,14 @@ use hyper::{Method, Uri};
 use openssl::base64;
 use reqwest::{RequestBuilder, Response};
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+// Both actors and standalone key documents embed the key under "publicKey"
+#[derive(Deserialize)]
+struct KeyDocument {
+    #[serde(rename = "publicKey")]
+    public_key: PublicKey,
+}
 
 fn build_activitypub_request(
     method: Method,
@@ -36,7 +45,7 @@ fn build_activitypub_request(
         .request(method.clone(), url)
         .header(DATE, date_header)
         .header(USER_AGENT, "Mastodon/3.1.3")
-        .header(ACCEPT, HeaderValue::from_static(SHORT_ACCEPT_HEADER));
+        .header(ACCEPT, HeaderValue::from_static(ACTIVITY_JSON));
 
     let signature;
     if let Some(body) = body {
@@ -56,7 +65,7 @@ fn build_activitypub_request(
     Ok(request)
 }
 
-async fn get_from_ap<'a, T>(uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<T>
+async fn get_from_ap<T>(uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<T>
 where
     T: DeserializeOwned,
 {
@@ -67,7 +76,7 @@ where
     Ok(item)
 }
 
-pub async fn send_as<'a>(uri: &Uri, profile: &CurrentProfile, body: String) -> InternalResult<Response>
+pub async fn send_as(uri: &Uri, profile: &CurrentProfile, body: String) -> InternalResult<Response>
 {
     let request = build_activitypub_request(Method::POST, uri, profile, Some(body))?;
     let res = request.send().await.map_err(map_bad_gateway)?;
@@ -78,6 +87,11 @@ pub async fn get_actor(uri: &Uri, current_profile: &CurrentProfile) -> InternalR
     get_from_ap(uri, current_profile).await
 }
 
+pub async fn get_public_key(uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<PublicKey> {
+    let document: KeyDocument = get_from_ap(uri, current_profile).await?;
+    Ok(document.public_key)
+}
+
 pub async fn get_outbox(uri: &Uri, current_profile: &CurrentProfile) -> InternalResult<Outbox> {
     get_from_ap(uri, current_profile).await
 }
@@ -92,7 +106,7 @@ pub async fn get_webfinger(host: &str, account_name: &str) -> InternalResult<Web
     let request = reqwest::Client::new()
         .get(uri)
         .header(USER_AGENT, "curl/8.4.0")
-        .header(ACCEPT, HeaderValue::from_static(SHORT_ACCEPT_HEADER))
+        .header(ACCEPT, HeaderValue::from_static(ACTIVITY_JSON))
         .query(&[("resource", resource)]);
     let res = request.send().await.map_err(map_bad_gateway)?;
     let text = res.text().await.map_err(map_bad_gateway)?;
@@ -1,16 +1,16 @@
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use chrono_tz::Tz;
-use hyper::header::HeaderValue;
-use hyper::{Method, Uri};
+use hyper::header::{HeaderValue, DATE};
+use hyper::{HeaderMap, Method, Uri};
 use openssl::base64;
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
 use openssl::pkey::Private;
 use openssl::rsa::Padding;
-use openssl::sign::Signer;
+use openssl::sign::{Signer, Verifier};
 
-use crate::server::error::ServerError;
+use crate::server::error::{unauthorized, ServerError};
 
 pub fn get_signature_header(
     method: &Method,
@@ -58,3 +58,303 @@ fn get_signature(
     let vec = signer.sign_to_vec()?;
     Ok(base64::encode_block(&vec))
 }
+
+// Remote servers' clocks drift, so allow some slack in both directions (these match Mastodon)
+const MAX_SIGNATURE_AGE_SECS: i64 = 60 * 60 * 12;
+const MAX_CLOCK_SKEW_SECS: i64 = 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub struct SignatureParams {
+    pub key_id: String,
+    pub algorithm: Option<String>,
+    pub headers: Vec<String>,
+    pub signature: String,
+}
+
+/// Everything needed to check an inbound signature, minus the sender's public key
+#[derive(Debug)]
+pub struct PendingVerification {
+    pub key_id: String,
+    pub signing_string: String,
+    pub signature: String,
+}
+
+pub fn parse_signature_header(header: &str) -> Result<SignatureParams, ServerError> {
+    let mut key_id = None;
+    let mut algorithm = None;
+    let mut headers = None;
+    let mut signature = None;
+
+    for pair in header.split(',') {
+        let (key, value) = pair
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| unauthorized("Malformed signature header"))?;
+        let value = value.trim_matches('"').to_owned();
+        match key {
+            "keyId" => key_id = Some(value),
+            "algorithm" => algorithm = Some(value),
+            "headers" => headers = Some(value),
+            "signature" => signature = Some(value),
+            _ => {}
+        }
+    }
+
+    // Per the spec, only the date is signed if the headers parameter is missing
+    let headers = headers
+        .unwrap_or("date".to_owned())
+        .split_whitespace()
+        .map(|h| h.to_lowercase())
+        .collect();
+
+    Ok(SignatureParams {
+        key_id: key_id.ok_or_else(|| unauthorized("Signature is missing keyId"))?,
+        algorithm,
+        headers,
+        signature: signature.ok_or_else(|| unauthorized("Signature is missing signature"))?,
+    })
+}
+
+/// Check everything about an inbound request's signature that doesn't require the public key,
+/// and rebuild the string that the sender should have signed
+pub fn prepare_verification(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<PendingVerification, ServerError> {
+    let signature_header = headers
+        .get("signature")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| unauthorized("Request is not signed"))?;
+    let params = parse_signature_header(signature_header)?;
+
+    match params.algorithm.as_deref() {
+        None | Some("rsa-sha256") | Some("hs2019") => {}
+        Some(a) => return Err(unauthorized(&format!("Unsupported signature algorithm {}", a))),
+    }
+
+    for required in ["(request-target)", "host", "date"] {
+        if !params.headers.iter().any(|h| h == required) {
+            return Err(unauthorized(&format!("Signature does not cover {}", required)));
+        }
+    }
+
+    check_date(headers)?;
+
+    if !body.is_empty() {
+        if !params.headers.iter().any(|h| h == "digest") {
+            return Err(unauthorized("Signature does not cover digest"));
+        }
+        check_digest(headers, body)?;
+    }
+
+    let signing_string = get_signing_string(method, uri, headers, &params.headers)?;
+    Ok(PendingVerification {
+        key_id: params.key_id,
+        signing_string,
+        signature: params.signature,
+    })
+}
+
+pub fn verify_signature(
+    signing_string: &str,
+    signature: &str,
+    public_key_pem: &str,
+) -> Result<bool, ServerError> {
+    let pkey = PKey::public_key_from_pem(public_key_pem.as_bytes())?;
+    let signature = base64::decode_block(signature)
+        .map_err(|_| unauthorized("Signature is not valid base64"))?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+    verifier.set_rsa_padding(Padding::PKCS1)?;
+    verifier.update(signing_string.as_bytes())?;
+    Ok(verifier.verify(&signature).unwrap_or(false))
+}
+
+/// A key can only speak for an actor on the same server, or anyone could claim to be anyone by
+/// hosting a key document that names them as its owner
+pub fn is_same_origin(key_id: &str, owner: &str) -> bool {
+    let origin = |id: &str| {
+        let uri = id.parse::<Uri>().ok()?;
+        let port = uri.port_u16();
+        Some((uri.scheme_str()?.to_owned(), uri.host()?.to_ascii_lowercase(), port))
+    };
+    match (origin(key_id), origin(owner)) {
+        (Some(key_origin), Some(owner_origin)) => key_origin == owner_origin,
+        _ => false,
+    }
+}
+
+fn get_signing_string(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    signed_headers: &[String],
+) -> Result<String, ServerError> {
+    let lines = signed_headers
+        .iter()
+        .map(|name| {
+            if name == "(request-target)" {
+                let target = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                let method = method.as_str().to_lowercase();
+                return Ok(format!("(request-target): {} {}", method, target));
+            }
+
+            let mut values: Vec<&str> = headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            // HTTP/2 requests carry the host in the :authority pseudo-header, which hyper puts in the URI
+            if values.is_empty() && name == "host" {
+                values.extend(uri.authority().map(|a| a.as_str()));
+            }
+            if values.is_empty() {
+                return Err(unauthorized(&format!("Signed header {} is missing", name)));
+            }
+            Ok(format!("{}: {}", name, values.join(", ")))
+        })
+        .collect::<Result<Vec<String>, ServerError>>()?;
+
+    Ok(lines.join("\n"))
+}
+
+fn check_date(headers: &HeaderMap) -> Result<(), ServerError> {
+    let date = headers
+        .get(DATE)
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| unauthorized("Missing date header"))?;
+    let date = DateTime::parse_from_rfc2822(date)
+        .map_err(|_| unauthorized("Invalid date header"))?;
+
+    let age = (Utc::now() - date.with_timezone(&Utc)).num_seconds();
+    if !(-MAX_CLOCK_SKEW_SECS..=MAX_SIGNATURE_AGE_SECS).contains(&age) {
+        return Err(unauthorized("Signature date is out of range"));
+    }
+    Ok(())
+}
+
+fn check_digest(headers: &HeaderMap, body: &[u8]) -> Result<(), ServerError> {
+    let digest_header = headers
+        .get("digest")
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| unauthorized("Missing digest header"))?;
+
+    let expected = base64::encode_block(&openssl::sha::sha256(body));
+    let matches = digest_header
+        .split(',')
+        .filter_map(|d| d.trim().split_once('='))
+        .any(|(algorithm, value)| algorithm.eq_ignore_ascii_case("sha-256") && value == expected);
+
+    match matches {
+        true => Ok(()),
+        false => Err(unauthorized("Digest does not match body")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Etc::GMT;
+    use hyper::header::HOST;
+    use openssl::rsa::Rsa;
+
+    fn signed_headers(uri: &Uri, body: &str, pkey: &PKey<Private>) -> HeaderMap {
+        let date = Utc::now().with_timezone(&GMT);
+        let digest = format!("sha-256={}", base64::encode_block(&openssl::sha::sha256(body.as_bytes())));
+        let key_id = "https://example.com/profiles/1#main-key";
+        let signature =
+            get_signature_header(&Method::POST, key_id, uri, date, pkey, Some(digest.clone())).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_str(uri.host().unwrap()).unwrap());
+        let date = date.format("%a, %d %b %Y %X %Z").to_string();
+        headers.insert(DATE, HeaderValue::from_str(&date).unwrap());
+        headers.insert("digest", HeaderValue::from_str(&digest).unwrap());
+        headers.insert("signature", signature);
+        headers
+    }
+
+    #[test]
+    fn keys_only_speak_for_owners_on_the_same_host() {
+        assert!(is_same_origin("https://example.com/users/alex#main-key", "https://example.com/users/alex"));
+        assert!(is_same_origin("https://EXAMPLE.com/keys/1", "https://example.com/users/alex"));
+        assert!(!is_same_origin("https://evil.example/keys/1", "https://victim.example/users/alice"));
+        assert!(!is_same_origin("https://example.com:8443/keys/1", "https://example.com/users/alex"));
+        assert!(!is_same_origin("http://example.com/keys/1", "https://example.com/users/alex"));
+        assert!(!is_same_origin("not a uri", "https://example.com/users/alex"));
+    }
+
+    #[test]
+    fn parses_signature_header() {
+        let header = r#"keyId="https://example.com/users/a#main-key",algorithm="rsa-sha256",headers="(request-target) host date",signature="abc+/=""#;
+        let params = parse_signature_header(header).unwrap();
+        assert_eq!(params, SignatureParams {
+            key_id: "https://example.com/users/a#main-key".to_owned(),
+            algorithm: Some("rsa-sha256".to_owned()),
+            headers: vec!["(request-target)".to_owned(), "host".to_owned(), "date".to_owned()],
+            signature: "abc+/=".to_owned(),
+        })
+    }
+
+    #[test]
+    fn rejects_signature_without_key_id() {
+        let header = r#"headers="date",signature="abc""#;
+        let params = parse_signature_header(header);
+        assert_eq!(params, Err(unauthorized("Signature is missing keyId")))
+    }
+
+    #[test]
+    fn verifies_own_signature() {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key_pem = String::from_utf8(pkey.public_key_to_pem().unwrap()).unwrap();
+        let uri: Uri = "https://example.com/inbox".parse().unwrap();
+        let body = r#"{"type":"Follow"}"#;
+        let headers = signed_headers(&uri, body, &pkey);
+
+        let pending = prepare_verification(&Method::POST, &uri, &headers, body.as_bytes()).unwrap();
+        let verified = verify_signature(&pending.signing_string, &pending.signature, &public_key_pem);
+        assert_eq!(verified, Ok(true))
+    }
+
+    #[test]
+    fn verifies_signature_without_host_header() {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key_pem = String::from_utf8(pkey.public_key_to_pem().unwrap()).unwrap();
+        let uri: Uri = "https://example.com/inbox".parse().unwrap();
+        let body = r#"{"type":"Follow"}"#;
+        let mut headers = signed_headers(&uri, body, &pkey);
+        headers.remove(HOST);
+
+        let pending = prepare_verification(&Method::POST, &uri, &headers, body.as_bytes()).unwrap();
+        let verified = verify_signature(&pending.signing_string, &pending.signature, &public_key_pem);
+        assert_eq!(verified, Ok(true));
+
+        // Without a host header or an authority, there's nothing to check the signed host against
+        let uri: Uri = "/inbox".parse().unwrap();
+        let pending = prepare_verification(&Method::POST, &uri, &headers, body.as_bytes());
+        assert_eq!(pending.unwrap_err(), unauthorized("Signed header host is missing"))
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let uri: Uri = "https://example.com/inbox".parse().unwrap();
+        let headers = signed_headers(&uri, r#"{"type":"Follow"}"#, &pkey);
+
+        let pending = prepare_verification(&Method::POST, &uri, &headers, br#"{"type":"Undo"}"#);
+        assert_eq!(pending.unwrap_err(), unauthorized("Digest does not match body"))
+    }
+
+    #[test]
+    fn rejects_stale_date() {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let uri: Uri = "https://example.com/inbox".parse().unwrap();
+        let body = r#"{"type":"Follow"}"#;
+        let mut headers = signed_headers(&uri, body, &pkey);
+        headers.insert(DATE, HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        let pending = prepare_verification(&Method::POST, &uri, &headers, body.as_bytes());
+        assert_eq!(pending.unwrap_err(), unauthorized("Signature date is out of range"))
+    }
+}
@@ -1,22 +1,230 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+use crate::server::setup::normalize_domain;
+
+// Every setting can come from a flag, an environment variable or the config file, in that order
+// of precedence, falling back to the defaults below
+
 const PORT_DEFAULT: u16 = 3000;
+// Binding to localhost in development keeps MacOS from asking about incoming connections
+const HOST_DEFAULT: IpAddr = if cfg!(debug_assertions) {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+} else {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+};
+const DB_PATH_DEFAULT: &str = "./sailboat.db";
+const MEDIA_PATH_DEFAULT: &str = "./media";
+const LOG_LEVEL_DEFAULT: LevelFilter = LevelFilter::DEBUG;
+// Sessions last this long since they were last used
+const SESSION_TTL_DAYS_DEFAULT: i64 = 30;
+// Connections that requests can have open at once; background work has a few more of its own
+const DB_POOL_SIZE_DEFAULT: usize = 16;
+// How long open connections and deliveries get to finish once we're asked to stop
+const SHUTDOWN_TIMEOUT_SECS_DEFAULT: u64 = 10;
+// Only read if it exists; a file passed with --config or SB_CONFIG has to exist
+const CONFIG_FILE_DEFAULT: &str = "./sailboat.toml";
 
+#[derive(Clone, Debug)]
 pub struct Config {
     pub port: u16,
+    pub host: IpAddr,
+    pub db_path: String,
+    pub db_pool_size: usize,
+    /// The directory uploaded media is saved in
+    pub media_path: PathBuf,
+    /// Overrides the domain chosen during setup, e.g. for a tunnel in development
+    pub domain: Option<String>,
+    pub log_level: LevelFilter,
+    pub hide_usage_stats: bool,
+    pub session_ttl_secs: i64,
+    pub shutdown_timeout: Duration,
+    /// PEM files to terminate TLS with; both or neither are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// Plain HTTP port that redirects everything to HTTPS, only when TLS is on
+    pub http_redirect_port: Option<u16>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    port: Option<u16>,
+    host: Option<String>,
+    db_path: Option<String>,
+    db_pool_size: Option<usize>,
+    media_path: Option<String>,
+    domain: Option<String>,
+    log_level: Option<String>,
+    hide_usage_stats: Option<bool>,
+    session_ttl_days: Option<i64>,
+    shutdown_timeout_secs: Option<u64>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    http_redirect_port: Option<u16>,
 }
 
 impl Config {
     pub fn new(mut args: Vec<String>) -> Config {
-        let port_string = find_flag_with_value(&mut args, "--port");
-        let port = match port_string {
-            None => PORT_DEFAULT,
-            Some(port_string) => port_string
-                .parse()
-                .unwrap_or_else(|_| panic!("Invalid value for port: {}", port_string)),
+        let env: HashMap<String, String> = std::env::vars().collect();
+        let file = match find_setting(&mut args, &env, "--config", "SB_CONFIG") {
+            Some(path) => read_config_file(&path),
+            None if Path::new(CONFIG_FILE_DEFAULT).exists() => read_config_file(CONFIG_FILE_DEFAULT),
+            None => ConfigFile::default(),
+        };
+        Config::from_sources(args, &env, file)
+    }
+
+    fn from_sources(mut args: Vec<String>, env: &HashMap<String, String>, file: ConfigFile) -> Config {
+        let port = match find_setting(&mut args, env, "--port", "SB_PORT") {
+            Some(port) => port.parse().unwrap_or_else(|_| panic!("Invalid value for port: {}", port)),
+            None => file.port.unwrap_or(PORT_DEFAULT),
+        };
+
+        let host = match find_setting(&mut args, env, "--host", "SB_HOST").or(file.host) {
+            Some(host) => host.parse().unwrap_or_else(|_| panic!("Invalid value for host: {}", host)),
+            None => HOST_DEFAULT,
+        };
+
+        // DB_PATH is what this was called before there was a config file
+        let db_path = find_setting(&mut args, env, "--db-path", "SB_DB_PATH")
+            .or_else(|| env.get("DB_PATH").cloned())
+            .or(file.db_path)
+            .unwrap_or(DB_PATH_DEFAULT.to_owned());
+        if db_path.is_empty() {
+            panic!("Invalid value for database path: it can't be empty");
+        }
+
+        let db_pool_size = match find_setting(&mut args, env, "--db-pool-size", "SB_DB_POOL_SIZE") {
+            Some(size) => size.parse().unwrap_or_else(|_| panic!("Invalid value for database pool size: {}", size)),
+            None => file.db_pool_size.unwrap_or(DB_POOL_SIZE_DEFAULT),
+        };
+        if db_pool_size == 0 {
+            panic!("Invalid value for database pool size: {}", db_pool_size);
+        }
+
+        // MEDIA_PATH is what this was called before there was a config file
+        let media_path = find_setting(&mut args, env, "--media-path", "SB_MEDIA_PATH")
+            .or_else(|| env.get("MEDIA_PATH").cloned())
+            .or(file.media_path)
+            .unwrap_or(MEDIA_PATH_DEFAULT.to_owned());
+        if media_path.is_empty() {
+            panic!("Invalid value for media path: it can't be empty");
+        }
+        let media_path = PathBuf::from(media_path);
+
+        let domain = find_setting(&mut args, env, "--domain", "SB_DOMAIN")
+            .or(file.domain)
+            .map(|domain| normalize_domain(&domain)
+                .unwrap_or_else(|_| panic!("Invalid value for domain: {}", domain)));
+
+        let log_level = match find_setting(&mut args, env, "--log-level", "SB_LOG_LEVEL").or(file.log_level) {
+            Some(level) => LevelFilter::from_str(&level)
+                .unwrap_or_else(|_| panic!("Invalid value for log level: {}", level)),
+            None => LOG_LEVEL_DEFAULT,
+        };
+
+        let hide_usage_stats = if find_flag(&mut args, "--hide-usage-stats") {
+            true
+        } else {
+            match env.get("SB_HIDE_USAGE_STATS") {
+                Some(value) => parse_bool(value)
+                    .unwrap_or_else(|| panic!("Invalid value for SB_HIDE_USAGE_STATS: {}", value)),
+                None => file.hide_usage_stats.unwrap_or(false),
+            }
+        };
+
+        let session_ttl_days = match find_setting(&mut args, env, "--session-ttl-days", "SB_SESSION_TTL_DAYS") {
+            Some(days) => days.parse().unwrap_or_else(|_| panic!("Invalid value for session TTL: {}", days)),
+            None => file.session_ttl_days.unwrap_or(SESSION_TTL_DAYS_DEFAULT),
+        };
+        if session_ttl_days <= 0 {
+            panic!("Invalid value for session TTL: {}", session_ttl_days);
+        }
+        let session_ttl_secs = session_ttl_days * 60 * 60 * 24;
+
+        let shutdown_timeout_secs = match find_setting(&mut args, env, "--shutdown-timeout-secs", "SB_SHUTDOWN_TIMEOUT_SECS") {
+            Some(secs) => secs.parse().unwrap_or_else(|_| panic!("Invalid value for shutdown timeout: {}", secs)),
+            None => file.shutdown_timeout_secs.unwrap_or(SHUTDOWN_TIMEOUT_SECS_DEFAULT),
+        };
+        let shutdown_timeout = Duration::from_secs(shutdown_timeout_secs);
+
+        let tls_cert_path = find_setting(&mut args, env, "--tls-cert", "SB_TLS_CERT").or(file.tls_cert);
+        let tls_key_path = find_setting(&mut args, env, "--tls-key", "SB_TLS_KEY").or(file.tls_key);
+        match (&tls_cert_path, &tls_key_path) {
+            (Some(_), None) => panic!("Invalid value for TLS key: it's required with a TLS certificate"),
+            (None, Some(_)) => panic!("Invalid value for TLS certificate: it's required with a TLS key"),
+            _ => {}
+        }
+
+        let http_redirect_port = match find_setting(&mut args, env, "--http-redirect-port", "SB_HTTP_REDIRECT_PORT") {
+            Some(port) => Some(port.parse().unwrap_or_else(|_| panic!("Invalid value for HTTP redirect port: {}", port))),
+            None => file.http_redirect_port,
         };
-        Config { port }
+        if http_redirect_port.is_some() && tls_cert_path.is_none() {
+            panic!("Invalid value for HTTP redirect port: it only works with TLS enabled");
+        }
+        if http_redirect_port == Some(port) {
+            panic!("Invalid value for HTTP redirect port: it can't be the same as the port");
+        }
+
+        // The first argument is the binary itself
+        if let Some(arg) = args.get(1) {
+            panic!("Unknown argument: {}", arg);
+        }
+
+        Config {
+            port,
+            host,
+            db_path,
+            db_pool_size,
+            media_path,
+            domain,
+            log_level,
+            hide_usage_stats,
+            session_ttl_secs,
+            shutdown_timeout,
+            tls_cert_path,
+            tls_key_path,
+            http_redirect_port,
+        }
+    }
+}
+
+fn read_config_file(path: &str) -> ConfigFile {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", path, e));
+    toml::from_str(&contents).unwrap_or_else(|e| panic!("Invalid config file {}: {}", path, e))
+}
+
+fn find_setting(
+    args: &mut Vec<String>,
+    env: &HashMap<String, String>,
+    flag: &'static str,
+    var: &str,
+) -> Option<String> {
+    find_flag_with_value(args, flag).or_else(|| env.get(var).cloned())
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" | "" => Some(false),
+        _ => None,
     }
 }
 
+fn find_flag(args: &mut Vec<String>, flag: &'static str) -> bool {
+    args.iter().position(|x| x == flag).map(|index| args.remove(index)).is_some()
+}
+
 fn find_flag_with_value(args: &mut Vec<String>, flag: &'static str) -> Option<String> {
     args.iter().position(|x| x == flag).map(|index| {
         if index + 1 == args.len() {
@@ -34,31 +242,161 @@ mod tests {
         ($($x:expr),*) => (vec![$($x.to_string()),*]);
     }
 
+    macro_rules! str_map {
+        ($($k:expr => $v:expr),*) => (HashMap::from([$(($k.to_string(), $v.to_string())),*]));
+    }
+
+    // Keeps the tests independent of whatever is set in the environment they run in
+    fn from_args(args: Vec<String>) -> Config {
+        Config::from_sources(args, &HashMap::new(), ConfigFile::default())
+    }
+
     #[test]
     fn no_args_default() {
         let args = str_vec!["sailboat"];
-        let config = Config::new(args);
-        assert_eq!(config.port, 3000)
+        let config = from_args(args);
+        assert_eq!(config.port, 3000);
+        assert!(!config.hide_usage_stats)
+    }
+
+    #[test]
+    fn sets_hide_usage_stats() {
+        let args = str_vec!["sailboat", "--hide-usage-stats", "--port", "8080"];
+        let config = from_args(args);
+        assert!(config.hide_usage_stats);
+        assert_eq!(config.port, 8080)
     }
 
     #[test]
     fn sets_port_with_long_opt() {
         let args = str_vec!["sailboat", "--port", "8080"];
-        let config = Config::new(args);
+        let config = from_args(args);
         assert_eq!(config.port, 8080)
     }
 
+    #[test]
+    fn sets_session_ttl_in_days() {
+        let args = str_vec!["sailboat", "--session-ttl-days", "2"];
+        let config = from_args(args);
+        assert_eq!(config.session_ttl_secs, 60 * 60 * 24 * 2)
+    }
+
+    #[test]
+    fn sets_shutdown_timeout_in_secs() {
+        let args = str_vec!["sailboat", "--shutdown-timeout-secs", "45"];
+        let config = from_args(args);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(45))
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for session TTL: 0")]
+    fn invalid_session_ttl() {
+        let args = str_vec!["sailboat", "--session-ttl-days", "0"];
+        from_args(args);
+    }
+
     #[test]
     #[should_panic(expected = "Missing value for --port")]
     fn missing_value_after_port() {
         let args = str_vec!["sailboat", "--port"];
-        Config::new(args);
+        from_args(args);
     }
 
     #[test]
     #[should_panic(expected = "Invalid value for port: --other")]
     fn invalid_value_for_port() {
         let args = str_vec!["sailboat", "--port", "--other"];
-        Config::new(args);
+        from_args(args);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown argument: --prot")]
+    fn unknown_argument() {
+        let args = str_vec!["sailboat", "--prot", "8080"];
+        from_args(args);
+    }
+
+    #[test]
+    fn flags_override_env_override_file() {
+        let file: ConfigFile = toml::from_str(r#"
+            port = 4000
+            db-path = "/var/lib/sailboat/file.db"
+            media-path = "/var/lib/sailboat/media"
+            domain = "file.example"
+            log-level = "warn"
+            hide-usage-stats = true
+        "#).unwrap();
+        let env = str_map!["SB_PORT" => "5000", "SB_DOMAIN" => "https://Env.example/", "MEDIA_PATH" => "./old-media"];
+        let args = str_vec!["sailboat", "--port", "6000"];
+
+        let config = Config::from_sources(args, &env, file);
+        assert_eq!(config.port, 6000);
+        assert_eq!(config.domain.as_deref(), Some("env.example"));
+        assert_eq!(config.db_path, "/var/lib/sailboat/file.db");
+        assert_eq!(config.media_path, Path::new("./old-media"));
+        assert_eq!(config.log_level, LevelFilter::WARN);
+        assert!(config.hide_usage_stats);
+        assert_eq!(config.session_ttl_secs, 60 * 60 * 24 * SESSION_TTL_DAYS_DEFAULT);
+    }
+
+    #[test]
+    fn reads_legacy_db_path() {
+        let env = str_map!["DB_PATH" => "./old.db"];
+        let config = Config::from_sources(str_vec!["sailboat"], &env, ConfigFile::default());
+        assert_eq!(config.db_path, "./old.db");
+    }
+
+    #[test]
+    fn defaults_media_path() {
+        let config = from_args(str_vec!["sailboat"]);
+        assert_eq!(config.media_path, Path::new("./media"));
+        let config = from_args(str_vec!["sailboat", "--media-path", "/srv/media"]);
+        assert_eq!(config.media_path, Path::new("/srv/media"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for database pool size: 0")]
+    fn invalid_db_pool_size() {
+        let args = str_vec!["sailboat", "--db-pool-size", "0"];
+        from_args(args);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for host: localhost")]
+    fn invalid_host() {
+        let env = str_map!["SB_HOST" => "localhost"];
+        Config::from_sources(str_vec!["sailboat"], &env, ConfigFile::default());
+    }
+
+    #[test]
+    fn sets_tls_from_file() {
+        let file: ConfigFile = toml::from_str(r#"
+            tls-cert = "/etc/sailboat/cert.pem"
+            tls-key = "/etc/sailboat/key.pem"
+            http-redirect-port = 80
+        "#).unwrap();
+        let config = Config::from_sources(str_vec!["sailboat", "--port", "443"], &HashMap::new(), file);
+        assert_eq!(config.tls_cert_path.as_deref(), Some("/etc/sailboat/cert.pem"));
+        assert_eq!(config.tls_key_path.as_deref(), Some("/etc/sailboat/key.pem"));
+        assert_eq!(config.http_redirect_port, Some(80));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for TLS key")]
+    fn tls_cert_without_key() {
+        let args = str_vec!["sailboat", "--tls-cert", "cert.pem"];
+        from_args(args);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value for HTTP redirect port")]
+    fn http_redirect_without_tls() {
+        let args = str_vec!["sailboat", "--http-redirect-port", "80"];
+        from_args(args);
+    }
+
+    #[test]
+    fn rejects_unknown_file_settings() {
+        assert!(toml::from_str::<ConfigFile>("prot = 8080").is_err());
     }
 }
@@ -0,0 +1,145 @@
+-- Everything added between the initial schema and the setup wizard
+
+ALTER TABLE profiles ADD COLUMN summary TEXT;
+ALTER TABLE profiles ADD COLUMN avatar_url TEXT;
+ALTER TABLE profiles ADD COLUMN header_url TEXT;
+ALTER TABLE profiles ADD COLUMN hide_network INTEGER NOT NULL DEFAULT FALSE; -- only expose follower/following counts over ActivityPub
+
+-- Rebuilt rather than altered, since neither AUTOINCREMENT nor a default of the current time can be added to a table
+CREATE TABLE new_posts (
+  post_id INTEGER PRIMARY KEY AUTOINCREMENT, -- ids of deleted posts must never be reused
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  content TEXT,
+  in_reply_to TEXT, -- the id of the parent post, which may be local or remote
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+INSERT INTO new_posts (post_id, profile_id, content, created_at)
+SELECT post_id, profile_id, content, created_at FROM posts;
+DROP TABLE posts;
+ALTER TABLE new_posts RENAME TO posts;
+
+CREATE TABLE new_known_actors (
+  actor_id TEXT PRIMARY KEY, -- note that this HAS to be a URL
+  name TEXT NOT NULL,
+  preferred_username TEXT NOT NULL,
+  url TEXT,
+  inbox TEXT,
+  outbox TEXT,
+  summary TEXT,
+  icon_url TEXT,
+  host TEXT, -- the host in their handle, which isn't always the host of their actor_id
+  actor_json TEXT, -- the whole Actor document, as last fetched
+  fetched_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+-- Without an actor_json, these get fetched again the next time they're looked up
+INSERT INTO new_known_actors (actor_id, name, preferred_username, url, inbox, outbox, summary, icon_url)
+SELECT actor_id, name, preferred_username, url, inbox, outbox, summary, icon_url FROM known_actors;
+DROP TABLE known_actors;
+ALTER TABLE new_known_actors RENAME TO known_actors;
+
+CREATE INDEX known_actors_handle ON known_actors (preferred_username, host);
+
+CREATE TABLE post_tags (
+  post_id INTEGER NOT NULL REFERENCES posts ON DELETE CASCADE ON UPDATE CASCADE,
+  tag_type TEXT NOT NULL, -- 'Mention' or 'Hashtag'
+  href TEXT NOT NULL,
+  name TEXT NOT NULL, -- the handle for mentions, and the tag (with its #) for hashtags
+  UNIQUE (post_id, tag_type, href)
+) STRICT;
+
+CREATE INDEX post_tags_name ON post_tags (name COLLATE NOCASE);
+
+CREATE TABLE media (
+  media_id TEXT PRIMARY KEY, -- also the name of the file in the media directory
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  post_id INTEGER REFERENCES posts ON DELETE CASCADE ON UPDATE CASCADE,
+  media_type TEXT NOT NULL,
+  alt_text TEXT,
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+CREATE INDEX media_post_id ON media (post_id);
+
+CREATE TABLE tombstones (
+  post_id INTEGER PRIMARY KEY,
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  deleted_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+CREATE TABLE remote_posts (
+  object_id TEXT PRIMARY KEY, -- the Note's id, which is a URL
+  actor_id TEXT NOT NULL REFERENCES known_actors ON DELETE CASCADE ON UPDATE CASCADE,
+  url TEXT,
+  content TEXT NOT NULL,
+  in_reply_to TEXT,
+  attachments TEXT NOT NULL DEFAULT '[]', -- JSON array of {url, media_type, alt}
+  published TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+CREATE INDEX posts_in_reply_to ON posts (in_reply_to);
+CREATE INDEX remote_posts_in_reply_to ON remote_posts (in_reply_to);
+
+CREATE TABLE public_keys (
+  key_id TEXT PRIMARY KEY,
+  owner TEXT NOT NULL, -- the actor_id that this key signs for
+  public_key_pem TEXT NOT NULL,
+  fetched_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+-- Repeated follows used to be stored twice
+DELETE FROM followers
+WHERE rowid NOT IN (SELECT MIN(rowid) FROM followers GROUP BY profile_id, actor_id);
+CREATE UNIQUE INDEX followers_profile_actor ON followers (profile_id, actor_id);
+
+DELETE FROM following
+WHERE rowid NOT IN (SELECT MIN(rowid) FROM following GROUP BY profile_id, actor_id);
+CREATE UNIQUE INDEX following_profile_actor ON following (profile_id, actor_id);
+
+ALTER TABLE following ADD COLUMN follow_activity_id TEXT; -- the id of the Follow we sent, which the Accept will refer to
+ALTER TABLE following ADD COLUMN is_accepted INTEGER NOT NULL DEFAULT FALSE;
+
+-- Follows from before Accepts were tracked were always shown as accepted
+UPDATE following SET is_accepted = TRUE;
+
+CREATE TABLE deliveries (
+  delivery_id INTEGER PRIMARY KEY,
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  inbox TEXT NOT NULL,
+  body TEXT NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP)),
+  last_error TEXT,
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+CREATE INDEX deliveries_next_attempt_at ON deliveries (next_attempt_at);
+
+-- Sessions without a CSRF token can't be used any more, so everyone signs in again
+DROP TABLE sessions;
+CREATE TABLE sessions (
+  token TEXT NOT NULL UNIQUE,
+  csrf_token TEXT NOT NULL, -- sent back with every state-changing request
+  needs_second_factor INTEGER NOT NULL DEFAULT FALSE, -- the password was right, but there's a TOTP code to go
+  timestamp TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP)),
+  last_seen TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP)) -- sessions expire this long after
+) STRICT;
+
+-- There's a single admin login, so there's only ever one row
+CREATE TABLE credentials (
+  credential_id INTEGER PRIMARY KEY CHECK (credential_id = 1),
+  password_hash TEXT NOT NULL, -- $scrypt$ln=..,r=..,p=..$salt$hash
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  locked_until TEXT,
+  totp_secret TEXT, -- base32; two-factor authentication is on whenever this is set
+  totp_pending_secret TEXT, -- the secret being enrolled, until a code from it is confirmed
+  totp_last_step INTEGER, -- the time step of the last code used, so codes can't be replayed
+  updated_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+-- Single-use codes for logging in without the authenticator
+CREATE TABLE recovery_codes (
+  code_hash TEXT PRIMARY KEY, -- sha256 hex; the codes are random enough not to need a slow hash
+  used_at TEXT
+) STRICT;
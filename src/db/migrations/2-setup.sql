@@ -0,0 +1,12 @@
+-- The domain used to be hardcoded here; it's now chosen in the setup wizard
+CREATE UNIQUE INDEX globals_key ON globals (key);
+
+-- Servers that were already in use keep their domain and skip the wizard
+DELETE FROM globals
+WHERE key = 'domain' AND NOT EXISTS (SELECT 1 FROM profiles);
+
+INSERT INTO globals (key, value)
+SELECT 'setup_completed_at', strftime('%FT%TZ', CURRENT_TIMESTAMP)
+WHERE EXISTS (SELECT 1 FROM profiles)
+  AND EXISTS (SELECT 1 FROM credentials)
+  AND EXISTS (SELECT 1 FROM globals WHERE key = 'domain');
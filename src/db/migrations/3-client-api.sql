@@ -0,0 +1,29 @@
+-- Client apps that registered through the Mastodon API
+CREATE TABLE oauth_apps (
+  client_id TEXT PRIMARY KEY,
+  client_secret TEXT NOT NULL,
+  name TEXT NOT NULL,
+  website TEXT,
+  redirect_uris TEXT NOT NULL, -- one per line
+  scopes TEXT NOT NULL, -- space-separated, the most that the app can ask for
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+-- Handed back to an app once a profile is authorized, and swapped for an access token
+CREATE TABLE oauth_codes (
+  code_hash TEXT PRIMARY KEY, -- sha256 hex, like the access tokens
+  client_id TEXT NOT NULL REFERENCES oauth_apps ON DELETE CASCADE ON UPDATE CASCADE,
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scopes TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
+
+-- Each token acts as a single profile, unlike a browser session which can switch between them
+CREATE TABLE access_tokens (
+  token_hash TEXT PRIMARY KEY,
+  client_id TEXT NOT NULL REFERENCES oauth_apps ON DELETE CASCADE ON UPDATE CASCADE,
+  profile_id INTEGER NOT NULL REFERENCES profiles ON DELETE CASCADE ON UPDATE CASCADE,
+  scopes TEXT NOT NULL,
+  created_at TEXT NOT NULL DEFAULT (strftime('%FT%TZ', CURRENT_TIMESTAMP))
+) STRICT;
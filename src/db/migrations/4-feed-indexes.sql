@@ -0,0 +1,3 @@
+-- Feeds filter each table down before sorting, so these cover the profile and actor feeds
+CREATE INDEX posts_profile_id ON posts (profile_id, created_at);
+CREATE INDEX remote_posts_actor_id ON remote_posts (actor_id, published);
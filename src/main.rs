@@ -1,50 +1,93 @@
 use crate::config::Config;
 use crate::server::context::GlobalContext;
 use hyper::body;
-use sqlite::initliaze_db;
+use crate::server::error::ServerError;
+use crate::server::setup::{create_setup_token, get_saved_domain, is_setup_complete};
+use sqlite::pool::Pool;
+use sqlite::{checkpoint_wal, get_conn, migrate_db};
 use std::env;
 use std::net::SocketAddr;
+use std::process::ExitCode;
 use std::sync::Arc;
+use shutdown::{wait_for_signal, Shutdown};
 use templates::load_env;
-use tokio::signal;
-use tokio_util::task::TaskTracker;
+use tls::{redirect_to_https, TlsAcceptor};
 
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use std::path::Path;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::convert::Infallible;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tracing::{error, info, level_filters::LevelFilter};
+use tracing::{error, info, warn};
 
 mod activitypub;
 mod config;
+mod mastodon;
+mod media;
 mod queries;
 mod router;
 mod server;
+mod shutdown;
 mod sqlite;
 mod static_files;
 mod templates;
+mod tls;
 
-const DEFAULT_DB: &str = "./sailboat.db";
+const DELIVERY_WORKERS: usize = 4;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let config = Config::new(env::args().collect());
-    let port = config.port;
-    let tracker = Arc::new(TaskTracker::new());
 
-    let db_path = std::env::var("DB_PATH").unwrap_or(DEFAULT_DB.to_owned());
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
 
-    // If db does not exist, create it
-    // TODO eventually this needs to be done with some kind of admin/setup panel
-    let has_db = Path::new(&db_path).exists();
-    if !has_db {
-        println!("No file for database found; creating one.");
-        initliaze_db(&db_path).expect("Failed to startup database");
-    } else {
-        println!("Found database file, initializing");
+    let db_path = config.db_path.clone();
+
+    // Creates the database if it doesn't exist yet, and applies any new migrations if it does
+    if let Err(e) = migrate_db(&db_path) {
+        error!("Failed to migrate database at {}: {}", db_path, e);
+        return ExitCode::FAILURE;
     }
 
+    // Bind before starting anything else, so a port that's taken fails the startup
+    let addr = SocketAddr::new(config.host, config.port);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen at {}: {}", addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => match TlsAcceptor::new(cert_path, key_path) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("Failed to load TLS certificate {} and key {}: {}", cert_path, key_path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+
+    let redirect_listener = match config.http_redirect_port {
+        Some(redirect_port) => {
+            let addr = SocketAddr::new(config.host, redirect_port);
+            match TcpListener::bind(addr).await {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    error!("Failed to listen at {}: {}", addr, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
+
     // Setup template environment
     let env = Arc::new(load_env());
 
@@ -52,76 +95,188 @@ async fn main() {
     let statics = static_files::load_static();
     let statics = Arc::new(statics);
 
-    let mut g_ctx = GlobalContext::new(env, statics);
+    if let Some(domain) = &config.domain {
+        info!("Running with callback domain: {}", domain);
+    }
 
-    // Right now this only available as a debug feature, but potentially available for more soon
-    if cfg!(debug_assertions) {
-        g_ctx.domain = env::var("SB_DOMAIN").ok();
-        if g_ctx.domain.is_none() {
-            println!("Dev mode running but no global callback domain is specified. Remote servers will not be able to call back.");
-        }
-        println!(
-            "Running with callback domain: {}",
-            g_ctx.domain.clone().unwrap()
-        );
+    let shutdown = Shutdown::new();
+    let pool = Pool::new(&db_path, config.db_pool_size);
+
+    // Start sending queued activities, including any left over from the last run
+    activitypub::delivery::start_workers(&shutdown, DELIVERY_WORKERS, &pool, config.domain.clone());
+    activitypub::actor_cache::start_refresher(&shutdown, &pool, config.domain.clone());
+
+    if let Some(tls) = &tls {
+        tls::start_reloader(&shutdown, tls.clone());
     }
 
+    let shutdown_timeout = config.shutdown_timeout;
+    let g_ctx = GlobalContext::new(env, statics, config, pool);
+    if let Err(e) = load_setup_state(&g_ctx) {
+        error!("Failed to read the setup state from the database: {}", e);
+        return ExitCode::FAILURE;
+    }
     let g_ctx = Arc::new(g_ctx);
+    // Started once the saved domain is loaded, since that's where it redirects to
+    if let Some(redirect_listener) = redirect_listener {
+        shutdown.spawn(run_redirect_server(redirect_listener, shutdown.clone(), g_ctx.clone()));
+    }
+    let mut server = tokio::spawn(run_server(listener, tls, shutdown.clone(), g_ctx));
 
-    // TODO this does not properly crash on startup if it can't bind a port
-    tokio::task::spawn(run_server(port, tracker.clone(), g_ctx));
-
-    // TODO upgrade this to handle interrupts
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Received shutdown signal, waiting for requests to end.");
-            // TODO actually exit gracefully
-            std::process::exit(0);
-            // tracker.close();
-            // tracker.wait().await;
-        }
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
+    let mut status = ExitCode::SUCCESS;
+    tokio::select! {
+        result = wait_for_signal() => match result {
+            Ok(()) => info!("Received shutdown signal, waiting for requests to end."),
+            Err(e) => {
+                error!("Unable to listen for shutdown signal: {}", e);
+                status = ExitCode::FAILURE;
+            }
+        },
+        result = &mut server => {
+            match result {
+                Ok(Ok(())) => error!("Server stopped unexpectedly"),
+                Ok(Err(e)) => error!("Server stopped unexpectedly: {}", e),
+                Err(e) => error!("Server crashed: {}", e),
+            }
+            status = ExitCode::FAILURE;
         }
     }
+
+    shutdown.request();
+    if !shutdown.drain(shutdown_timeout).await {
+        warn!("Requests and deliveries were still running after {:?}; exiting anyway", shutdown_timeout);
+        status = ExitCode::FAILURE;
+    }
+
+    if let Err(e) = checkpoint_wal(&db_path) {
+        error!("Failed to checkpoint the database: {}", e);
+        status = ExitCode::FAILURE;
+    }
+
+    info!("Shut down");
+    status
 }
 
-// Doing this because MacOS shows me an annoying notification for the latter
-const HOST: &str = if cfg!(debug_assertions) {
-    "127.0.0.1"
-} else {
-    "0.0.0.0"
-};
+// The domain and whether setup is done only change in the setup wizard, so they're read just once
+fn load_setup_state(g_ctx: &GlobalContext) -> Result<(), ServerError> {
+    let db = get_conn(&g_ctx.config.db_path)?;
+    if let Some(domain) = get_saved_domain(&db)? {
+        g_ctx.set_saved_domain(domain);
+    }
+    if is_setup_complete(&db)? {
+        g_ctx.mark_setup_complete();
+    } else {
+        let token = create_setup_token();
+        warn!("Setup isn't finished yet; enter this token in the setup wizard at /setup: {}", token);
+        g_ctx.set_setup_token(token);
+    }
+    Ok(())
+}
 
 async fn run_server(
-    port: u16,
-    tracker: Arc<TaskTracker>,
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
     g_ctx: Arc<GlobalContext<'static>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Setup logging (leaving at DEBUG level for now)
-    tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::DEBUG)
-        .init();
-    let addr: SocketAddr = format!("{}:{}", HOST, port).parse()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Now listening at {}://{}", scheme, listener.local_addr()?);
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Stop taking new connections; the listener closes when this returns
+            _ = shutdown.requested() => return Ok(()),
+        };
+
+        // Spawn a tokio task to serve multiple connections concurrently
+        let connection_shutdown = shutdown.clone();
+        let shared_ctx = g_ctx.clone();
+        let tls = tls.clone();
+        shutdown.spawn(async move {
+            // The handshake happens here rather than in the accept loop, so a slow client can't
+            // hold up everyone else
+            match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, connection_shutdown, shared_ctx).await,
+                    Err(e) => warn!("TLS handshake with {} failed: {}", remote_addr, e),
+                },
+                None => serve_connection(stream, connection_shutdown, shared_ctx).await,
+            }
+        });
+    }
+}
 
-    let listener = TcpListener::bind(addr).await?;
-    info!("Now listening at http://localhost:{}", port);
+// Speaks HTTP/1.1 or HTTP/2, whichever the client picked (over TLS, with ALPN)
+async fn serve_connection<I>(stream: I, shutdown: Shutdown, g_ctx: Arc<GlobalContext<'static>>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Wrapper to use Hyper traits with Tokio streams
+    let io = TokioIo::new(stream);
+
+    let service = service_fn(move |req: hyper::Request<body::Incoming>| {
+        router::serve(req, g_ctx.clone())
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection(io, service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        // Finish the requests in progress, but close the connection instead of keeping it alive
+        _ = shutdown.requested() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        error!("Error serving connection: {}", err);
+    }
+}
+
+// Plain HTTP only ever gets sent on to HTTPS, so nothing here touches the database or templates
+async fn run_redirect_server(listener: TcpListener, shutdown: Shutdown, g_ctx: Arc<GlobalContext<'static>>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Redirecting http://{} to HTTPS", addr);
+    }
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept HTTP connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.requested() => return,
+        };
 
-        // Wrapper to use Hyper traits with Tokio streams
         let io = TokioIo::new(stream);
-
-        let shared_ctx = g_ctx.clone(); // Why is this necessary?
+        let shared_ctx = g_ctx.clone();
         let service = service_fn(move |req: hyper::Request<body::Incoming>| {
-            router::serve(req, shared_ctx.clone())
+            let res = redirect_to_https(&req, shared_ctx.get_domain(), shared_ctx.config.port);
+            async move { Ok::<_, Infallible>(res) }
         });
 
-        // Spawn a tokio task to serve multiple connections concurrently
-        tracker.spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                error!("Error serving connection: {}", err);
+        let connection_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            let connection = http1::Builder::new().serve_connection(io, service);
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = connection_shutdown.requested() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+
+            if let Err(err) = result {
+                error!("Error serving redirect connection: {}", err);
             }
         });
     }
@@ -0,0 +1,85 @@
+use openssl::base64;
+
+pub mod account;
+pub mod instance;
+pub mod pagination;
+pub mod status;
+
+// Marks ids that stand in for a remote ActivityPub id, since local ones are always numbers
+const REMOTE_PREFIX: char = 'r';
+
+/// Mastodon ids have to fit in a path segment, so local posts and profiles go by their own ids,
+/// and remote ones by their ActivityPub id, encoded
+#[derive(Debug, PartialEq)]
+pub enum ApiId {
+    Local(i64),
+    Remote(String),
+}
+
+impl ApiId {
+    pub fn parse(id: &str) -> Option<ApiId> {
+        let Some(encoded) = id.strip_prefix(REMOTE_PREFIX) else {
+            return id.parse().ok().map(ApiId::Local);
+        };
+
+        let mut encoded = encoded.replace('-', "+").replace('_', "/");
+        while encoded.len() % 4 != 0 {
+            encoded.push('=');
+        }
+        let decoded = base64::decode_block(&encoded).ok()?;
+        String::from_utf8(decoded).ok().map(ApiId::Remote)
+    }
+
+    /// The ActivityPub id, where local ones live under the given path (e.g. "posts")
+    pub fn to_object_id(&self, path: &str, domain: &str) -> String {
+        match self {
+            ApiId::Local(id) => format!("https://{}/{}/{}", domain, path, id),
+            ApiId::Remote(object_id) => object_id.clone(),
+        }
+    }
+}
+
+pub fn get_status_id(object_id: &str, domain: &str) -> String {
+    get_api_id(object_id, &format!("https://{}/posts/", domain))
+}
+
+pub fn get_account_id(actor_id: &str, domain: &str) -> String {
+    get_api_id(actor_id, &format!("https://{}/profiles/", domain))
+}
+
+fn get_api_id(object_id: &str, local_prefix: &str) -> String {
+    match object_id.strip_prefix(local_prefix).and_then(|id| id.parse::<i64>().ok()) {
+        Some(id) => id.to_string(),
+        None => {
+            let encoded = base64::encode_block(object_id.as_bytes()).replace('+', "-").replace('/', "_");
+            format!("{}{}", REMOTE_PREFIX, encoded.trim_end_matches('='))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_ids_are_numbers() {
+        assert_eq!(get_status_id("https://example.com/posts/12", "example.com"), "12");
+        assert_eq!(ApiId::parse("12"), Some(ApiId::Local(12)));
+        assert_eq!(ApiId::Local(12).to_object_id("posts", "example.com"), "https://example.com/posts/12");
+    }
+
+    #[test]
+    fn remote_ids_round_trip() {
+        let object_id = "https://other.example/users/alex/statuses/1?x=y~";
+        let id = get_status_id(object_id, "example.com");
+        assert!(id.starts_with('r'));
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(ApiId::parse(&id), Some(ApiId::Remote(object_id.to_owned())));
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert_eq!(ApiId::parse("r!!"), None);
+        assert_eq!(ApiId::parse("twelve"), None);
+    }
+}
@@ -0,0 +1,208 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::activitypub::objects::actor::summary_to_html;
+use crate::mastodon::{get_account_id, ApiId};
+use crate::server::server_response::InternalResult;
+
+// https://docs.joinmastodon.org/entities/Account/
+#[derive(Debug, Clone, Serialize)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub acct: String,
+    pub display_name: String,
+    pub locked: bool,
+    pub bot: bool,
+    pub group: bool,
+    pub discoverable: bool,
+    pub created_at: String,
+    pub note: String,
+    pub url: String,
+    pub uri: String,
+    pub avatar: String,
+    pub avatar_static: String,
+    pub header: String,
+    pub header_static: String,
+    pub followers_count: i64,
+    pub following_count: i64,
+    pub statuses_count: i64,
+    pub last_status_at: Option<String>,
+    pub emojis: Vec<serde_json::Value>,
+    pub fields: Vec<serde_json::Value>,
+    // Only sent to the account's own apps, through verify_credentials
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    pub note: String,
+    pub fields: Vec<serde_json::Value>,
+    pub privacy: &'static str,
+    pub sensitive: bool,
+    pub language: Option<String>,
+    pub follow_requests_count: i64,
+}
+
+pub fn get_account(db: &Connection, id: &ApiId, domain: &str) -> InternalResult<Option<Account>> {
+    match id {
+        ApiId::Local(profile_id) => get_local_account(db, *profile_id, domain),
+        ApiId::Remote(actor_id) => get_remote_account(db, actor_id, domain),
+    }
+}
+
+/// Look up whoever an ActivityPub actor id belongs to, whether it's one of ours or not
+pub fn get_account_for_actor(db: &Connection, actor_id: &str, domain: &str) -> InternalResult<Option<Account>> {
+    match ApiId::parse(&get_account_id(actor_id, domain)) {
+        Some(id) => get_account(db, &id, domain),
+        None => Ok(None),
+    }
+}
+
+// Profiles don't keep track of when they were made, so they go by their first post
+pub fn get_local_account(db: &Connection, profile_id: i64, domain: &str) -> InternalResult<Option<Account>> {
+    let account = db.query_row(
+        "SELECT preferred_username,
+            display_name,
+            summary,
+            avatar_url,
+            header_url,
+            COALESCE(
+                (SELECT MIN(created_at) FROM posts WHERE profile_id = ?1),
+                strftime('%FT%TZ', CURRENT_TIMESTAMP)
+            ),
+            (SELECT COUNT(*) FROM followers WHERE profile_id = ?1),
+            (SELECT COUNT(*) FROM following WHERE profile_id = ?1),
+            (SELECT COUNT(*) FROM posts WHERE profile_id = ?1),
+            (SELECT date(MAX(created_at)) FROM posts WHERE profile_id = ?1)
+        FROM profiles
+        WHERE profile_id = ?1",
+        [profile_id],
+        |row| {
+            let preferred_username: String = row.get(0)?;
+            let summary: Option<String> = row.get(2)?;
+            let avatar_url: Option<String> = row.get(3)?;
+            let header_url: Option<String> = row.get(4)?;
+
+            let url = format!("https://{}/profiles/{}", domain, profile_id);
+            let avatar = avatar_url.unwrap_or_else(|| get_default_avatar(domain));
+            let header = header_url.unwrap_or_default();
+
+            let account = Account {
+                id: profile_id.to_string(),
+                acct: preferred_username.clone(),
+                username: preferred_username,
+                display_name: row.get(1)?,
+                locked: false,
+                bot: false,
+                group: false,
+                discoverable: true,
+                created_at: row.get(5)?,
+                note: summary.as_deref().map(summary_to_html).unwrap_or_default(),
+                uri: url.clone(),
+                url,
+                avatar_static: avatar.clone(),
+                avatar,
+                header_static: header.clone(),
+                header,
+                followers_count: row.get(6)?,
+                following_count: row.get(7)?,
+                statuses_count: row.get(8)?,
+                last_status_at: row.get(9)?,
+                emojis: vec![],
+                fields: vec![],
+                source: None,
+            };
+            Ok(account)
+        },
+    ).optional()?;
+    Ok(account)
+}
+
+/// The account as its own apps see it, with the bio as it was written
+pub fn get_credential_account(db: &Connection, profile_id: i64, domain: &str) -> InternalResult<Option<Account>> {
+    let Some(mut account) = get_local_account(db, profile_id, domain)? else {
+        return Ok(None);
+    };
+
+    let summary: Option<String> = db.query_row(
+        "SELECT summary FROM profiles WHERE profile_id = ?1",
+        [profile_id],
+        |row| row.get(0),
+    )?;
+    account.source = Some(Source {
+        note: summary.unwrap_or_default(),
+        fields: vec![],
+        // Every post is public for now
+        privacy: "public",
+        sensitive: false,
+        language: None,
+        follow_requests_count: 0,
+    });
+    Ok(Some(account))
+}
+
+// We only know what other servers have sent us, so the network counts are left at zero
+pub fn get_remote_account(db: &Connection, actor_id: &str, domain: &str) -> InternalResult<Option<Account>> {
+    let account = db.query_row(
+        "SELECT name,
+            preferred_username,
+            url,
+            summary,
+            icon_url,
+            host,
+            COALESCE((SELECT MIN(published) FROM remote_posts WHERE actor_id = ?1), fetched_at),
+            (SELECT COUNT(*) FROM remote_posts WHERE actor_id = ?1),
+            (SELECT date(MAX(published)) FROM remote_posts WHERE actor_id = ?1)
+        FROM known_actors
+        WHERE actor_id = ?1",
+        [actor_id],
+        |row| {
+            let preferred_username: String = row.get(1)?;
+            let url: Option<String> = row.get(2)?;
+            let summary: Option<String> = row.get(3)?;
+            let icon_url: Option<String> = row.get(4)?;
+            let host: Option<String> = row.get(5)?;
+
+            let acct = match host {
+                Some(host) => format!("{}@{}", preferred_username, host),
+                None => preferred_username.clone(),
+            };
+            let avatar = icon_url.unwrap_or_else(|| get_default_avatar(domain));
+
+            let account = Account {
+                id: get_account_id(actor_id, domain),
+                username: preferred_username,
+                acct,
+                display_name: row.get(0)?,
+                locked: false,
+                bot: false,
+                group: false,
+                discoverable: false,
+                created_at: row.get(6)?,
+                note: summary.unwrap_or_default(),
+                url: url.unwrap_or_else(|| actor_id.to_owned()),
+                uri: actor_id.to_owned(),
+                avatar_static: avatar.clone(),
+                avatar,
+                header: String::new(),
+                header_static: String::new(),
+                followers_count: 0,
+                following_count: 0,
+                statuses_count: row.get(7)?,
+                last_status_at: row.get(8)?,
+                emojis: vec![],
+                fields: vec![],
+                source: None,
+            };
+            Ok(account)
+        },
+    ).optional()?;
+    Ok(account)
+}
+
+// The same one that local actors fall back to
+fn get_default_avatar(domain: &str) -> String {
+    format!("https://{}/static/images/pineapple.svg", domain)
+}
@@ -0,0 +1,114 @@
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::media::{MAX_ATTACHMENTS, MAX_UPLOAD_SIZE, SUPPORTED_MEDIA_TYPES};
+use crate::server::server_response::InternalResult;
+
+// Clients decide what to offer based on the Mastodon version, so claim the one we mostly match
+const COMPATIBLE_VERSION: &str = "4.0.0";
+// Nothing limits the length of posts, but clients insist on a number
+const MAX_CHARACTERS: i64 = 5000;
+
+// https://docs.joinmastodon.org/entities/V1_Instance/
+#[derive(Debug, Serialize)]
+pub struct Instance {
+    pub uri: String,
+    pub title: String,
+    pub short_description: String,
+    pub description: String,
+    pub email: String,
+    pub version: String,
+    pub urls: Urls,
+    pub stats: Stats,
+    pub thumbnail: Option<String>,
+    pub languages: Vec<String>,
+    pub registrations: bool,
+    pub approval_required: bool,
+    pub invites_enabled: bool,
+    pub configuration: Configuration,
+    pub contact_account: Option<serde_json::Value>,
+    pub rules: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Urls {
+    pub streaming_api: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub user_count: i64,
+    pub status_count: i64,
+    pub domain_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Configuration {
+    pub statuses: StatusConfiguration,
+    pub media_attachments: MediaConfiguration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusConfiguration {
+    pub max_characters: i64,
+    pub max_media_attachments: usize,
+    pub characters_reserved_per_url: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MediaConfiguration {
+    pub supported_mime_types: Vec<&'static str>,
+    pub image_size_limit: usize,
+    pub video_size_limit: usize,
+}
+
+pub fn get_instance(db: &Connection, domain: &str, hide_usage_stats: bool) -> InternalResult<Instance> {
+    let stats = if hide_usage_stats {
+        Stats::default()
+    } else {
+        get_stats(db)?
+    };
+
+    Ok(Instance {
+        uri: domain.to_owned(),
+        title: domain.to_owned(),
+        short_description: String::new(),
+        description: String::new(),
+        email: String::new(),
+        version: format!("{} (compatible; {} {})", COMPATIBLE_VERSION, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        urls: Urls { streaming_api: format!("wss://{}", domain) },
+        stats,
+        thumbnail: None,
+        languages: vec![],
+        // Profiles are only ever created by the instance owner
+        registrations: false,
+        approval_required: false,
+        invites_enabled: false,
+        configuration: Configuration {
+            statuses: StatusConfiguration {
+                max_characters: MAX_CHARACTERS,
+                max_media_attachments: MAX_ATTACHMENTS,
+                characters_reserved_per_url: 23,
+            },
+            media_attachments: MediaConfiguration {
+                supported_mime_types: SUPPORTED_MEDIA_TYPES.to_vec(),
+                image_size_limit: MAX_UPLOAD_SIZE,
+                video_size_limit: MAX_UPLOAD_SIZE,
+            },
+        },
+        contact_account: None,
+        rules: vec![],
+    })
+}
+
+fn get_stats(db: &Connection) -> InternalResult<Stats> {
+    let stats = db.query_row(
+        "SELECT
+            (SELECT COUNT(*) FROM profiles),
+            (SELECT COUNT(*) FROM posts),
+            (SELECT COUNT(DISTINCT host) FROM known_actors)",
+        (),
+        |row| Ok(Stats { user_count: row.get(0)?, status_count: row.get(1)?, domain_count: row.get(2)? }),
+    )?;
+    Ok(stats)
+}
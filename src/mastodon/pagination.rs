@@ -0,0 +1,134 @@
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::mastodon::{get_status_id, ApiId};
+use crate::queries::{get_post_by_object_id, FeedQuery};
+use crate::server::server_response::InternalResult;
+use crate::templates::_partials::post::Post;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 40;
+
+/// The usual query for a list of statuses, where each id is the status on the far side of the page
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    pub max_id: Option<String>,
+    pub since_id: Option<String>,
+    pub min_id: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl PageQuery {
+    /// Find where the page starts and ends in the feed, or None when there can't be anything on it
+    pub fn get_feed_query(&self, db: &Connection, domain: &str) -> InternalResult<Option<FeedQuery>> {
+        let find = |id: &str| -> InternalResult<Option<(String, String)>> {
+            let Some(id) = ApiId::parse(id) else {
+                return Ok(None);
+            };
+            let post = get_post_by_object_id(db, &id.to_object_id("posts", domain), domain)?;
+            Ok(post.map(|post| (post.created_at, post.object_id)))
+        };
+
+        // A page can't start after a status that's no longer there
+        let before = match &self.max_id {
+            Some(max_id) => match find(max_id)? {
+                Some(cursor) => Some(cursor),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        let after = match self.min_id.as_ref().or(self.since_id.as_ref()) {
+            Some(id) => find(id)?,
+            None => None,
+        };
+
+        Ok(Some(FeedQuery {
+            before,
+            after,
+            // min_id asks for the statuses just after it, where the others want the newest ones
+            oldest_first: self.min_id.is_some(),
+            limit: Some(self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)),
+            ..FeedQuery::default()
+        }))
+    }
+}
+
+/// Point clients at the pages on either side, which is how most of them paginate
+pub fn get_link_header(posts: &[Post], base_url: &str, domain: &str) -> Option<String> {
+    let (first, last) = (posts.first()?, posts.last()?);
+    Some(format!(
+        "<{base_url}?max_id={}>; rel=\"next\", <{base_url}?min_id={}>; rel=\"prev\"",
+        get_id(last, domain),
+        get_id(first, domain),
+    ))
+}
+
+fn get_id(post: &Post, domain: &str) -> String {
+    match post.post_id {
+        Some(post_id) => post_id.to_string(),
+        None => get_status_id(&post.object_id, domain),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::{create_profile, get_posts_in_profile};
+    use crate::sqlite::open_test_db;
+
+    fn page(max_id: Option<&str>, since_id: Option<&str>, min_id: Option<&str>, limit: usize) -> PageQuery {
+        PageQuery {
+            max_id: max_id.map(str::to_owned),
+            since_id: since_id.map(str::to_owned),
+            min_id: min_id.map(str::to_owned),
+            limit: Some(limit),
+        }
+    }
+
+    fn get_page(db: &Connection, profile_id: i64, page: PageQuery) -> Vec<i64> {
+        let Some(feed) = page.get_feed_query(db, "example.com").unwrap() else {
+            return vec![];
+        };
+        let posts = get_posts_in_profile(db, profile_id, "example.com", false, &feed).unwrap();
+        posts.into_iter().filter_map(|post| post.post_id).collect()
+    }
+
+    #[test]
+    fn pages_through_a_feed() {
+        let db = open_test_db();
+        let profile_id = create_profile(&db, "sam", "Sam", "sam").unwrap();
+        for second in 1..=10 {
+            let created_at = format!("2024-01-01T00:00:{:02}Z", second);
+            db.execute("INSERT INTO posts (profile_id, content, created_at) VALUES (?1, '', ?2)", (profile_id, created_at))
+                .unwrap();
+        }
+
+        assert_eq!(get_page(&db, profile_id, page(None, None, None, 3)), [10, 9, 8]);
+        assert_eq!(get_page(&db, profile_id, page(Some("8"), None, None, 3)), [7, 6, 5]);
+        assert_eq!(get_page(&db, profile_id, page(None, Some("3"), None, 3)), [10, 9, 8]);
+        assert_eq!(get_page(&db, profile_id, page(None, None, Some("3"), 3)), [6, 5, 4]);
+        assert_eq!(get_page(&db, profile_id, page(Some("5"), Some("2"), None, 10)), [4, 3]);
+        assert!(get_page(&db, profile_id, page(Some("11"), None, None, 3)).is_empty());
+    }
+
+    #[test]
+    fn pages_through_posts_made_at_the_same_time() {
+        let db = open_test_db();
+        let profile_id = create_profile(&db, "sam", "Sam", "sam").unwrap();
+        for _ in 0..5 {
+            db.execute("INSERT INTO posts (profile_id, content, created_at) VALUES (?1, '', '2024-01-01T00:00:00Z')", [profile_id])
+                .unwrap();
+        }
+
+        let mut seen = vec![];
+        let mut max_id = None;
+        loop {
+            let ids = get_page(&db, profile_id, page(max_id.as_deref(), None, None, 2));
+            let Some(last) = ids.last() else { break };
+            max_id = Some(last.to_string());
+            seen.extend(ids);
+        }
+        seen.sort();
+        assert_eq!(seen, [1, 2, 3, 4, 5]);
+    }
+}
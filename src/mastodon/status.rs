@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::mastodon::account::{get_account_for_actor, get_local_account, Account};
+use crate::mastodon::{get_account_id, get_status_id};
+use crate::queries::get_post_by_object_id;
+use crate::server::error::not_found;
+use crate::server::server_response::InternalResult;
+use crate::templates::_partials::post::{Post, PostAttachment};
+
+// https://docs.joinmastodon.org/entities/Status/
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub id: String,
+    pub uri: String,
+    pub url: String,
+    pub created_at: String,
+    pub account: Account,
+    pub content: String,
+    pub visibility: &'static str,
+    pub sensitive: bool,
+    pub spoiler_text: String,
+    pub media_attachments: Vec<MediaAttachment>,
+    pub mentions: Vec<Mention>,
+    pub tags: Vec<Tag>,
+    pub emojis: Vec<serde_json::Value>,
+    pub reblogs_count: i64,
+    pub favourites_count: i64,
+    pub replies_count: i64,
+    pub in_reply_to_id: Option<String>,
+    pub in_reply_to_account_id: Option<String>,
+    // There are no boosts, polls or link previews here, so these are always null
+    pub reblog: Option<serde_json::Value>,
+    pub poll: Option<serde_json::Value>,
+    pub card: Option<serde_json::Value>,
+    pub language: Option<String>,
+    pub edited_at: Option<String>,
+    pub favourited: bool,
+    pub reblogged: bool,
+    pub muted: bool,
+    pub bookmarked: bool,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MediaAttachment {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub media_type: &'static str,
+    pub url: String,
+    pub preview_url: String,
+    pub remote_url: Option<String>,
+    pub description: Option<String>,
+    pub blurhash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mention {
+    pub id: String,
+    pub username: String,
+    pub acct: String,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub url: String,
+}
+
+/// Turn posts from the feed queries into statuses, looking up each author only once
+pub fn get_statuses(db: &Connection, posts: Vec<Post>, domain: &str) -> InternalResult<Vec<Status>> {
+    let mut accounts = HashMap::new();
+    posts.into_iter().map(|post| get_status(db, post, domain, &mut accounts)).collect()
+}
+
+fn get_status(
+    db: &Connection,
+    post: Post,
+    domain: &str,
+    accounts: &mut HashMap<String, Account>,
+) -> InternalResult<Status> {
+    let account = match (post.profile_id, &post.actor_id) {
+        (Some(profile_id), _) => get_cached_account(accounts, &profile_id.to_string(), || {
+            get_local_account(db, profile_id, domain)
+        })?,
+        (None, Some(actor_id)) => get_cached_account(accounts, actor_id, || {
+            get_account_for_actor(db, actor_id, domain)
+        })?,
+        (None, None) => return Err(not_found()),
+    };
+
+    let (mentions, tags) = match post.post_id {
+        Some(post_id) => get_tags(db, post_id, domain)?,
+        None => (vec![], vec![]),
+    };
+
+    let replies_count = db.query_row(
+        "SELECT (SELECT COUNT(*) FROM posts WHERE in_reply_to = ?1)
+            + (SELECT COUNT(*) FROM remote_posts WHERE in_reply_to = ?1)",
+        [&post.object_id],
+        |row| row.get(0),
+    )?;
+
+    // We can only say who a reply was to if we have the post it's replying to
+    let in_reply_to_account_id = match &post.in_reply_to {
+        Some(in_reply_to) => get_post_by_object_id(db, in_reply_to, domain)?.and_then(|parent| {
+            match (parent.profile_id, parent.actor_id) {
+                (Some(profile_id), _) => Some(profile_id.to_string()),
+                (None, Some(actor_id)) => Some(get_account_id(&actor_id, domain)),
+                (None, None) => None,
+            }
+        }),
+        None => None,
+    };
+
+    let id = match post.post_id {
+        Some(post_id) => post_id.to_string(),
+        None => get_status_id(&post.object_id, domain),
+    };
+    let media_attachments = post.attachments.into_iter()
+        .enumerate()
+        .map(|(i, attachment)| get_media_attachment(&id, i, attachment, domain))
+        .collect();
+
+    let status = Status {
+        id,
+        uri: post.object_id.clone(),
+        url: post.object_id,
+        created_at: post.created_at,
+        account,
+        content: post.content,
+        visibility: "public",
+        sensitive: false,
+        spoiler_text: String::new(),
+        media_attachments,
+        mentions,
+        tags,
+        emojis: vec![],
+        reblogs_count: 0,
+        favourites_count: 0,
+        replies_count,
+        in_reply_to_id: post.in_reply_to.as_deref().map(|id| get_status_id(id, domain)),
+        in_reply_to_account_id,
+        reblog: None,
+        poll: None,
+        card: None,
+        language: None,
+        edited_at: None,
+        favourited: false,
+        reblogged: false,
+        muted: false,
+        bookmarked: false,
+        pinned: false,
+    };
+    Ok(status)
+}
+
+fn get_cached_account(
+    accounts: &mut HashMap<String, Account>,
+    key: &str,
+    get: impl FnOnce() -> InternalResult<Option<Account>>,
+) -> InternalResult<Account> {
+    if let Some(account) = accounts.get(key) {
+        return Ok(account.clone());
+    }
+
+    let account = get()?.ok_or_else(not_found)?;
+    accounts.insert(key.to_owned(), account.clone());
+    Ok(account)
+}
+
+// Only local posts keep their tags; remote ones come with them already rendered into the content
+fn get_tags(db: &Connection, post_id: i64, domain: &str) -> InternalResult<(Vec<Mention>, Vec<Tag>)> {
+    let mut query = db.prepare("SELECT tag_type, href, name FROM post_tags WHERE post_id = ?1")?;
+    let rows = query.query_map([post_id], |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)))?;
+
+    let mut mentions = Vec::new();
+    let mut tags = Vec::new();
+    for row in rows {
+        let (tag_type, href, name): (String, String, String) = row?;
+        match tag_type.as_str() {
+            "Mention" => mentions.push(get_mention(href, &name, domain)),
+            "Hashtag" => tags.push(Tag { name: name.trim_start_matches('#').to_owned(), url: href }),
+            _ => {}
+        }
+    }
+    Ok((mentions, tags))
+}
+
+// Mentions are stored with their full handle, like "@alex@example.com"
+fn get_mention(href: String, name: &str, domain: &str) -> Mention {
+    let handle = name.trim_start_matches('@');
+    let (username, host) = handle.split_once('@').unwrap_or((handle, domain));
+    let acct = if host == domain { username.to_owned() } else { handle.to_owned() };
+
+    Mention {
+        id: get_account_id(&href, domain),
+        username: username.to_owned(),
+        acct,
+        url: href,
+    }
+}
+
+fn get_media_attachment(status_id: &str, index: usize, attachment: PostAttachment, domain: &str) -> MediaAttachment {
+    // Local media is linked relative to the site
+    let url = match attachment.url.strip_prefix("/media/") {
+        Some(media_id) => format!("https://{}/media/{}", domain, media_id),
+        None => attachment.url,
+    };
+    let id = match url.strip_prefix(&format!("https://{}/media/", domain)) {
+        Some(media_id) => media_id.to_owned(),
+        None => format!("{}-{}", status_id, index),
+    };
+    let media_type = match attachment.media_type.as_deref() {
+        Some(t) if t.starts_with("image/") => "image",
+        Some(t) if t.starts_with("video/") => "video",
+        Some(t) if t.starts_with("audio/") => "audio",
+        _ => "unknown",
+    };
+
+    MediaAttachment {
+        id,
+        media_type,
+        preview_url: url.clone(),
+        url,
+        remote_url: None,
+        description: attachment.alt,
+        blurhash: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_of_local_profiles_use_short_acct() {
+        let mention = get_mention("https://example.com/profiles/2".to_owned(), "@sam@example.com", "example.com");
+        assert_eq!(mention.id, "2");
+        assert_eq!(mention.acct, "sam");
+
+        let mention = get_mention("https://other.example/users/alex".to_owned(), "@alex@other.example", "example.com");
+        assert_eq!(mention.username, "alex");
+        assert_eq!(mention.acct, "alex@other.example");
+    }
+
+    #[test]
+    fn local_media_gets_absolute_urls() {
+        let attachment = PostAttachment {
+            url: "/media/00ff".to_owned(),
+            media_type: Some("image/png".to_owned()),
+            alt: Some("A boat".to_owned()),
+        };
+        let media = get_media_attachment("1", 0, attachment, "example.com");
+        assert_eq!(media.id, "00ff");
+        assert_eq!(media.url, "https://example.com/media/00ff");
+        assert_eq!(media.media_type, "image");
+    }
+}
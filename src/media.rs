@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use hyper::body::Bytes;
+use rand::random;
+use tracing::warn;
+
+use crate::server::error::map_internal_error;
+use crate::server::server_response::InternalResult;
+
+// ISO-BMFF files all start with ftyp, but only these major brands are MP4 video that browsers play,
+// rather than HEIC, AVIF, QuickTime or audio
+const MP4_BRANDS: &[&[u8; 4]] = &[b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V "];
+
+/// The largest request body accepted when uploading media with a post
+pub const MAX_UPLOAD_SIZE: usize = 1024 * 1024 * 16;
+pub const MAX_ATTACHMENTS: usize = 4;
+// Everything that sniff_media_type recognizes
+pub const SUPPORTED_MEDIA_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp", "video/mp4", "video/webm"];
+
+pub fn new_media_id() -> String {
+    format!("{:016x}", random::<u64>())
+}
+
+/// Work out what kind of file this is from its contents, rather than trusting the uploader;
+/// returns None for anything that we don't want to serve
+pub fn sniff_media_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', a, b, c, d, ..] if MP4_BRANDS.contains(&&[*a, *b, *c, *d]) => Some("video/mp4"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("video/webm"),
+        _ => None,
+    }
+}
+
+pub async fn write_media_file(media_path: &Path, media_id: &str, data: Bytes) -> InternalResult<()> {
+    tokio::fs::create_dir_all(media_path).await.map_err(map_internal_error)?;
+    tokio::fs::write(media_path.join(media_id), data).await.map_err(map_internal_error)?;
+    Ok(())
+}
+
+pub async fn read_media_file(media_path: &Path, media_id: &str) -> Option<Vec<u8>> {
+    tokio::fs::read(media_path.join(media_id)).await.ok()
+}
+
+pub fn delete_media_files(media_path: &Path, media_ids: &[String]) {
+    for media_id in media_ids {
+        if let Err(e) = std::fs::remove_file(media_path.join(media_id)) {
+            warn!("Failed to delete media file {}: {}", media_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_media_types() {
+        assert_eq!(sniff_media_type(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff_media_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_media_type(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+        assert_eq!(sniff_media_type(b"\0\0\0\x20ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff_media_type(b"\0\0\0\x20ftypheic"), None);
+        assert_eq!(sniff_media_type(b"\0\0\0\x20ftypavif"), None);
+        assert_eq!(sniff_media_type(b"\0\0\0\x14ftypqt  "), None);
+        assert_eq!(sniff_media_type(b"\0\0\0\x20ftyp"), None);
+        assert_eq!(sniff_media_type(b"<html><script>"), None);
+        assert_eq!(sniff_media_type(b""), None);
+    }
+}
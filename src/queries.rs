@@ -1,116 +1,332 @@
-use crate::activitypub::objects::actor::{Actor, LinkType};
-use crate::activitypub::requests::{get_actor, get_webfinger};
-use crate::activitypub::FullHandle;
+use crate::activitypub::objects::actor::PublicKey;
 use crate::query_row;
-use crate::server::error::{bad_request, map_bad_gateway, ServerError};
-use crate::server::server_request::CurrentProfile;
+use crate::server::error::{bad_request, map_bad_request};
 use crate::server::server_response::InternalResult;
+use crate::templates::_partials::follow_button::FollowStatus;
 use crate::templates::_partials::post::Post;
 use hyper::Uri;
-use rusqlite::Connection;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
 use tracing::warn;
 
-pub fn get_posts_in_profile(db: &Connection, profile_id: i64, is_owner: bool) -> Result<Vec<Post>, ServerError> {
-    let mut query = db.prepare(
-        "SELECT post_id,
+// Local and remote posts in the same shape, keyed by their ActivityPub ids (?1 is our domain)
+// Each query filters the two tables before they're combined, so that their indexes can be used
+fn all_posts(local_filter: &str, remote_filter: &str) -> String {
+    format!("
+    all_posts AS (
+        SELECT {LOCAL_OBJECT_ID} as object_id,
+            in_reply_to,
+            post_id,
+            profile_id,
             display_name as actor_name,
-            preferred_username as actor_handle,
+            preferred_username,
+            NULL as actor_id,
             content,
-            created_at
-         FROM posts
-         LEFT JOIN profiles AS f USING (profile_id)
-         WHERE profile_id = ?1
-         ORDER BY created_at DESC
-         ",
-    )?;
+            created_at,
+            avatar_url as avi_url,
+            (
+                SELECT json_group_array(json_object('url', '/media/' || media_id, 'media_type', media_type, 'alt', alt_text))
+                FROM media
+                WHERE media.post_id = posts.post_id
+            ) as attachments
+        FROM posts
+        LEFT JOIN profiles USING (profile_id)
+        WHERE {local_filter}
+        UNION ALL
+        SELECT object_id,
+            in_reply_to,
+            NULL as post_id,
+            NULL as profile_id,
+            name as actor_name,
+            preferred_username,
+            actor_id,
+            content,
+            published as created_at,
+            icon_url as avi_url,
+            attachments
+        FROM remote_posts
+        LEFT JOIN known_actors USING (actor_id)
+        WHERE {remote_filter}
+    )")
+}
+
+// Local posts are looked up by their post_id, rather than by building every post's object id
+fn local_post_id(object_id: &str) -> String {
+    format!(
+        "CASE WHEN substr({object_id}, 1, length('https://' || ?1 || '/posts/')) = 'https://' || ?1 || '/posts/'
+            THEN CAST(substr({object_id}, length('https://' || ?1 || '/posts/') + 1) AS INTEGER)
+        END"
+    )
+}
+
+// A local post's ActivityPub id (?1 is our domain)
+const LOCAL_OBJECT_ID: &str = "'https://' || ?1 || '/posts/' || posts.post_id";
+
+// The columns that post_from_row expects, in order
+const POST_COLUMNS: &str =
+    "object_id, in_reply_to, post_id, actor_name, preferred_username, actor_id, content, created_at, avi_url, attachments, profile_id";
+
+// Replies are only followed this far up or down a thread
+const MAX_THREAD_DEPTH: i64 = 50;
 
-    let rows = query.query_map([profile_id], |row| {
-        let post = Post {
-            post_id: row.get(0)?,
-            actor_name: row.get(1)?,
-            actor_handle: row.get(2)?,
-            content: row.get(3)?,
-            created_at: row.get(4)?,
-            is_owner,
-        };
-        Ok(post)
-    })?;
+/// Which part of a feed to get; the default is all of it
+#[derive(Debug, Default)]
+pub struct FeedQuery {
+    /// Only posts older than this one, given as its (created_at, object_id)
+    pub before: Option<(String, String)>,
+    /// Only posts newer than this one
+    pub after: Option<(String, String)>,
+    /// Take the posts just after `after`, rather than the newest ones
+    pub oldest_first: bool,
+    pub limit: Option<usize>,
+    pub exclude_replies: bool,
+    pub only_media: bool,
+}
+
+fn post_from_row(row: &rusqlite::Row) -> rusqlite::Result<Post> {
+    let post_id: Option<i64> = row.get(2)?;
+    let preferred_username: String = row.get(4)?;
+    let actor_id: Option<String> = row.get(5)?;
+
+    // Remote actors are shown with their full handle, local ones with just the username
+    let remote_host = actor_id.as_ref().and_then(|id| id.parse::<Uri>().ok()?.host().map(str::to_owned));
+    let actor_handle = match remote_host {
+        Some(host) => format!("@{}@{}", preferred_username, host),
+        None => preferred_username,
+    };
+
+    // Attachments come out of the database as JSON, so a bad one shouldn't break the whole feed
+    let attachments: String = row.get(9)?;
+    let attachments = serde_json::from_str(&attachments).unwrap_or_else(|e| {
+        warn!("Invalid attachments stored for post: {}", e);
+        vec![]
+    });
 
+    let post = Post {
+        post_id,
+        object_id: row.get(0)?,
+        in_reply_to: row.get(1)?,
+        actor_name: row.get(3)?,
+        actor_handle,
+        content: row.get(6)?,
+        created_at: row.get(7)?,
+        avi_url: row.get(8)?,
+        attachments,
+        profile_id: row.get(10)?,
+        actor_id,
+        is_owner: post_id.is_some(),
+    };
+    Ok(post)
+}
+
+fn get_posts(db: &Connection, query: &str, params: impl rusqlite::Params) -> InternalResult<Vec<Post>> {
+    let mut query = db.prepare(query)?;
+    let rows = query.query_map(params, post_from_row)?;
     let posts: Vec<Post> = rows.collect::<Result<_, _>>()?;
     Ok(posts)
 }
 
-pub async fn get_or_search_for_actor(
-    handle: &FullHandle,
-    current_profile: &CurrentProfile,
-) -> Result<Option<Actor>, ServerError> {
-    // First check if we are following them
-    let FullHandle {
-        preferred_username,
-        host,
-    } = handle;
-    // let actor = db.query_row("
-    // SELECT name, url, preferred_username, host, summary
-    // FROM followed_actors
-    // WHERE handle = ? AND host = ?",
-    // (preferred_username, host), |row| {
-    //     let actor = Actor {
-    //         name: row.get(0)?,
-    //         url: row.get(1)?,
-    //         preferred_username: row.get(2)?,
-    //         host: row.get(3)?,
-    //         summary: row.get(4)?,
-    //         icon_url: "".to_owned(),
-    //         is_followed: true
-    //     };
-    //     Ok(actor)
-    // });
-
-    // if actor.is_ok() { return Ok(actor.ok()); }
-
-    let web_finger = get_webfinger(host, preferred_username).await?;
-    let actor_link = web_finger
-        .links
-        .as_ref()
-        .and_then(|links| {
-            links
-                .iter()
-                .find(|l| l.rel == "self" && l.link_type == Some(LinkType::ActivityJson))
-        })
-        .and_then(|link| link.href.clone());
-
-    let uri = match actor_link.as_ref() {
-        None => return Ok(None),
-        Some(link) => link.clone().parse::<Uri>(),
+// Feeds are sorted by (created_at, object_id), so pages can pick up after any post in them.
+// The page's conditions go on each table, like the feed's own filters, so their indexes can be used
+fn get_feed(
+    db: &Connection,
+    local_filter: &str,
+    remote_filter: &str,
+    mut params: Vec<Value>,
+    feed: &FeedQuery,
+) -> InternalResult<Vec<Post>> {
+    let mut local_conditions = vec![format!("({local_filter})")];
+    let mut remote_conditions = vec![format!("({remote_filter})")];
+
+    if let Some((created_at, object_id)) = &feed.before {
+        let (at, id) = (params.len() + 1, params.len() + 2);
+        params.extend([Value::from(created_at.clone()), Value::from(object_id.clone())]);
+        local_conditions.push(format!(
+            "posts.created_at <= ?{at} AND (posts.created_at < ?{at} OR {LOCAL_OBJECT_ID} < ?{id})"
+        ));
+        remote_conditions.push(format!(
+            "remote_posts.published <= ?{at} AND (remote_posts.published < ?{at} OR remote_posts.object_id < ?{id})"
+        ));
+    }
+    if let Some((created_at, object_id)) = &feed.after {
+        let (at, id) = (params.len() + 1, params.len() + 2);
+        params.extend([Value::from(created_at.clone()), Value::from(object_id.clone())]);
+        local_conditions.push(format!(
+            "posts.created_at >= ?{at} AND (posts.created_at > ?{at} OR {LOCAL_OBJECT_ID} > ?{id})"
+        ));
+        remote_conditions.push(format!(
+            "remote_posts.published >= ?{at} AND (remote_posts.published > ?{at} OR remote_posts.object_id > ?{id})"
+        ));
+    }
+    if feed.exclude_replies {
+        local_conditions.push("posts.in_reply_to IS NULL".to_owned());
+        remote_conditions.push("remote_posts.in_reply_to IS NULL".to_owned());
+    }
+    if feed.only_media {
+        local_conditions.push("EXISTS (SELECT 1 FROM media WHERE media.post_id = posts.post_id)".to_owned());
+        remote_conditions.push("remote_posts.attachments != '[]'".to_owned());
+    }
+
+    let all_posts = all_posts(&local_conditions.join(" AND "), &remote_conditions.join(" AND "));
+    let order = if feed.oldest_first { "ASC" } else { "DESC" };
+    let limit = feed.limit.map(|limit| format!("LIMIT {}", limit)).unwrap_or_default();
+    let query = format!(
+        "WITH {all_posts}
+        SELECT {POST_COLUMNS}
+        FROM all_posts
+        ORDER BY created_at {order}, object_id {order}
+        {limit}"
+    );
+
+    let mut posts = get_posts(db, &query, params_from_iter(params))?;
+    // Feeds always come back newest first
+    if feed.oldest_first {
+        posts.reverse();
     }
-    .map_err(|e| {
-        warn!(
-            "Invalid URI provided for self by {}@{}: {:?}",
-            preferred_username, host, &actor_link
-        );
-        map_bad_gateway(e)
-    })?;
-
-    let actor = get_actor(&uri, current_profile).await?;
-
-    // let actor = Actor {
-    //     name: actor.name,
-    //     url: local_url,
-    //     preferred_username: actor.preferred_username,
-    //     host: host.to_owned(),
-    //     summary: actor.summary.unwrap_or("".to_owned()),
-    //     icon_url,
-    //     is_followed: false,
-    //     outbox: actor.outbox.unwrap()
-    // };
-
-    Ok(Some(actor))
+    Ok(posts)
+}
+
+pub fn get_posts_in_profile(
+    db: &Connection,
+    profile_id: i64,
+    domain: &str,
+    is_owner: bool,
+    feed: &FeedQuery,
+) -> InternalResult<Vec<Post>> {
+    let params = vec![Value::from(domain.to_owned()), Value::from(profile_id)];
+    let mut posts = get_feed(db, "posts.profile_id = ?2", "FALSE", params, feed)?;
+    posts.iter_mut().for_each(|post| post.is_owner = is_owner);
+    Ok(posts)
+}
+
+/// Get the posts we've been sent by a remote actor, newest first
+pub fn get_posts_by_actor(db: &Connection, actor_id: &str, domain: &str, feed: &FeedQuery) -> InternalResult<Vec<Post>> {
+    let params = vec![Value::from(domain.to_owned()), Value::from(actor_id.to_owned())];
+    get_feed(db, "FALSE", "remote_posts.actor_id = ?2", params, feed)
+}
+
+/// Get the profile's own posts, interleaved with the posts of every actor that it follows and
+/// any replies to its posts
+pub fn get_home_timeline(db: &Connection, profile_id: i64, domain: &str, feed: &FeedQuery) -> InternalResult<Vec<Post>> {
+    let own_posts = "SELECT 'https://' || ?1 || '/posts/' || post_id FROM posts WHERE profile_id = ?2";
+    let params = vec![Value::from(domain.to_owned()), Value::from(profile_id)];
+    get_feed(
+        db,
+        &format!("posts.profile_id = ?2 OR posts.in_reply_to IN ({own_posts})"),
+        &format!(
+            "remote_posts.actor_id IN (SELECT actor_id FROM following WHERE profile_id = ?2)
+            OR remote_posts.in_reply_to IN ({own_posts})"
+        ),
+        params,
+        feed,
+    )
+}
+
+/// Get every local post tagged with the hashtag, newest first
+pub fn get_posts_with_hashtag(db: &Connection, name: &str, domain: &str) -> InternalResult<Vec<Post>> {
+    let params = vec![Value::from(domain.to_owned()), Value::from(name.to_owned())];
+    get_feed(
+        db,
+        "posts.post_id IN (
+            SELECT post_id FROM post_tags WHERE tag_type = 'Hashtag' AND name = '#' || ?2 COLLATE NOCASE
+        )",
+        "FALSE",
+        params,
+        &FeedQuery::default(),
+    )
+}
+
+/// Get a single local or remote post by its ActivityPub id
+pub fn get_post_by_object_id(db: &Connection, object_id: &str, domain: &str) -> InternalResult<Option<Post>> {
+    let all_posts = all_posts(&format!("posts.post_id = {}", local_post_id("?2")), "remote_posts.object_id = ?2");
+    let query = format!("WITH {all_posts} SELECT {POST_COLUMNS} FROM all_posts");
+    let post = db.query_row(&query, (domain, object_id), post_from_row).optional()?;
+    Ok(post)
+}
+
+/// Get every post above this one in its thread that we know about, starting from the top
+pub fn get_thread_ancestors(db: &Connection, object_id: &str, domain: &str) -> InternalResult<Vec<Post>> {
+    let parent_of_local = format!("SELECT in_reply_to FROM posts WHERE post_id = {}", local_post_id("ancestors.object_id"));
+    let all_posts = all_posts(
+        &format!("posts.post_id IN (SELECT {} FROM ancestors WHERE depth > 0)", local_post_id("object_id")),
+        "remote_posts.object_id IN (SELECT object_id FROM ancestors WHERE depth > 0)",
+    );
+    let query = format!(
+        "WITH RECURSIVE
+        ancestors (object_id, depth) AS (
+            SELECT ?2, 0
+            UNION ALL
+            SELECT COALESCE(
+                ({parent_of_local}),
+                (SELECT in_reply_to FROM remote_posts WHERE remote_posts.object_id = ancestors.object_id)
+            ), depth + 1
+            FROM ancestors
+            WHERE object_id IS NOT NULL AND depth < ?3
+        ),
+        {all_posts}
+        SELECT {POST_COLUMNS}
+        FROM all_posts
+        INNER JOIN ancestors USING (object_id)
+        WHERE depth > 0
+        ORDER BY depth DESC"
+    );
+
+    get_posts(db, &query, (domain, object_id, MAX_THREAD_DEPTH))
+}
+
+/// Get every reply below this one in its thread, oldest first
+pub fn get_thread_replies(db: &Connection, object_id: &str, domain: &str) -> InternalResult<Vec<Post>> {
+    let all_posts = all_posts(
+        &format!("posts.post_id IN (SELECT {} FROM replies)", local_post_id("object_id")),
+        "remote_posts.object_id IN (SELECT object_id FROM replies)",
+    );
+    let query = format!(
+        "WITH RECURSIVE
+        replies (object_id, depth) AS (
+            SELECT 'https://' || ?1 || '/posts/' || post_id, 1 FROM posts WHERE in_reply_to = ?2
+            UNION ALL
+            SELECT object_id, 1 FROM remote_posts WHERE in_reply_to = ?2
+            UNION ALL
+            SELECT 'https://' || ?1 || '/posts/' || post_id, depth + 1
+            FROM posts
+            INNER JOIN replies ON posts.in_reply_to = replies.object_id
+            WHERE depth < ?3
+            UNION ALL
+            SELECT remote_posts.object_id, depth + 1
+            FROM remote_posts
+            INNER JOIN replies ON remote_posts.in_reply_to = replies.object_id
+            WHERE depth < ?3
+        ),
+        {all_posts}
+        SELECT {POST_COLUMNS}
+        FROM all_posts
+        ORDER BY created_at ASC"
+    );
+
+    get_posts(db, &query, (domain, object_id, MAX_THREAD_DEPTH))
+}
+
+pub fn get_follow_status(db: &Connection, profile_id: i64, actor_id: &str) -> InternalResult<FollowStatus> {
+    let is_accepted: Option<bool> = db.query_row(
+        "SELECT is_accepted FROM following WHERE profile_id = ?1 AND actor_id = ?2",
+        (profile_id, actor_id),
+        |row| row.get(0),
+    ).optional()?;
+
+    let status = match is_accepted {
+        None => FollowStatus::None,
+        Some(false) => FollowStatus::Requested,
+        Some(true) => FollowStatus::Following,
+    };
+    Ok(status)
 }
 
 pub fn get_profile_id_from_url(db: &Connection, url: &str) -> InternalResult<i64> {
     // let preferred_username = _get_preferred_username_from_url(url)?;
     let uri: Uri = url.parse().map_err(|_| bad_request("Invalid URI provided"))?;
-    let profile_id = uri.path().split('/').last();
+    let profile_id = uri.path().split('/').next_back();
     let profile = query_row!(
         db,
         Profile { profile_id: i64 },
@@ -121,6 +337,46 @@ pub fn get_profile_id_from_url(db: &Connection, url: &str) -> InternalResult<i64
     Ok(profile.profile_id)
 }
 
+pub fn get_cached_public_key(db: &Connection, key_id: &str) -> InternalResult<Option<PublicKey>> {
+    let key = db.query_row(
+        "SELECT key_id, owner, public_key_pem FROM public_keys WHERE key_id = ?1",
+        [key_id],
+        |row| {
+            let key = PublicKey {
+                id: row.get(0)?,
+                owner: row.get(1)?,
+                public_key_pem: row.get(2)?,
+            };
+            Ok(key)
+        },
+    ).optional()?;
+
+    Ok(key)
+}
+
+pub fn cache_public_key(db: &Connection, key: &PublicKey) -> InternalResult<()> {
+    db.execute(
+        "INSERT OR REPLACE INTO public_keys (key_id, owner, public_key_pem) VALUES (?1, ?2, ?3)",
+        (&key.id, &key.owner, &key.public_key_pem),
+    )?;
+    Ok(())
+}
+
+/// Create a profile with a fresh keypair, returning its id
+pub fn create_profile(db: &Connection, preferred_username: &str, display_name: &str, nickname: &str) -> InternalResult<i64> {
+    // TODO encrypt this
+    let rsa = Rsa::generate(2048)?;
+    let pkey = PKey::from_rsa(rsa)?.private_key_to_pem_pkcs8()?;
+    let pkey = String::from_utf8(pkey).map_err(map_bad_request)?;
+
+    db.execute(
+        "INSERT INTO profiles (preferred_username, display_name, nickname, private_key_pem)
+        VALUES (?1, ?2, ?3, ?4)",
+        (preferred_username, display_name, nickname, &pkey),
+    )?;
+    Ok(db.last_insert_rowid())
+}
+
 fn _get_preferred_username_from_url(url: &str) -> InternalResult<String> {
     let uri: Uri = url.parse().map_err(|_| bad_request("Invalid URI provided"))?;
     let path = &uri.path()[1..];
@@ -138,6 +394,54 @@ fn _get_preferred_username_from_url(url: &str) -> InternalResult<String> {
 mod tests {
     use super::*;
 
+    fn get_object_ids(posts: Vec<Post>) -> Vec<String> {
+        posts.into_iter().map(|post| post.object_id).collect()
+    }
+
+    #[test]
+    fn follows_threads_across_local_and_remote_posts() {
+        let db = crate::sqlite::open_test_db();
+        let domain = "example.com";
+        let profile_id = create_profile(&db, "sam", "Sam", "sam").unwrap();
+        db.execute(
+            "INSERT INTO known_actors (actor_id, name, preferred_username, host) VALUES ('https://other.example/alex', 'Alex', 'alex', 'other.example')",
+            (),
+        ).unwrap();
+
+        // 1 <- remote a <- 2 <- remote b, with remote c unrelated
+        db.execute("INSERT INTO posts (profile_id, content) VALUES (?1, 'one')", [profile_id]).unwrap();
+        db.execute(
+            "INSERT INTO remote_posts (object_id, actor_id, content, in_reply_to) VALUES
+                ('https://other.example/a', 'https://other.example/alex', 'a', 'https://example.com/posts/1'),
+                ('https://other.example/c', 'https://other.example/alex', 'c', NULL)",
+            (),
+        ).unwrap();
+        db.execute("INSERT INTO posts (profile_id, content, in_reply_to) VALUES (?1, 'two', 'https://other.example/a')", [profile_id]).unwrap();
+        db.execute(
+            "INSERT INTO remote_posts (object_id, actor_id, content, in_reply_to) VALUES
+                ('https://other.example/b', 'https://other.example/alex', 'b', 'https://example.com/posts/2')",
+            (),
+        ).unwrap();
+
+        let ancestors = get_thread_ancestors(&db, "https://other.example/b", domain).unwrap();
+        assert_eq!(get_object_ids(ancestors), ["https://example.com/posts/1", "https://other.example/a", "https://example.com/posts/2"]);
+
+        let replies = get_thread_replies(&db, "https://example.com/posts/1", domain).unwrap();
+        let mut replies = get_object_ids(replies);
+        replies.sort();
+        assert_eq!(replies, ["https://example.com/posts/2", "https://other.example/a", "https://other.example/b"]);
+
+        let post = get_post_by_object_id(&db, "https://example.com/posts/2", domain).unwrap().unwrap();
+        assert_eq!(post.in_reply_to.as_deref(), Some("https://other.example/a"));
+        assert!(get_post_by_object_id(&db, "https://example.com/posts/3", domain).unwrap().is_none());
+        assert!(get_post_by_object_id(&db, "https://other.example/c", domain).unwrap().is_some());
+
+        // Replies to our posts show up at home, even from actors we don't follow
+        let mut home = get_object_ids(get_home_timeline(&db, profile_id, domain, &FeedQuery::default()).unwrap());
+        home.sort();
+        assert_eq!(home, ["https://example.com/posts/1", "https://example.com/posts/2", "https://other.example/a", "https://other.example/b"]);
+    }
+
     #[test]
     fn bad_url() {
         let url = "not a url";
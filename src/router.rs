@@ -1,3 +1,4 @@
+mod api;
 mod debug;
 mod feeds;
 mod follow;
@@ -5,18 +6,24 @@ mod healthcheck;
 mod index;
 mod login;
 mod logout;
+mod media;
+mod nodeinfo;
+mod oauth;
+mod password;
 mod posts;
 mod profiles;
 mod search;
+mod settings;
 mod serve_static;
+mod setup;
 mod switch;
+mod tags;
 mod well_known;
 
 use crate::router::posts::_post_id;
-use crate::router::well_known::webfinger;
+use crate::router::well_known::{nodeinfo as nodeinfo_discovery, webfinger};
 use crate::server::error::{forbidden, ServerError};
 use crate::server::server_response::{redirect, ServerResult};
-use crate::sqlite::get_conn;
 use feeds::_feed_handle;
 use hyper::body::Incoming;
 use hyper::header::HOST;
@@ -26,23 +33,30 @@ use std::sync::Arc;
 use tracing::debug;
 use tracing::error;
 use tracing::warn;
-use crate::router::profiles::_profile_id::{followers, following, inbox, outbox};
+use crate::router::profiles::_profile_id::{edit, followers, following, inbox, outbox};
 
 use crate::server::context::GlobalContext;
-use crate::server::server_request::{new_request, AuthStatus, AuthedRequest, PlainRequest, SetupRequest, SetupStatus};
+use crate::router::api::v1::{accounts, apps, follows, instance, statuses, timelines};
+use crate::server::error::unauthorized;
+use crate::server::server_request::{new_request, ApiRequest, AuthState, AuthStatus, AuthedRequest, PlainRequest, SetupRequest, SetupStatus, TokenStatus};
+use crate::server::sessions::{is_valid_csrf_token, CSRF_HEADER};
 use crate::server::server_response;
 
 pub const GET: &Method = &Method::GET;
 pub const POST: &Method = &Method::POST;
 pub const DELETE: &Method = &Method::DELETE;
 
-const DEFAULT_DB: &str = "./sailboat.db";
-
 pub enum MiddlewareResult<T> {
     Continue(T),
     Finish(ServerResult)
 }
 
+/// A request for something that's public as ActivityPub, but only shown to us as a page
+pub enum Negotiated<'a> {
+    ActivityPub(PlainRequest<'a>),
+    Html(AuthedRequest<'a>),
+}
+
 macro_rules! routes {
     (
         $req:ident,
@@ -55,7 +69,7 @@ macro_rules! routes {
         match (&$method_to_match, $sub_routes_to_match) {
             $(
                 ($method, $sub_routes) => {
-                    let req = match $auth_func($req) {
+                    let req = match $auth_func($req).await {
                         MiddlewareResult::Continue(r) => r,
                         MiddlewareResult::Finish(e) => return e
                     };
@@ -70,33 +84,28 @@ macro_rules! routes {
 #[rustfmt::skip]
 pub async fn router(req: Request<Incoming>, g_ctx: Arc<GlobalContext<'_>>) -> ServerResult {
     let path = req.uri().path();
+    // HTTP/2 requests carry the host in the URI's authority instead of a Host header
     let host = req
         .headers()
         .get(HOST)
         .map(|h| h.to_str().unwrap_or("UNKNOWN"))
+        .or(req.uri().authority().map(|a| a.as_str()))
         .unwrap_or("UNKNOWN");
 
     if path != "/debug" {
         debug!("Received {} request at {} from host {}", &req.method(), path, host);
     }
 
-    let db_path = std::env::var("DB_PATH").unwrap_or(DEFAULT_DB.to_owned());
-    let db = get_conn(&db_path)?;
-
-    let domain = if g_ctx.domain.is_some() {
-        g_ctx.domain.clone().unwrap()
-    } else {
-        db.query_row("SELECT value FROM globals WHERE key = 'domain'", (), |row| row.get(0))?
-    };
-
-    let req = new_request(req, g_ctx, db, domain)?;
-
     // Serve static files separately
-    // TODO: Refactor this so it happens before all the DB stuff
-    if req.uri().path().starts_with("/static") {
-        return serve_static::get(req).await;
+    if let Some(file) = path.strip_prefix("/static/") {
+        return serve_static::get(&g_ctx, file);
     }
 
+    // There's no domain until setup is done, so go by whichever one the request was sent to
+    let domain = g_ctx.get_domain().unwrap_or(host).to_owned();
+
+    let req = new_request(req, g_ctx, domain)?;
+
     // Remove the query parameter for routing purposes
     let without_query = match req.uri().path().split_once('?') {
         None => req.uri().path().to_owned(),
@@ -109,22 +118,42 @@ pub async fn router(req: Request<Incoming>, g_ctx: Arc<GlobalContext<'_>>) -> Se
     let sub_routes = &sub_routes[1..];
     let method = req.method().clone();
 
+    // Until the wizard is done there's no domain or password, so it's the only page on offer
+    if !is_available_during_setup(sub_routes) && !req.global.is_setup_complete() {
+        return redirect("/setup");
+    }
+
     routes!(req, method, sub_routes, {
         (GET,       [""]) =>                            (any, index::get),
 
+        (GET,       ["setup"]) =>                       (any, setup::get),
+        (POST,      ["setup"]) =>                       (any, setup::post),
+
         (GET,       ["login"]) =>                       (any, login::get),
         (POST,      ["login"]) =>                       (any, login::post),
-        (GET,       ["logout"]) =>                      (any, logout::get),
+        (GET,       ["login", "two-factor"]) =>         (any, login::two_factor::get),
+        (POST,      ["login", "two-factor"]) =>         (any, login::two_factor::post),
+        (POST,      ["logout"]) =>                      (require_authentication, logout::post),
+        (POST,      ["logout", "all"]) =>               (require_authentication, logout::post_all),
+        (GET,       ["password"]) =>                    (require_authentication, password::get),
+        (POST,      ["password"]) =>                    (require_authentication, password::post),
+        (GET,       ["settings", "two-factor"]) =>      (require_authentication, settings::two_factor::get),
+        (POST,      ["settings", "two-factor"]) =>      (require_authentication, settings::two_factor::post),
+        (POST,      ["settings", "two-factor", "confirm"]) => (require_authentication, settings::two_factor::post_confirm),
+        (POST,      ["settings", "two-factor", "disable"]) => (require_authentication, settings::two_factor::post_disable),
 
         (POST,      ["inbox"]) =>                       (any, inbox::post),
         (GET,       ["feeds", _]) =>                    (require_full_setup, _feed_handle::get),
         (POST,      ["follow"]) =>                      (require_full_setup, follow::post),
+        (DELETE,    ["follow"]) =>                      (require_full_setup, follow::delete),
 
         (POST,      ["profiles"]) =>                    (require_authentication, profiles::post),
         (GET,       ["profiles", "new"]) =>             (require_authentication, profiles::new::get),
         (GET,       ["profiles", _]) =>                 (any, _profile_id::get),
-        (GET,       ["profiles", _, "following"]) =>    (require_full_setup, following::get),
-        (GET,       ["profiles", _, "followers"]) =>    (require_full_setup, followers::get),
+        (GET,       ["profiles", _, "edit"]) =>         (require_full_setup, edit::get),
+        (POST,      ["profiles", _, "edit"]) =>         (require_full_setup, edit::post),
+        (GET,       ["profiles", _, "following"]) =>    (require_full_setup_for_html, following::get),
+        (GET,       ["profiles", _, "followers"]) =>    (require_full_setup_for_html, followers::get),
         (GET,       ["profiles", _, "outbox"]) =>       (any, outbox::get),
         (POST,       ["profiles", _, "inbox"]) =>       (any, inbox::post),
 
@@ -132,29 +161,56 @@ pub async fn router(req: Request<Incoming>, g_ctx: Arc<GlobalContext<'_>>) -> Se
         (GET,       ["posts", ..]) =>                   (any, _post_id::get),
         (DELETE,    ["posts", ..]) =>                   (require_full_setup, posts::delete),
 
+        (GET,       ["tags", _]) =>                     (any, tags::get),
+        (GET,       ["media", _]) =>                    (any, media::get),
+
         (GET,       ["switch", _]) =>                   (any, switch::get),
         (GET,       ["search", ..]) =>                  (require_full_setup, search::get),
         (POST,      ["search", ..]) =>                  (require_full_setup, search::post),
 
         (GET,       [".well-known", "webfinger"]) =>    (any, webfinger::get),
+        (GET,       [".well-known", "nodeinfo"]) =>     (any, nodeinfo_discovery::get),
+        (GET,       ["nodeinfo", "2.1"]) =>             (any, nodeinfo::get),
+
+        (POST,      ["api", "v1", "apps"]) =>           (any, apps::post),
+        (GET,       ["api", "v1", "instance"]) =>       (any, instance::get),
+        (GET,       ["api", "v1", "accounts", "verify_credentials"]) => (require_token, accounts::verify_credentials::get),
+        (GET,       ["api", "v1", "accounts", _]) =>    (any, accounts::_account_id::get),
+        (GET,       ["api", "v1", "accounts", _, "statuses"]) => (any, accounts::_account_id::statuses::get),
+        (POST,      ["api", "v1", "statuses"]) =>       (require_token, statuses::post),
+        (GET,       ["api", "v1", "statuses", _]) =>    (any, statuses::_status_id::get),
+        (DELETE,    ["api", "v1", "statuses", _]) =>    (require_token, statuses::_status_id::delete),
+        (GET,       ["api", "v1", "timelines", "home"]) => (require_token, timelines::home::get),
+        (POST,      ["api", "v1", "follows"]) =>        (require_token, follows::post),
+
+        (GET,       ["oauth", "authorize"]) =>          (any, oauth::authorize::get),
+        (POST,      ["oauth", "authorize"]) =>          (require_full_setup, oauth::authorize::post),
+        (POST,      ["oauth", "token"]) =>              (any, oauth::token::post),
+        (POST,      ["oauth", "revoke"]) =>             (any, oauth::revoke::post),
+        (_,         ["api", ..]) =>                     (any, api::get_not_found),
 
         (GET,       ["debug"]) =>                       (any, debug::get),
         (GET,       ["healthcheck"]) =>                 (any, healthcheck::get),
     })
 }
 
-fn any(req: PlainRequest) -> MiddlewareResult<PlainRequest> {
+// Webfinger is how the wizard checks that the domain it's given points back here
+fn is_available_during_setup(sub_routes: &[&str]) -> bool {
+    matches!(sub_routes, ["setup"] | [".well-known", "webfinger"] | ["healthcheck"])
+}
+
+async fn any(req: PlainRequest<'_>) -> MiddlewareResult<PlainRequest<'_>> {
     MiddlewareResult::Continue(req)
 }
 
-fn require_full_setup(req: PlainRequest) -> MiddlewareResult<AuthedRequest> {
-    let req = require_authentication(req);
+async fn require_full_setup(req: PlainRequest<'_>) -> MiddlewareResult<AuthedRequest<'_>> {
+    let req = require_authentication(req).await;
     let req = match req {
         MiddlewareResult::Continue(r) => r,
         MiddlewareResult::Finish(e) => return MiddlewareResult::Finish(e)
     };
 
-    let req = match req.has_passed_setup() {
+    let req = match req.has_passed_setup().await {
         Ok(r) => r,
         Err(e) => return MiddlewareResult::Finish(Err(e))
     };
@@ -165,32 +221,78 @@ fn require_full_setup(req: PlainRequest) -> MiddlewareResult<AuthedRequest> {
     }
 }
 
-fn require_authentication(req: PlainRequest) -> MiddlewareResult<SetupRequest> {
-    let req = req.authenticate();
+async fn require_full_setup_for_html(req: PlainRequest<'_>) -> MiddlewareResult<Negotiated<'_>> {
+    if req.is_ap_req() {
+        return MiddlewareResult::Continue(Negotiated::ActivityPub(req));
+    }
+
+    match require_full_setup(req).await {
+        MiddlewareResult::Continue(r) => MiddlewareResult::Continue(Negotiated::Html(r)),
+        MiddlewareResult::Finish(res) => MiddlewareResult::Finish(server_response::vary_on_accept(res))
+    }
+}
+
+async fn require_authentication(req: PlainRequest<'_>) -> MiddlewareResult<SetupRequest<'_>> {
+    let req = req.authenticate().await;
     match req {
-        AuthStatus::Success(r) => MiddlewareResult::Continue(r),
+        AuthStatus::Success(r) if has_valid_csrf_token(&r) => MiddlewareResult::Continue(r),
+        AuthStatus::Success(_) => {
+            warn!("Rejecting request with a missing or invalid CSRF token");
+            MiddlewareResult::Finish(Err(forbidden()))
+        }
         AuthStatus::Failure(_) => MiddlewareResult::Finish(Err(forbidden()))
     }
 }
 
-fn log_warn_and_send_specific_message(err: ServerError) -> ServerResult {
+// Client apps send an access token instead of a session cookie, so they don't need a CSRF token
+async fn require_token(req: PlainRequest<'_>) -> MiddlewareResult<ApiRequest<'_>> {
+    match req.authenticate_token().await {
+        Ok(TokenStatus::Success(r)) => MiddlewareResult::Continue(r),
+        Ok(TokenStatus::Failure(_)) => MiddlewareResult::Finish(Err(unauthorized("The access token is invalid"))),
+        Err(e) => MiddlewareResult::Finish(Err(e)),
+    }
+}
+
+// Anything that can change state has to prove it came from one of our own pages
+fn has_valid_csrf_token(req: &SetupRequest) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+
+    let provided = req.headers().get(CSRF_HEADER).and_then(|h| h.to_str().ok());
+    req.data.csrf_token().is_some_and(|expected| is_valid_csrf_token(expected, provided))
+}
+
+// Client apps get their errors as JSON, rather than as a page meant for people
+fn is_api_path(path: &str) -> bool {
+    path.starts_with("/api/") || path.starts_with("/oauth/")
+}
+
+fn log_warn_and_send_specific_message(err: ServerError, is_api: bool) -> ServerResult {
     warn!("Returning {} with error: {}", err.status_code, err);
+    if is_api {
+        return server_response::send_json_error(err.status_code, &err.message);
+    }
     server_response::send_status_and_message(err)
 }
 
-fn log_error_and_send_generic_message(err: ServerError) -> ServerResult {
+fn log_error_and_send_generic_message(err: ServerError, is_api: bool) -> ServerResult {
     error!("{}", err);
+    if is_api {
+        return server_response::send_json_error(err.status_code, "");
+    }
     server_response::send_status(err.status_code)
 }
 
 pub async fn serve(req: Request<Incoming>, g_ctx: Arc<GlobalContext<'_>>) -> ServerResult {
+    let is_api = is_api_path(req.uri().path());
     let result = router(req, g_ctx).await;
     if let Err(err) = result {
         // 4xx error messages are passed onto users, the rest aren't
         match err.status_code.as_u16() {
-            400..=499 => log_warn_and_send_specific_message(err),
-            500..=599 => log_error_and_send_generic_message(err),
-            _ => log_error_and_send_generic_message(err),
+            400..=499 => log_warn_and_send_specific_message(err, is_api),
+            500..=599 => log_error_and_send_generic_message(err, is_api),
+            _ => log_error_and_send_generic_message(err, is_api),
         }
     } else {
         result
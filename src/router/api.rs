@@ -0,0 +1,10 @@
+use crate::server::error::not_found;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::ServerResult;
+
+pub mod v1;
+
+// Anything else under the API gets its 404 as JSON, rather than as the page meant for people
+pub async fn get_not_found(_req: PlainRequest<'_>) -> ServerResult {
+    Err(not_found())
+}
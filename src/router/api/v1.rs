@@ -0,0 +1,6 @@
+pub mod accounts;
+pub mod apps;
+pub mod follows;
+pub mod instance;
+pub mod statuses;
+pub mod timelines;
@@ -0,0 +1,2 @@
+pub mod _account_id;
+pub mod verify_credentials;
@@ -0,0 +1,18 @@
+use serde_json::json;
+
+use crate::mastodon::account::get_account;
+use crate::mastodon::ApiId;
+use crate::server::error::not_found;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+pub mod statuses;
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let id = req.get_url_param(4, "Missing account ID")?;
+    let id = ApiId::parse(id).ok_or_else(not_found)?;
+
+    let domain = req.domain.clone();
+    let account = req.db.run(move |db| get_account(db, &id, &domain)).await?.ok_or_else(not_found)?;
+    Ok(send_json(json!(account).to_string()))
+}
@@ -0,0 +1,64 @@
+use hyper::header::{HeaderValue, LINK};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::mastodon::account::get_account;
+use crate::mastodon::pagination::{get_link_header, PageQuery};
+use crate::mastodon::status::get_statuses;
+use crate::mastodon::ApiId;
+use crate::queries::{get_posts_by_actor, get_posts_in_profile, FeedQuery};
+use crate::server::error::{map_bad_request, not_found};
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+#[derive(Default, Deserialize)]
+struct Filters {
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    exclude_replies: bool,
+    #[serde(default)]
+    only_media: bool,
+}
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let account_id = req.get_url_param(4, "Missing account ID")?;
+    let id = ApiId::parse(account_id).ok_or_else(not_found)?;
+
+    let query = req.uri().query().unwrap_or_default();
+    let page: PageQuery = serde_html_form::from_str(query).map_err(map_bad_request)?;
+    let filters: Filters = serde_html_form::from_str(query).map_err(map_bad_request)?;
+
+    let base_url = format!("https://{}/api/v1/accounts/{}/statuses", req.domain, account_id);
+    let domain = req.domain.clone();
+    let page = req.db.run(move |db| {
+        get_account(db, &id, &domain)?.ok_or_else(not_found)?;
+
+        // Nothing can be pinned yet
+        if filters.pinned {
+            return Ok(None);
+        }
+
+        let Some(feed) = page.get_feed_query(db, &domain)? else {
+            return Ok(None);
+        };
+        let feed = FeedQuery { exclude_replies: filters.exclude_replies, only_media: filters.only_media, ..feed };
+        let posts = match &id {
+            ApiId::Local(profile_id) => get_posts_in_profile(db, *profile_id, &domain, false, &feed)?,
+            ApiId::Remote(actor_id) => get_posts_by_actor(db, actor_id, &domain, &feed)?,
+        };
+
+        let link = get_link_header(&posts, &base_url, &domain);
+        let statuses = get_statuses(db, posts, &domain)?;
+        Ok(Some((statuses, link)))
+    }).await?;
+    let Some((statuses, link)) = page else {
+        return Ok(send_json("[]"));
+    };
+
+    let mut res = send_json(json!(statuses).to_string());
+    if let Some(link) = link {
+        res.headers_mut().insert(LINK, HeaderValue::from_str(&link)?);
+    }
+    Ok(res)
+}
@@ -0,0 +1,17 @@
+use serde_json::json;
+
+use crate::mastodon::account::get_credential_account;
+use crate::server::error::not_found;
+use crate::server::server_request::ApiRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+pub async fn get(req: ApiRequest<'_>) -> ServerResult {
+    req.data.require_scope("read:accounts")?;
+    let profile_id = req.data.current_profile.profile_id;
+
+    let domain = req.domain.clone();
+    let account = req.db.run(move |db| get_credential_account(db, profile_id, &domain))
+        .await?
+        .ok_or_else(not_found)?;
+    Ok(send_json(json!(account).to_string()))
+}
@@ -0,0 +1,35 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::server::oauth::create_app;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+#[derive(Deserialize)]
+struct AppForm {
+    client_name: String,
+    redirect_uris: String,
+    scopes: Option<String>,
+    website: Option<String>,
+}
+
+pub async fn post(req: PlainRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: AppForm = req.parse_form_or_json()?;
+
+    let app = req.db.run(move |db| {
+        create_app(db, &form.client_name, form.website.as_deref(), &form.redirect_uris, form.scopes.as_deref())
+    }).await?;
+
+    // https://docs.joinmastodon.org/entities/Application/
+    let body = json!({
+        "id": app.client_id,
+        "name": app.name,
+        "website": app.website,
+        "redirect_uri": app.redirect_uris.join("\n"),
+        "scopes": app.scopes.split(' ').collect::<Vec<_>>(),
+        "client_id": app.client_id,
+        "client_secret": app.client_secret,
+    });
+    Ok(send_json(body.to_string()))
+}
@@ -0,0 +1,34 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::activitypub::actor_cache::get_or_search_for_actor;
+use crate::activitypub::get_full_handle;
+use crate::mastodon::account::get_remote_account;
+use crate::router::follow::send_follow;
+use crate::server::error::not_found;
+use crate::server::server_request::ApiRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+#[derive(Deserialize)]
+struct FollowForm {
+    uri: String,
+}
+
+// The old way of following someone by their handle, from before clients looked accounts up first
+pub async fn post(req: ApiRequest<'_>) -> ServerResult {
+    req.data.require_scope("write:follows")?;
+    let req = req.into_text().await?;
+    let form: FollowForm = req.parse_form_or_json()?;
+    let handle = get_full_handle(&form.uri)?;
+
+    let actor = get_or_search_for_actor(&req.db, &handle, &req.data.current_profile)
+        .await?
+        .ok_or_else(not_found)?;
+    let profile_id = req.data.current_profile.profile_id;
+    let domain = req.domain.clone();
+    let account = req.db.run(move |db| {
+        send_follow(db, profile_id, &actor.id, &actor.inbox, &domain)?;
+        get_remote_account(db, &actor.id, &domain)
+    }).await?.ok_or_else(not_found)?;
+    Ok(send_json(json!(account).to_string()))
+}
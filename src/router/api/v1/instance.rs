@@ -0,0 +1,12 @@
+use serde_json::json;
+
+use crate::mastodon::instance::get_instance;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let domain = req.domain.clone();
+    let hide_usage_stats = req.global.config.hide_usage_stats;
+    let instance = req.db.run(move |db| get_instance(db, &domain, hide_usage_stats)).await?;
+    Ok(send_json(json!(instance).to_string()))
+}
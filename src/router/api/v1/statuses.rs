@@ -0,0 +1,61 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::mastodon::status::get_statuses;
+use crate::mastodon::ApiId;
+use crate::queries::get_post_by_object_id;
+use crate::router::posts::{publish_post, Draft};
+use crate::server::error::{bad_request, not_found};
+use crate::server::server_request::ApiRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+pub mod _status_id;
+
+#[derive(Deserialize)]
+struct StatusForm {
+    status: String,
+    in_reply_to_id: Option<String>,
+    #[serde(default, rename = "media_ids[]", alias = "media_ids")]
+    media_ids: Vec<String>,
+    spoiler_text: Option<String>,
+    visibility: Option<String>,
+}
+
+pub async fn post(req: ApiRequest<'_>) -> ServerResult {
+    req.data.require_scope("write:statuses")?;
+    let req = req.into_text().await?;
+    let form: StatusForm = req.parse_form_or_json()?;
+
+    // Refuse anything that would be posted differently than asked, rather than quietly ignoring it
+    if form.visibility.as_deref().is_some_and(|v| v != "public") {
+        return Err(bad_request("Only public posts are supported"));
+    }
+    if form.spoiler_text.as_deref().is_some_and(|s| !s.is_empty()) {
+        return Err(bad_request("Content warnings are not supported"));
+    }
+    if !form.media_ids.is_empty() {
+        return Err(bad_request("Attachments are not supported through the API"));
+    }
+    if form.status.trim().is_empty() {
+        return Err(bad_request("Posts can't be empty"));
+    }
+
+    let in_reply_to = match form.in_reply_to_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(id) => {
+            let id = ApiId::parse(id).ok_or_else(|| bad_request("Can't reply to a post that we don't know about"))?;
+            Some(id.to_object_id("posts", &req.domain))
+        }
+        None => None,
+    };
+
+    let draft = Draft { content: form.status, in_reply_to, uploads: vec![] };
+    let post_id = publish_post(&req.db, &req.global.config.media_path, &req.domain, &req.data.current_profile, draft).await?;
+
+    let object_id = format!("https://{}/posts/{}", req.domain, post_id);
+    let domain = req.domain.clone();
+    let status = req.db.run(move |db| {
+        let post = get_post_by_object_id(db, &object_id, &domain)?.ok_or_else(not_found)?;
+        get_statuses(db, vec![post], &domain)?.pop().ok_or_else(not_found)
+    }).await?;
+    Ok(send_json(json!(status).to_string()))
+}
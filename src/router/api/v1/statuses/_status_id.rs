@@ -0,0 +1,53 @@
+use rusqlite::OptionalExtension;
+use serde_json::json;
+
+use crate::mastodon::status::{get_statuses, Status};
+use crate::mastodon::ApiId;
+use crate::queries::get_post_by_object_id;
+use crate::router::posts::delete_post;
+use crate::server::error::not_found;
+use crate::server::server_request::{ApiRequest, AuthState, AnyRequest, PlainRequest};
+use crate::server::server_response::{send_json, InternalResult, ServerResult};
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let status = get_status(&req).await?;
+    Ok(send_json(json!(status).to_string()))
+}
+
+pub async fn delete(req: ApiRequest<'_>) -> ServerResult {
+    req.data.require_scope("write:statuses")?;
+    let status_id = req.get_url_param(4, "Missing status ID")?;
+    let Some(ApiId::Local(post_id)) = ApiId::parse(status_id) else {
+        return Err(not_found());
+    };
+
+    // Other profiles' posts are hidden from the app, rather than forbidden
+    let profile_id: Option<i64> = req.db.run(move |db| {
+        let profile_id = db
+            .query_row("SELECT profile_id FROM posts WHERE post_id = ?1", [post_id], |row| row.get(0))
+            .optional()?;
+        Ok(profile_id)
+    }).await?;
+    if profile_id != Some(req.data.current_profile.profile_id) {
+        return Err(not_found());
+    }
+
+    // Clients use what's sent back to offer to redraft the post, so get it before it's gone
+    let status = get_status(&req).await?;
+    let media_path = req.global.config.media_path.clone();
+    let domain = req.domain.clone();
+    req.db.run(move |db| delete_post(db, &media_path, &post_id.to_string(), &domain)).await?;
+    Ok(send_json(json!(status).to_string()))
+}
+
+// Deleted posts are gone from the feed queries, so there's no need to check for a tombstone
+async fn get_status<Au: AuthState>(req: &AnyRequest<'_, Au>) -> InternalResult<Status> {
+    let status_id = req.get_url_param(4, "Missing status ID")?;
+    let object_id = ApiId::parse(status_id).ok_or_else(not_found)?.to_object_id("posts", &req.domain);
+
+    let domain = req.domain.clone();
+    req.db.run(move |db| {
+        let post = get_post_by_object_id(db, &object_id, &domain)?.ok_or_else(not_found)?;
+        get_statuses(db, vec![post], &domain)?.pop().ok_or_else(not_found)
+    }).await
+}
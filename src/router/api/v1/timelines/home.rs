@@ -0,0 +1,37 @@
+use hyper::header::{HeaderValue, LINK};
+use serde_json::json;
+
+use crate::mastodon::pagination::{get_link_header, PageQuery};
+use crate::mastodon::status::get_statuses;
+use crate::queries::get_home_timeline;
+use crate::server::error::map_bad_request;
+use crate::server::server_request::ApiRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+pub async fn get(req: ApiRequest<'_>) -> ServerResult {
+    req.data.require_scope("read:statuses")?;
+    let query = req.uri().query().unwrap_or_default();
+    let page: PageQuery = serde_html_form::from_str(query).map_err(map_bad_request)?;
+    let profile_id = req.data.current_profile.profile_id;
+
+    let base_url = format!("https://{}/api/v1/timelines/home", req.domain);
+    let domain = req.domain.clone();
+    let page = req.db.run(move |db| {
+        let Some(feed) = page.get_feed_query(db, &domain)? else {
+            return Ok(None);
+        };
+        let posts = get_home_timeline(db, profile_id, &domain, &feed)?;
+        let link = get_link_header(&posts, &base_url, &domain);
+        let statuses = get_statuses(db, posts, &domain)?;
+        Ok(Some((statuses, link)))
+    }).await?;
+    let Some((statuses, link)) = page else {
+        return Ok(send_json("[]"));
+    };
+
+    let mut res = send_json(json!(statuses).to_string());
+    if let Some(link) = link {
+        res.headers_mut().insert(LINK, HeaderValue::from_str(&link)?);
+    }
+    Ok(res)
+}
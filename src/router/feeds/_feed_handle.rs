@@ -1,7 +1,8 @@
 use hyper::Uri;
 use minijinja::context;
 
-use crate::activitypub::get_full_handle;
+use crate::activitypub::{actor_cache, get_full_handle};
+use crate::activitypub::content::sanitize_html;
 use crate::activitypub::objects::outbox::Object::Note;
 use crate::activitypub::objects::outbox::PageOrLink;
 use crate::activitypub::requests::{get_outbox, get_outbox_page};
@@ -9,12 +10,14 @@ use crate::queries;
 use crate::server::error::bad_gateway;
 use crate::server::server_request::AuthedRequest;
 use crate::server::server_response::{send, ServerResult};
+use crate::templates::_partials::follow_button::FollowButton;
+use crate::templates::_partials::post::PostAttachment;
 
 pub async fn get(req: AuthedRequest<'_>) -> ServerResult {
-    let url_param = req.uri().path().split('/').last().unwrap();
+    let url_param = req.uri().path().split('/').next_back().unwrap();
     let handle = get_full_handle(url_param)?;
 
-    let actor = queries::get_or_search_for_actor(&handle, &req.data.current_profile).await?;
+    let actor = actor_cache::get_or_search_for_actor(&req.db, &handle, &req.data.current_profile).await?;
     let actor = match actor {
         None => return Ok(send("No account found")),
         Some(actor) => actor,
@@ -41,19 +44,25 @@ pub async fn get(req: AuthedRequest<'_>) -> ServerResult {
                 Note(n) => n,
                 _ => return None,
             };
+            let attachments = PostAttachment::from_remote(note.attachment);
             let context = context! {
+                attachments,
                 actor_name => actor.name,
                 actor_handle => handle.to_string(),
-                content => note.content,
+                content => sanitize_html(&note.content),
                 created_at => note.published,
                 avi_url => actor.icon.as_ref().unwrap().url.clone()
             };
             Some(context)
         })
         .collect();
+    let profile_id = req.data.current_profile.profile_id;
+    let actor_id = actor.id.clone();
+    let follow_status = req.db.run(move |db| queries::get_follow_status(db, profile_id, &actor_id)).await?;
+    let follow_button = FollowButton::new(&actor, follow_status);
     let actor = context! { handle => handle.to_string(), name => actor.name };
 
-    let context = context! { actor, posts };
+    let context = context! { actor, posts, follow_button };
     let body = req.render("feeds/_feed_handle.html", context)?;
     Ok(send(body))
 }
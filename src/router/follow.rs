@@ -1,7 +1,16 @@
+use minijinja::context;
+use rand::random;
+use rusqlite::{Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
+use crate::activitypub::delivery;
+use crate::activitypub::objects::outbox::{ActivityType, FollowActivity, UndoActivity};
+use crate::activitypub::objects::{AtContext, Context};
+use crate::server::error::{bad_request, map_bad_request, not_found};
 use crate::server::server_request::AuthedRequest;
-use crate::server::server_response::{send, ServerResult};
+use crate::server::server_response::{send, InternalResult, ServerResult};
+use crate::templates::_partials::follow_button::{FollowButton, FollowStatus};
 
 #[derive(Serialize, Deserialize)]
 struct Actor {
@@ -17,21 +26,133 @@ struct Actor {
 pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
     let req = req.into_text().await?;
     let form: Actor = req.get_form_data()?;
+    let profile_id = req.data.current_profile.profile_id;
 
-    req.db.execute(
-        "INSERT OR REPLACE INTO known_actors
-            (actor_id, url, preferred_username, name, inbox, outbox, summary)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-        (&form.id, &form.url, &form.preferred_username, &form.name, &form.inbox, &form.outbox, &form.summary),
+    let domain = req.domain.clone();
+    let form = req.db.run(move |db| {
+        db.execute(
+            "INSERT INTO known_actors
+                (actor_id, url, preferred_username, name, inbox, outbox, summary)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (actor_id) DO UPDATE SET
+                url = excluded.url,
+                preferred_username = excluded.preferred_username,
+                name = excluded.name,
+                inbox = excluded.inbox,
+                outbox = excluded.outbox,
+                summary = excluded.summary",
+            (&form.id, &form.url, &form.preferred_username, &form.name, &form.inbox, &form.outbox, &form.summary),
+        )?;
+
+        send_follow(db, profile_id, &form.id, &form.inbox, &domain)?;
+        Ok(form)
+    }).await?;
+
+    let follow_button = FollowButton {
+        id: form.id,
+        url: form.url,
+        name: form.name,
+        preferred_username: form.preferred_username,
+        inbox: form.inbox,
+        outbox: form.outbox,
+        summary: form.summary,
+        status: FollowStatus::Requested,
+    };
+    let body = req.render("_partials/follow-button.html", context! { follow_button })?;
+    Ok(send(body))
+}
+
+/// Ask a remote actor if we can follow them
+pub fn send_follow(db: &Connection, profile_id: i64, actor_id: &str, inbox: &str, domain: &str) -> InternalResult<()> {
+    let follow = FollowActivity {
+        context: Some(AtContext::Context(Context::ActivityStreams)),
+        id: format!("https://{}/activity/{}", domain, random::<u64>()),
+        activity_type: ActivityType::Follow,
+        actor: format!("https://{}/profiles/{}", domain, profile_id),
+        object: actor_id.to_owned(),
+    };
+
+    // The follow stays pending until the remote server sends back an Accept
+    db.execute(
+        "INSERT OR REPLACE INTO following (profile_id, actor_id, follow_activity_id, is_accepted)
+        VALUES (?1, ?2, ?3, FALSE)",
+        (profile_id, actor_id, &follow.id),
+    )?;
+
+    delivery::enqueue(db, profile_id, inbox, &json!(follow).to_string())
+}
+
+#[derive(Deserialize)]
+struct Unfollow {
+    id: String,
+}
+
+pub async fn delete(req: AuthedRequest<'_>) -> ServerResult {
+    // htmx sends the parameters for DELETE requests in the query string
+    let query = req.uri().query().ok_or(bad_request("Missing actor to unfollow"))?;
+    let form: Unfollow = serde_html_form::from_str(query).map_err(map_bad_request)?;
+    let profile_id = req.data.current_profile.profile_id;
+
+    let domain = req.domain.clone();
+    let follow_button = req.db.run(move |db| unfollow(db, profile_id, form.id, &domain)).await?;
+
+    let body = req.render("_partials/follow-button.html", context! { follow_button })?;
+    Ok(send(body))
+}
+
+// Returns the button to show in place of the Unfollow one
+fn unfollow(db: &Connection, profile_id: i64, actor_id: String, domain: &str) -> InternalResult<FollowButton> {
+    let follow_activity_id: Option<String> = db.query_row(
+        "SELECT follow_activity_id FROM following WHERE profile_id = ?1 AND actor_id = ?2",
+        (profile_id, &actor_id),
+        |row| row.get(0),
+    ).optional()?.ok_or_else(not_found)?;
+
+    let follow_button = db.query_row(
+        "SELECT actor_id, url, name, preferred_username, inbox, outbox, summary
+        FROM known_actors
+        WHERE actor_id = ?1",
+        [&actor_id],
+        |row| {
+            let follow_button = FollowButton {
+                id: row.get(0)?,
+                url: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                name: row.get(2)?,
+                preferred_username: row.get(3)?,
+                inbox: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                outbox: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                summary: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                status: FollowStatus::None,
+            };
+            Ok(follow_button)
+        },
     )?;
 
-    req.db.execute(
-        "INSERT INTO following (profile_id, actor_id)
-        VALUES (?1, ?2)",
-        (req.data.current_profile.profile_id, form.id),
+    db.execute(
+        "DELETE FROM following WHERE profile_id = ?1 AND actor_id = ?2",
+        (profile_id, &actor_id),
     )?;
 
+    // If we never stored the Follow's id, remote servers can still match the Undo on the actors
+    let actor = format!("https://{}/profiles/{}", domain, profile_id);
+    let follow = FollowActivity {
+        context: None,
+        id: follow_activity_id.unwrap_or_else(|| format!("https://{}/activity/{}", domain, random::<u64>())),
+        activity_type: ActivityType::Follow,
+        actor: actor.clone(),
+        object: actor_id,
+    };
+    let undo = UndoActivity {
+        context: Some(AtContext::Context(Context::ActivityStreams)),
+        id: format!("https://{}/activity/{}", domain, random::<u64>()),
+        activity_type: ActivityType::Undo,
+        actor,
+        object: follow,
+    };
+
+    if !follow_button.inbox.is_empty() {
+        delivery::enqueue(db, profile_id, &follow_button.inbox, &json!(undo).to_string())?;
+    }
 
-    let res = "<button disabled>Followed!</button>".to_string();
-    Ok(send(res))
+    Ok(follow_button)
 }
@@ -1,41 +1,68 @@
 use minijinja::context;
-use rusqlite::named_params;
+use rusqlite::{named_params, Connection};
+use serde_json::json;
 
-use crate::queries::get_posts_in_profile;
+use crate::queries::{get_home_timeline, get_posts_in_profile, FeedQuery};
 use crate::query_row_custom;
 use crate::server::server_request::{AuthedRequest, AuthStatus, PlainRequest, SetupStatus};
-use crate::server::server_response::{self, redirect, ServerResult};
+use crate::server::server_response::{self, redirect, InternalResult, ServerResult};
 
 pub async fn get(req: PlainRequest<'_>) -> ServerResult {
-    let req = match req.authenticate() {
+    let req = match req.authenticate().await {
         AuthStatus::Success(r) => r,
-        AuthStatus::Failure(r) => return get_unauthed(r)
+        AuthStatus::Failure(r) => return get_unauthed(r).await
     };
 
-    match req.has_passed_setup()? {
+    match req.has_passed_setup().await? {
         SetupStatus::Complete(r) => get_authed(r).await,
         SetupStatus::Incomplete(_) => redirect("/profiles/new")
     }
 }
 
-pub fn get_unauthed(req: PlainRequest) -> ServerResult {
+pub async fn get_unauthed(req: PlainRequest<'_>) -> ServerResult {
     // TODO THIS IS OBVIOUSLY NOT HOW IT SHOULD WORK
-    let posts = get_posts_in_profile(&req.db, 1, false)?;
+    let domain = req.domain.clone();
+    let posts = req.db.run(move |db| get_posts_in_profile(db, 1, &domain, false, &FeedQuery::default())).await?;
     let body = req.render("index/index.html", context! { posts })?;
     Ok(server_response::send(body))
 }
 
 pub async fn get_authed(req: AuthedRequest<'_>) -> ServerResult {
     let current_profile_id = req.data.current_profile.profile_id;
-    let posts = get_posts_in_profile(&req.db, current_profile_id, true)?;
+    let domain = req.domain.clone();
+    let (posts, profile) = req.db.run(move |db| {
+        let posts = get_home_timeline(db, current_profile_id, &domain, &FeedQuery::default())?;
+        let profile = get_profile_summary(db, current_profile_id)?;
+        Ok((posts, profile))
+    }).await?;
 
+    let profile = match profile {
+        Some(x) => x,
+        None => return redirect("/profiles/new"),
+    };
+
+    let context = context! {
+        posts,
+        profile,
+        name => "Alex",
+    };
+
+    let body = req.render("index/index_auth.html", context)?;
+    Ok(server_response::send(body))
+}
+
+// The profile card beside the timeline, as JSON since its struct only exists inside the query
+fn get_profile_summary(db: &Connection, current_profile_id: i64) -> InternalResult<Option<serde_json::Value>> {
     let profile = query_row_custom!(
-        req.db,
+        db,
         Profile {
             profile_id: i64,
             preferred_username: String,
             display_name: String,
             nickname: String,
+            summary: Option<String>,
+            avatar_url: Option<String>,
+            header_url: Option<String>,
             following_count: i64,
             follower_count: i64
         },
@@ -45,6 +72,9 @@ pub async fn get_authed(req: AuthedRequest<'_>) -> ServerResult {
             preferred_username,
             display_name,
             nickname,
+            summary,
+            avatar_url,
+            header_url,
             (SELECT count(*) FROM following WHERE profile_id = :id) as following_count,
             (SELECT count(*) FROM followers WHERE profile_id = :id) as follower_count
         FROM profiles
@@ -52,17 +82,5 @@ pub async fn get_authed(req: AuthedRequest<'_>) -> ServerResult {
         named_params!{ ":id": current_profile_id }
     );
 
-    let profile = match profile {
-        Ok(x) => x,
-        Err(_) => return redirect("/profiles/new"),
-    };
-
-    let context = context! {
-        posts,
-        profile,
-        name => "Alex",
-    };
-
-    let body = req.render("index/index_auth.html", context)?;
-    Ok(server_response::send(body))
+    Ok(profile.ok().map(|profile| json!(profile)))
 }
@@ -1,35 +1,108 @@
 use hyper::header::{HeaderValue, SET_COOKIE};
+use hyper::StatusCode;
 use minijinja::context;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
 
-use crate::server::{server_request::{AnyRequest, PlainRequest}, server_response::{redirect, send, ServerResult}, utils::make_cookie};
+use crate::server::credentials::{check_password, has_two_factor, CredentialCheck};
+use crate::server::sessions::create_session;
+use crate::server::{server_request::{AnyRequest, PlainRequest, ServerRequest}, server_response::{redirect, send, ServerResult}, utils::make_cookie};
 use crate::server::server_request::AuthState;
 
+pub mod two_factor;
+
+// Where to go after logging in, for pages that needed a login first
+const RETURN_TO_COOKIE: &str = "return_to";
+
 pub async fn get<'a, Au: AuthState>(req: AnyRequest<'a, Au>) -> ServerResult {
-    let body = req.render("login.html", context! {})?;
-    Ok(send(body))
+    render_login(&req, None, StatusCode::OK)
 }
 
 #[derive(Deserialize)]
 struct FormData {
-    password: String
+    password: String,
 }
 
 pub async fn post(req: PlainRequest<'_>) -> ServerResult {
     let req = req.into_text().await?;
     let form: FormData = req.get_form_data()?;
-    let _pass = form.password;
-    let token: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
 
-    req.db.execute("INSERT INTO sessions (token) VALUES (?1)", (&token,))?;
+    match req.db.run(move |db| check_password(db, &form.password)).await? {
+        CredentialCheck::Correct => {
+            let needs_second_factor = req.db.run(has_two_factor).await?;
+            start_session(&req, needs_second_factor).await
+        }
+        CredentialCheck::Incorrect => {
+            render_login(&req, Some("Incorrect password"), StatusCode::UNAUTHORIZED)
+        }
+        CredentialCheck::LockedOut => {
+            let message = "Too many failed attempts, please try again later";
+            render_login(&req, Some(message), StatusCode::TOO_MANY_REQUESTS)
+        }
+    }
+}
+
+fn render_login<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    error: Option<&str>,
+    status: StatusCode,
+) -> ServerResult {
+    let context = context! { error };
+    let body = req.render("login.html", context)?;
+    let mut res = send(body);
+    *res.status_mut() = status;
+    Ok(res)
+}
+
+// With two-factor on, the session is only good for entering a code until that's done
+pub async fn start_session<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>, needs_second_factor: bool) -> ServerResult {
+    let ttl_secs = req.global.config.session_ttl_secs;
+    let token = req.db.run(move |db| create_session(db, ttl_secs, needs_second_factor)).await?;
+
+    let return_to = req.cookies.get(RETURN_TO_COOKIE).filter(|path| is_local_path(path));
+    let destination = match (needs_second_factor, return_to) {
+        (true, _) => "/login/two-factor",
+        (false, Some(path)) => path,
+        (false, None) => "/",
+    };
 
-    let mut res = redirect("/")?;
+    let mut res = redirect(destination)?;
     let cookie = make_cookie("token", &token);
     res.headers_mut().append(SET_COOKIE, HeaderValue::from_str(&cookie).unwrap());
+    if !needs_second_factor && req.cookies.contains_key(RETURN_TO_COOKIE) {
+        let cookie = format!("{}; Max-Age=0", make_cookie(RETURN_TO_COOKIE, ""));
+        res.headers_mut().append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    }
     Ok(res)
 }
+
+/// Send someone to log in, and then back to the page they were on
+pub fn redirect_to_login(return_to: &str) -> ServerResult {
+    let mut res = redirect("/login")?;
+    // Anything that won't fit in a cookie just means landing on the home page afterwards
+    if is_local_path(return_to) {
+        if let Ok(cookie) = HeaderValue::from_str(&make_cookie(RETURN_TO_COOKIE, return_to)) {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    Ok(res)
+}
+
+// Only paths on this site, so the cookie can't be used to send people somewhere else
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && path.chars().all(|c| c.is_ascii_graphic() && !matches!(c, ';' | ',' | '\\' | '"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_returns_to_local_paths() {
+        assert!(is_local_path("/oauth/authorize?client_id=abc&scope=read%20write"));
+        assert!(!is_local_path("//evil.example/"));
+        assert!(!is_local_path("https://evil.example/"));
+        assert!(!is_local_path("/path; Domain=evil.example"));
+    }
+}
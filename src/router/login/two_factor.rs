@@ -0,0 +1,66 @@
+use hyper::StatusCode;
+use minijinja::context;
+use serde::Deserialize;
+
+use crate::server::credentials::{check_second_factor, CredentialCheck};
+use crate::server::server_request::{AuthState, PlainRequest, ServerRequest};
+use crate::server::server_response::{redirect, send, InternalResult, ServerResult};
+use crate::server::sessions::{delete_session, is_awaiting_second_factor};
+
+use super::start_session;
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    if get_pending_token(&req).await?.is_none() {
+        return redirect("/login");
+    }
+    render_two_factor(&req, None, StatusCode::OK)
+}
+
+pub async fn post(req: PlainRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let token = match get_pending_token(&req).await? {
+        Some(token) => token,
+        None => return redirect("/login"),
+    };
+    let form: CodeForm = req.get_form_data()?;
+
+    match req.db.run(move |db| check_second_factor(db, &form.code)).await? {
+        // Swap the half-finished session for a new one, rather than upgrading it in place
+        CredentialCheck::Correct => {
+            req.db.run(move |db| delete_session(db, &token)).await?;
+            start_session(&req, false).await
+        }
+        CredentialCheck::Incorrect => {
+            render_two_factor(&req, Some("Incorrect code"), StatusCode::UNAUTHORIZED)
+        }
+        CredentialCheck::LockedOut => {
+            req.db.run(move |db| delete_session(db, &token)).await?;
+            let message = "Too many failed attempts, please try again later";
+            render_two_factor(&req, Some(message), StatusCode::TOO_MANY_REQUESTS)
+        }
+    }
+}
+
+async fn get_pending_token<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>) -> InternalResult<Option<String>> {
+    let token = match req.cookies.get("token") {
+        Some(token) => token.to_owned(),
+        None => return Ok(None),
+    };
+    req.db.run(move |db| Ok(is_awaiting_second_factor(db, &token)?.then_some(token))).await
+}
+
+fn render_two_factor<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    error: Option<&str>,
+    status: StatusCode,
+) -> ServerResult {
+    let body = req.render("login/two_factor.html", context! { error })?;
+    let mut res = send(body);
+    *res.status_mut() = status;
+    Ok(res)
+}
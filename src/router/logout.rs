@@ -1,10 +1,28 @@
-use crate::server::{server_request::AnyRequest, server_response::{redirect, ServerResult}};
-use crate::server::server_request::AuthState;
+use hyper::header::{HeaderValue, SET_COOKIE};
 
-pub async fn get<'a, Au: AuthState>(req: AnyRequest<'a, Au>) -> ServerResult {
-    req.db.execute("DELETE FROM sessions", ())?;
-    redirect("/")
+use crate::server::{server_request::SetupRequest, server_response::{redirect, ServerResult}, utils::make_cookie};
+use crate::server::oauth::revoke_all_tokens;
+use crate::server::sessions::{delete_all_sessions, delete_session};
+
+pub async fn post(req: SetupRequest<'_>) -> ServerResult {
+    if let Some(token) = req.cookies.get("token").cloned() {
+        req.db.run(move |db| delete_session(db, &token)).await?;
+    }
+    clear_session_cookie()
+}
+
+/// Log out every device, including this one, and every app
+pub async fn post_all(req: SetupRequest<'_>) -> ServerResult {
+    req.db.run(|db| {
+        delete_all_sessions(db)?;
+        revoke_all_tokens(db)
+    }).await?;
+    clear_session_cookie()
 }
 
-// pub fn post(req: UnauthedRequest<'_>) -> ServerResponse {
-// }
+fn clear_session_cookie() -> ServerResult {
+    let mut res = redirect("/")?;
+    let cookie = format!("{}; Max-Age=0", make_cookie("token", ""));
+    res.headers_mut().append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    Ok(res)
+}
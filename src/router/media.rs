@@ -0,0 +1,35 @@
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use rusqlite::OptionalExtension;
+
+use crate::media::read_media_file;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{not_found, send, ServerResult};
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let media_id = req.get_url_param(2, "Missing media ID")?.to_owned();
+    let lookup_id = media_id.clone();
+    let media_type: Option<String> = req.db.run(move |db| {
+        let media_type = db
+            .query_row("SELECT media_type FROM media WHERE media_id = ?1", [&lookup_id], |row| row.get(0))
+            .optional()?;
+        Ok(media_type)
+    }).await?;
+
+    let media_type = match media_type {
+        Some(media_type) => media_type,
+        None => return not_found(&req)
+    };
+
+    let data = match read_media_file(&req.global.config.media_path, &media_id).await {
+        Some(data) => data,
+        None => return not_found(&req)
+    };
+
+    // Media ids are never reused, so the file can be cached forever
+    let mut res = send(data);
+    let headers = res.headers_mut();
+    headers.append(CONTENT_TYPE, HeaderValue::from_str(&media_type)?);
+    headers.append(CACHE_CONTROL, HeaderValue::from_static("public, max-age=31536000, immutable"));
+    headers.append(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(res)
+}
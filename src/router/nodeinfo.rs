@@ -0,0 +1,15 @@
+use crate::activitypub::objects::nodeinfo::{get_nodeinfo, NODEINFO_CONTENT_TYPE};
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response;
+use crate::server::server_response::ServerResult;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use serde_json::json;
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let hide_usage_stats = req.global.config.hide_usage_stats;
+    let nodeinfo = req.db.run(move |db| get_nodeinfo(db, hide_usage_stats)).await?;
+    let body = json!(nodeinfo).to_string();
+    let mut res = server_response::send(body);
+    res.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static(NODEINFO_CONTENT_TYPE));
+    Ok(res)
+}
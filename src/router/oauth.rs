@@ -0,0 +1,3 @@
+pub mod authorize;
+pub mod revoke;
+pub mod token;
@@ -0,0 +1,110 @@
+use hyper::header::{HeaderName, HeaderValue};
+use minijinja::context;
+use serde::Deserialize;
+
+use crate::router::login::redirect_to_login;
+use crate::server::error::{bad_request, map_bad_request};
+use crate::server::oauth::{create_authorization_code, get_app, OAuthApp, OUT_OF_BAND_URI};
+use crate::server::server_request::{AuthState, AuthStatus, AuthedRequest, CurrentProfile, PlainRequest, ServerRequest, SetupStatus};
+use crate::server::server_response::{redirect, send, InternalResult, ServerResult};
+
+// htmx can't follow a redirect to an app's own URI scheme, so it's told to navigate there instead
+const HX_REDIRECT: HeaderName = HeaderName::from_static("hx-redirect");
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: Option<String>,
+    state: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeForm {
+    client_id: String,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    profile_id: i64,
+}
+
+// Apps open this in a browser, so anyone not logged in is sent to log in and then back here
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let return_to = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or_default().to_owned();
+    let req = match req.authenticate().await {
+        AuthStatus::Success(r) => r,
+        AuthStatus::Failure(_) => return redirect_to_login(&return_to),
+    };
+    let req = match req.has_passed_setup().await? {
+        SetupStatus::Complete(r) => r,
+        SetupStatus::Incomplete(_) => return redirect("/profiles/new"),
+    };
+
+    let query: AuthorizeQuery = serde_html_form::from_str(req.uri().query().unwrap_or_default())
+        .map_err(map_bad_request)?;
+    if query.response_type != "code" {
+        return Err(bad_request("Only the code response type is supported"));
+    }
+    let app = get_authorizing_app(&req, &query.client_id, &query.redirect_uri).await?;
+    let scope = app.get_requested_scopes(query.scope.as_deref())?;
+
+    let context = context! {
+        app,
+        scope,
+        scopes => scope.split(' ').collect::<Vec<_>>(),
+        redirect_uri => query.redirect_uri,
+        state => query.state,
+        current_profile_id => req.data.current_profile.profile_id,
+    };
+    let body = req.render("oauth/authorize.html", context)?;
+    Ok(send(body))
+}
+
+pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: AuthorizeForm = req.get_form_data()?;
+
+    let app = get_authorizing_app(&req, &form.client_id, &form.redirect_uri).await?;
+    let scope = app.get_requested_scopes(Some(&form.scope))?;
+
+    let (client_id, profile_id, redirect_uri) = (app.client_id.clone(), form.profile_id, form.redirect_uri.clone());
+    let domain = req.domain.clone();
+    let code = req.db.run(move |db| {
+        CurrentProfile::new(db, profile_id, &domain)
+            .ok_or_else(|| bad_request(&format!("Profile {} not found", profile_id)))?;
+        create_authorization_code(db, &client_id, profile_id, &redirect_uri, &scope)
+    }).await?;
+
+    // Apps without a way to catch the redirect have the user copy the code over by hand
+    if form.redirect_uri == OUT_OF_BAND_URI {
+        let body = req.render("oauth/authorize.html", context! { app, code })?;
+        return Ok(send(body));
+    }
+
+    let mut params = vec![("code", code.as_str())];
+    if let Some(state) = &form.state {
+        params.push(("state", state));
+    }
+    let params = serde_html_form::to_string(params).map_err(map_bad_request)?;
+    let separator = if form.redirect_uri.contains('?') { '&' } else { '?' };
+    let location = format!("{}{}{}", form.redirect_uri, separator, params);
+
+    let mut res = send("");
+    res.headers_mut().insert(HX_REDIRECT, HeaderValue::from_str(&location)?);
+    Ok(res)
+}
+
+// Codes only ever go back to a URI that the app registered, so they can't be sent anywhere else
+async fn get_authorizing_app<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    client_id: &str,
+    redirect_uri: &str,
+) -> InternalResult<OAuthApp> {
+    let client_id = client_id.to_owned();
+    let app = req.db.run(move |db| get_app(db, &client_id)).await?.ok_or_else(|| bad_request("Unknown client_id"))?;
+    if !app.redirect_uris.iter().any(|uri| uri == redirect_uri) {
+        return Err(bad_request("The redirect_uri doesn't match one registered by the app"));
+    }
+    Ok(app)
+}
@@ -0,0 +1,27 @@
+use serde::Deserialize;
+
+use crate::server::error::unauthorized;
+use crate::server::oauth::{get_app, revoke_token};
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    client_id: String,
+    client_secret: String,
+    token: String,
+}
+
+pub async fn post(req: PlainRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: RevokeForm = req.parse_form_or_json()?;
+
+    req.db.run(move |db| {
+        let app = get_app(db, &form.client_id)?
+            .filter(|app| app.has_secret(&form.client_secret))
+            .ok_or_else(|| unauthorized("Invalid client credentials"))?;
+        revoke_token(db, &app.client_id, &form.token)
+    }).await?;
+
+    Ok(send_json("{}"))
+}
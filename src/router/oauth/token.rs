@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::server::error::{bad_request, unauthorized};
+use crate::server::oauth::{exchange_authorization_code, get_app};
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send_json, ServerResult};
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    client_id: String,
+    client_secret: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+}
+
+// Tokens always act as a profile, so there's no client_credentials grant for apps acting as themselves
+pub async fn post(req: PlainRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: TokenForm = req.parse_form_or_json()?;
+
+    if form.grant_type != "authorization_code" {
+        return Err(bad_request("Only the authorization_code grant type is supported"));
+    }
+    let token = req.db.run(move |db| {
+        let app = get_app(db, &form.client_id)?
+            .filter(|app| app.has_secret(&form.client_secret))
+            .ok_or_else(|| unauthorized("Invalid client credentials"))?;
+
+        let code = form.code.ok_or_else(|| bad_request("Missing code"))?;
+        let redirect_uri = form.redirect_uri.ok_or_else(|| bad_request("Missing redirect_uri"))?;
+        exchange_authorization_code(db, &app.client_id, &code, &redirect_uri)?
+            .ok_or_else(|| bad_request("The authorization code is invalid or has expired"))
+    }).await?;
+
+    let body = json!({
+        "access_token": token.token,
+        "token_type": "Bearer",
+        "scope": token.scopes,
+        "created_at": token.created_at,
+    });
+    Ok(send_json(body.to_string()))
+}
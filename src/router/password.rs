@@ -0,0 +1,62 @@
+use hyper::StatusCode;
+use minijinja::context;
+use serde::Deserialize;
+
+use crate::server::credentials::{check_password, set_password, validate_new_password, CredentialCheck, MIN_PASSWORD_LENGTH};
+use crate::server::server_request::{AuthState, ServerRequest, SetupRequest};
+use crate::server::server_response::{send, ServerResult};
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    current_password: String,
+    new_password: String,
+    confirm_password: String,
+}
+
+pub async fn get(req: SetupRequest<'_>) -> ServerResult {
+    render_password(&req, None, None, StatusCode::OK)
+}
+
+pub async fn post(req: SetupRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: PasswordForm = req.get_form_data()?;
+
+    let current_password = form.current_password.clone();
+    match req.db.run(move |db| check_password(db, &current_password)).await? {
+        CredentialCheck::Correct => {}
+        CredentialCheck::Incorrect => {
+            return render_password(&req, Some("Current password is incorrect"), None, StatusCode::UNAUTHORIZED)
+        }
+        CredentialCheck::LockedOut => {
+            let message = "Too many failed attempts, please try again later";
+            return render_password(&req, Some(message), None, StatusCode::TOO_MANY_REQUESTS)
+        }
+    }
+
+    if let Err(e) = validate_new_password(&form.new_password, &form.confirm_password) {
+        return render_password(&req, Some(&e.message), None, StatusCode::BAD_REQUEST);
+    }
+
+    // Anyone else logged in with the old password gets logged out
+    let token = req.cookies.get("token").cloned().unwrap_or_default();
+    req.db.run(move |db| {
+        set_password(db, &form.new_password)?;
+        db.execute("DELETE FROM sessions WHERE token != ?1", [token])?;
+        Ok(())
+    }).await?;
+
+    render_password(&req, None, Some("Password changed"), StatusCode::OK)
+}
+
+fn render_password<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    error: Option<&str>,
+    message: Option<&str>,
+    status: StatusCode,
+) -> ServerResult {
+    let context = context! { error, message, min_length => MIN_PASSWORD_LENGTH };
+    let body = req.render("password.html", context)?;
+    let mut res = send(body);
+    *res.status_mut() = status;
+    Ok(res)
+}
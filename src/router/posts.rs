@@ -1,98 +1,306 @@
-use crate::activitypub::objects::note::get_post;
-use crate::activitypub::requests::send_as;
+use crate::activitypub::content::{render_html, tokenize, Token};
+use crate::activitypub::delivery::{enqueue_for_actors, enqueue_for_followers};
+use crate::activitypub::objects::note::{get_post, get_reply_mention, get_tombstone, save_post_tags, Tag};
+use crate::activitypub::objects::actor::Actor;
+use crate::media::{delete_media_files, new_media_id, sniff_media_type, write_media_file, MAX_ATTACHMENTS, MAX_UPLOAD_SIZE};
+use crate::activitypub::FullHandle;
+use crate::activitypub::actor_cache::get_or_search_for_actor;
+use crate::queries::get_post_by_object_id;
 use crate::query_map;
 use crate::router::debug;
-use crate::server::error::body_not_utf8;
-use crate::server::server_request::AuthedRequest;
-use crate::server::server_response::{send, ServerResult};
-use crate::templates::_partials::post::Post;
-use hyper::Uri;
+use crate::server::error::{bad_request, body_not_utf8, map_bad_request, not_found};
+use crate::server::multipart::{self, MultipartForm};
+use crate::server::server_request::{AuthedRequest, CurrentProfile};
+use crate::server::server_response::{send, InternalResult, ServerResult};
+use crate::sqlite::pool::Pool;
+use hyper::body::Bytes;
+use hyper::header::CONTENT_TYPE;
 use minijinja::context;
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::warn;
 use serde::Deserialize;
 use serde_json::json;
-use tracing::warn;
 
 pub mod _post_id;
 
 #[derive(Debug, Deserialize)]
 struct PostForm {
-    profile_id: String,
+    profile_id: Option<String>,
     content: String,
+    in_reply_to: Option<String>,
+    #[serde(default)]
+    alt: Vec<String>, // One for each attachment, in the same order
+}
+
+/// A post that's about to be published, however it was written
+pub struct Draft {
+    pub content: String,
+    pub in_reply_to: Option<String>,
+    pub uploads: Vec<Upload>,
+}
+
+pub struct Upload {
+    media_id: String,
+    media_type: &'static str,
+    alt_text: Option<String>,
+    data: Bytes,
 }
 
 pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
-    let req = req.into_text().await?;
-    let form: PostForm = req.get_form_data()?;
+    let content_type = req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let req = req.get_body_with_limit(MAX_UPLOAD_SIZE).await?;
+
+    // Posts with attachments come in as multipart forms, everything else is urlencoded
+    let (form, uploads) = match multipart::get_boundary(&content_type) {
+        Some(boundary) => {
+            let body = req.body().clone();
+            let multipart = multipart::parse(body, boundary).await?;
+            let form: PostForm = multipart.get_form_data()?;
+            let uploads = get_uploads(&multipart, &form.alt)?;
+            (form, uploads)
+        }
+        None => {
+            let form: PostForm = serde_html_form::from_bytes(req.body()).map_err(map_bad_request)?;
+            (form, vec![])
+        }
+    };
 
     // This should be the currently logged into profile, probably
-    let profile_id: i64 = form.profile_id.parse().map_err(|_| body_not_utf8())?;
-
-    req.db.execute(
-        "INSERT INTO posts (profile_id, content) VALUES (?1, ?2)",
-        (&profile_id, &form.content),
-    )?;
-    let post_id = req.db.last_insert_rowid();
-
-    let post: Post = req.db.query_row(
-        "
-        SELECT post_id, content, created_at, display_name, preferred_username
-        FROM posts
-        LEFT JOIN profiles USING (profile_id)
-        WHERE post_id = ?1
-        ",
-        (post_id,),
-        |row| {
-            let post = Post {
-                post_id: row.get(0)?,
-                content: row.get(1)?,
-                created_at: row.get(2)?,
-                actor_name: row.get(3)?,
-                actor_handle: row.get(4)?,
-                is_owner: true
-            };
-            Ok(post)
-        },
-    )?;
+    let profile_id: i64 = match &form.profile_id {
+        Some(profile_id) => profile_id.parse().map_err(|_| body_not_utf8())?,
+        None => req.data.current_profile.profile_id, // Reply forms leave it out
+    };
+    let domain = req.domain.clone();
+    let profile = req.db.run(move |db| Ok(CurrentProfile::new(db, profile_id, &domain)))
+        .await?
+        .ok_or_else(|| bad_request(&format!("Profile {} not found", profile_id)))?;
 
+    let draft = Draft { content: form.content, in_reply_to: form.in_reply_to, uploads };
+    let post_id = publish_post(&req.db, &req.global.config.media_path, &req.domain, &profile, draft).await?;
+
+    let object_id = format!("https://{}/posts/{}", req.domain, post_id);
+    let domain = req.domain.clone();
+    let post = req.db.run(move |db| get_post_by_object_id(db, &object_id, &domain))
+        .await?
+        .ok_or_else(not_found)?;
     let body = req.render("_partials/post.html", context! { post })?;
+    Ok(send(body))
+}
 
-    let post_to_federate = get_post(&req.db, &post_id.to_string(), &req.domain)?;
-    let followers = query_map!(
-        &req.db,
-        Follower { inbox: String },
-        "FROM followers LEFT JOIN known_actors USING (actor_id) WHERE profile_id = ?1",
-        [ profile_id ]
-    );
+/// Save a new post and send it out to followers and anyone it mentions, returning its id
+pub async fn publish_post(
+    pool: &Pool,
+    media_path: &Path,
+    domain: &str,
+    profile: &CurrentProfile,
+    draft: Draft,
+) -> InternalResult<i64> {
+    let profile_id = profile.profile_id;
+
+    // Each remote mention is a webfinger lookup, so they're all done before the database is touched
+    let remote_mentions = resolve_remote_mentions(pool, &draft.content, domain, profile).await;
 
-    // Notify followers
-    tokio::spawn(async move {
-        let create_activity = post_to_federate.into_create();
-        let body = json!(create_activity);
-        for follower in followers {
-            let body = body.to_string();
-            let inbox_uri: Uri = follower.inbox.parse().unwrap();
-            let res = send_as(&inbox_uri, &req.data.current_profile, body).await;
-            let res = match res {
-                Ok(r) => r,
-                Err(e) => { return warn!("Failed to send confirmation to {}: {:?}", inbox_uri, e) }
-            };
-
-            let code = res.status();
-            let res_body = res.text().await;
-            match res_body {
-                Ok(body) => debug!("Received {} {}", code, body),
-                Err(e) => warn!("Unable to reach {}: {:?}", inbox_uri, e)
-            };
+    let in_reply_to = draft.in_reply_to.filter(|id| !id.is_empty());
+    let (content, tags) = {
+        let (in_reply_to, domain) = (in_reply_to.clone(), domain.to_owned());
+        pool.run(move |db| get_content_and_tags(db, &draft.content, in_reply_to.as_deref(), &remote_mentions, &domain)).await?
+    };
 
+    // Get the files written before the post exists, so that it never points at missing media
+    let mut written = Vec::new();
+    for upload in &draft.uploads {
+        written.push(upload.media_id.clone());
+        if let Err(e) = write_media_file(media_path, &upload.media_id, upload.data.clone()).await {
+            delete_media_files(media_path, &written);
+            return Err(e);
         }
-    });
+    }
 
-    Ok(send(body))
+    let uploads = draft.uploads;
+    let domain = domain.to_owned();
+    let result = pool.run(move |db| {
+        // The post, its tags and media, and the Create going out all happen together, or not at all
+        let tx = db.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO posts (profile_id, content, in_reply_to) VALUES (?1, ?2, ?3)",
+            (&profile_id, &content, &in_reply_to),
+        )?;
+        let post_id = tx.last_insert_rowid();
+        save_post_tags(&tx, post_id, &tags)?;
+
+        for upload in &uploads {
+            tx.execute(
+                "INSERT INTO media (media_id, profile_id, post_id, media_type, alt_text) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (&upload.media_id, profile_id, post_id, upload.media_type, &upload.alt_text),
+            )?;
+        }
+
+        // Notify followers, and anyone who was mentioned or replied to
+        let mentioned: Vec<String> = tags.iter().filter_map(|tag| tag.mentioned_actor().map(str::to_owned)).collect();
+        let create_activity = get_post(&tx, &post_id.to_string(), &domain)?.into_create();
+        let create_activity = json!(create_activity).to_string();
+        enqueue_for_followers(&tx, profile_id, &create_activity)?;
+        enqueue_for_actors(&tx, profile_id, &mentioned, &create_activity)?;
+
+        tx.commit()?;
+        Ok(post_id)
+    }).await;
+
+    // Nothing will ever point at the files if the post didn't make it in
+    if result.is_err() {
+        delete_media_files(media_path, &written);
+    }
+    result
+}
+
+// Render the post's HTML, along with the tags for everyone it mentions or replies to
+fn get_content_and_tags(
+    db: &Connection,
+    text: &str,
+    in_reply_to: Option<&str>,
+    remote_mentions: &HashMap<String, Actor>,
+    domain: &str,
+) -> InternalResult<(String, Vec<Tag>)> {
+    let tokens = tokenize(text);
+    let mut mention_urls = HashMap::new();
+    let mut tags = Vec::new();
+
+    // We need to know who wrote the parent post in order to address the reply to them
+    if let Some(in_reply_to) = in_reply_to {
+        get_post_by_object_id(db, in_reply_to, domain)?
+            .ok_or_else(|| bad_request("Can't reply to a post that we don't know about"))?;
+        tags.extend(get_reply_mention(db, in_reply_to, domain)?);
+    }
+
+    for token in &tokens {
+        let tag = match token {
+            Token::Mention { preferred_username, host } => {
+                let host = host.unwrap_or(domain);
+                let (actor_id, url) = if host == domain {
+                    match get_local_actor_id(db, preferred_username, domain)? {
+                        Some(actor_id) => (actor_id.to_owned(), actor_id),
+                        None => continue,
+                    }
+                } else {
+                    // Looking them up saved them as a known actor, so the post can be delivered to them
+                    match remote_mentions.get(&token.source()) {
+                        Some(actor) => (actor.id.clone(), actor.url.clone()),
+                        None => continue,
+                    }
+                };
+
+                mention_urls.insert(token.source(), url);
+                Tag::mention(&actor_id, &format!("@{}@{}", preferred_username, host))
+            }
+            Token::Hashtag(name) => Tag::hashtag(name, domain),
+            Token::Text(_) => continue,
+        };
+
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    Ok((render_html(&tokens, &mention_urls, domain), tags))
+}
+
+fn get_uploads(multipart: &MultipartForm, alt_texts: &[String]) -> InternalResult<Vec<Upload>> {
+    let files = multipart.get_files("media");
+    if files.len() > MAX_ATTACHMENTS {
+        return Err(bad_request(&format!("Posts can have at most {} attachments", MAX_ATTACHMENTS)));
+    }
+
+    files.into_iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let media_type = sniff_media_type(&file.data)
+                .ok_or_else(|| bad_request("Attachments must be PNG, JPEG, GIF, WebP, MP4 or WebM files"))?;
+            let alt_text = alt_texts.get(i)
+                .map(|alt| alt.trim().to_owned())
+                .filter(|alt| !alt.is_empty());
+
+            let upload = Upload { media_id: new_media_id(), media_type, alt_text, data: file.data.clone() };
+            Ok(upload)
+        })
+        .collect()
+}
+
+fn get_local_actor_id(db: &Connection, preferred_username: &str, domain: &str) -> InternalResult<Option<String>> {
+    let profile_id: Option<i64> = db.query_row(
+        "SELECT profile_id FROM profiles WHERE preferred_username = ?1",
+        [preferred_username],
+        |row| row.get(0))
+        .optional()?;
+
+    Ok(profile_id.map(|id| format!("https://{}/profiles/{}", domain, id)))
+}
+
+// Keyed by the mention's text; any that can't be found are left as plain text, rather than failing the whole post
+async fn resolve_remote_mentions(pool: &Pool, text: &str, domain: &str, profile: &CurrentProfile) -> HashMap<String, Actor> {
+    let mut actors = HashMap::new();
+    for token in tokenize(text) {
+        let Token::Mention { preferred_username, host: Some(host) } = token else {
+            continue
+        };
+        if host == domain || actors.contains_key(&token.source()) {
+            continue
+        }
+
+        let handle = FullHandle { preferred_username: preferred_username.to_owned(), host: host.to_owned() };
+        match get_or_search_for_actor(pool, &handle, profile).await {
+            Ok(Some(actor)) => {
+                actors.insert(token.source(), actor);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to resolve mention of {}: {}", handle, e),
+        }
+    }
+    actors
 }
 
 pub async fn delete(req: AuthedRequest<'_>) -> ServerResult {
-    let post_param = req.get_url_param(2, "Missing post ID")?;
-    debug!("Deleting post {}", post_param);
-    req.db.execute("DELETE FROM posts WHERE post_id = ?1", [post_param])?;
+    let post_id = req.get_url_param(2, "Missing post ID")?.to_owned();
+    let media_path = req.global.config.media_path.clone();
+    let domain = req.domain.clone();
+    req.db.run(move |db| delete_post(db, &media_path, &post_id, &domain)).await?;
     Ok(send("".to_owned()))
 }
+
+/// Delete a local post and its media, leaving a tombstone and telling followers that it's gone
+pub fn delete_post(db: &Connection, media_path: &Path, post_id: &str, domain: &str) -> InternalResult<()> {
+    let profile_id: i64 = db
+        .query_row("SELECT profile_id FROM posts WHERE post_id = ?1", [post_id], |row| row.get(0))
+        .optional()?
+        .ok_or_else(not_found)?;
+
+    let media_ids = query_map!(
+        db,
+        Media { media_id: String },
+        "FROM media WHERE post_id = ?1",
+        [post_id]
+    );
+
+    debug!("Deleting post {}", post_id);
+    // The post, its tombstone and the Delete going out all happen together, or not at all
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM posts WHERE post_id = ?1", [post_id])?;
+
+    // Keep a record of the deletion so that we can tell anyone who asks for it later
+    tx.execute("INSERT INTO tombstones (post_id, profile_id) VALUES (?1, ?2)", (post_id, profile_id))?;
+
+    let tombstone = get_tombstone(&tx, post_id, domain)?.ok_or_else(not_found)?;
+    let actor_id = format!("https://{}/profiles/{}", domain, profile_id);
+    let delete_activity = tombstone.into_delete(&actor_id);
+    enqueue_for_followers(&tx, profile_id, &json!(delete_activity).to_string())?;
+    tx.commit()?;
+
+    // Files can't be rolled back, so they only go once the post is gone for good
+    delete_media_files(media_path, &media_ids.into_iter().map(|m| m.media_id).collect::<Vec<_>>());
+    Ok(())
+}
@@ -1,54 +1,83 @@
-use crate::activitypub::objects::note::{get_post, Note};
-use crate::query_row_custom;
-use crate::server::server_request::{AnyRequest, AuthState};
-use crate::server::server_response::{send, ServerResult};
+use crate::activitypub::objects::note::{get_post, get_tombstone, Note};
+use crate::activitypub::objects::{AtContext, Context};
+use crate::queries::{get_post_by_object_id, get_thread_ancestors, get_thread_replies};
+use crate::server::server_request::{AnyRequest, AuthState, AuthStatus, PlainRequest, SetupStatus};
+use crate::server::server_response::{gone, not_found, send, send_activity_json, vary_on_accept, ServerResult};
 
+use hyper::StatusCode;
 use minijinja::context;
 use serde_json::json;
 
-pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
-    match req.is_ap_req() {
-        true => get_json(req),
-        false => get_html(req)
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    vary_on_accept(negotiate(req).await)
+}
+
+async fn negotiate(req: PlainRequest<'_>) -> ServerResult {
+    if req.is_ap_req() {
+        return get_json(req).await;
+    }
+
+    // Threads are public, but logged in users get to reply to them
+    let req = match req.authenticate().await {
+        AuthStatus::Success(r) => r,
+        AuthStatus::Failure(r) => return get_html(r).await
+    };
+
+    match req.has_passed_setup().await? {
+        SetupStatus::Complete(r) => get_html(r).await,
+        SetupStatus::Incomplete(r) => get_html(r).await
     }
 }
 
-fn get_html<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
-    let post_id = req.get_url_param(2, "Missing post ID")?;
-    let post = query_row_custom!(
-        req.db,
-        Post {
-            post_id: i64,
-            actor_name: String,
-            actor_handle: String,
-            content: String,
-            created_at: String,
-            display_name: String,
-            preferred_username: String
-        },
-        "
-        SELECT
-            post_id,
-            display_name as actor_name,
-            preferred_username as actor_handle,
-            content,
-            created_at,
-            display_name,
-            preferred_username
-        FROM posts
-        LEFT JOIN profiles USING (profile_id)
-        WHERE post_id = ?1
-        ",
-        [post_id])?;
-
-    let body = req.render("posts/_post_id.html", context! { post })?;
+async fn get_html<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
+    let post_id = req.get_url_param(2, "Missing post ID")?.to_owned();
+    let object_id = format!("https://{}/posts/{}", req.domain, post_id);
+
+    let domain = req.domain.clone();
+    let tombstone = req.db.run(move |db| get_tombstone(db, &post_id, &domain)).await?;
+    if tombstone.is_some() {
+        return gone(&req);
+    }
+
+    let domain = req.domain.clone();
+    let thread = req.db.run(move |db| {
+        let Some(post) = get_post_by_object_id(db, &object_id, &domain)? else {
+            return Ok(None);
+        };
+        let ancestors = get_thread_ancestors(db, &object_id, &domain)?;
+        let replies = get_thread_replies(db, &object_id, &domain)?;
+        Ok(Some((post, ancestors, replies)))
+    }).await?;
+
+    let (post, ancestors, replies) = match thread {
+        Some(thread) => thread,
+        None => return not_found(&req)
+    };
+
+    let body = req.render("posts/_post_id.html", context! { post, ancestors, replies })?;
     Ok(send(body))
 }
 
-fn get_json<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
-    let post_id = req.get_url_param(2, "Missing post ID")?;
-    let note: Note = get_post(&req.db, post_id, &req.domain)?.into();
+async fn get_json<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
+    let post_id = req.get_url_param(2, "Missing post ID")?.to_owned();
+    let domain = req.domain.clone();
+    let post = req.db.run(move |db| match get_tombstone(db, &post_id, &domain)? {
+        Some(tombstone) => Ok(Err(tombstone)),
+        None => Ok(Ok(get_post(db, &post_id, &domain)?)),
+    }).await?;
+
+    let post = match post {
+        Ok(post) => post,
+        Err(mut tombstone) => {
+            tombstone.context = Some(AtContext::Context(Context::ActivityStreams));
+            let mut res = send_activity_json(json!(tombstone).to_string());
+            *res.status_mut() = StatusCode::GONE;
+            return Ok(res);
+        }
+    };
+
+    let note: Note = post.into();
     let body = json!(note).to_string();
-    Ok(send(body))
+    Ok(send_activity_json(body))
 }
 
@@ -1,16 +1,16 @@
-use crate::activitypub::objects::actor::{Actor, ActorType, Icon, PublicKey};
-use crate::activitypub::objects::Context;
-use crate::queries::get_posts_in_profile;
-use crate::server::error::bad_request;
+use crate::activitypub::objects::actor::get_local_actor;
+use crate::activitypub::objects::collection::{get_network_collection, get_network_page, is_network_hidden, Network};
+use crate::queries::{get_posts_in_profile, FeedQuery};
+use crate::server::error::{bad_request, forbidden};
 use crate::server::server_request::{AnyRequest, AuthState};
 use crate::server::server_response::{self, not_found};
-use crate::server::server_response::{send, ServerResult};
-use hyper::header::{HeaderValue, CONTENT_TYPE};
+use crate::server::server_response::{send_activity_json, vary_on_accept, ServerResult};
 use minijinja::context;
 use rusqlite::named_params;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+pub mod edit;
 pub mod inbox;
 pub mod outbox;
 pub mod following;
@@ -22,9 +22,11 @@ struct Profile {
     preferred_username: String,
     display_name: String,
     nickname: String,
+    summary: Option<String>,
+    avatar_url: Option<String>,
+    header_url: Option<String>,
     following_count: i64,
     follower_count: i64,
-    private_key_pem: String,
 }
 
 pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
@@ -37,16 +39,18 @@ pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
     .parse::<i64>()
     .map_err(|_| bad_request("Invalid profile ID"))?;
 
-    let profile = req.db.query_row(
+    let profile = req.db.run(move |db| Ok(db.query_row(
         "
         SELECT
             profile_id,
             preferred_username,
             display_name,
             nickname,
+            summary,
+            avatar_url,
+            header_url,
             (SELECT count(*) FROM following WHERE profile_id = :id) as following_count,
-            (SELECT count(*) FROM followers WHERE profile_id = :id) as follower_count,
-            private_key_pem
+            (SELECT count(*) FROM followers WHERE profile_id = :id) as follower_count
         FROM profiles
         where profile_id = :id",
         named_params!{ ":id": profile_id },
@@ -56,28 +60,32 @@ pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
                 preferred_username: row.get(1)?,
                 display_name: row.get(2)?,
                 nickname: row.get(3)?,
-                following_count: row.get(4)?,
-                follower_count: row.get(5)?,
-                private_key_pem: row.get(6)?,
+                summary: row.get(4)?,
+                avatar_url: row.get(5)?,
+                header_url: row.get(6)?,
+                following_count: row.get(7)?,
+                follower_count: row.get(8)?,
             };
             Ok(profile)
         },
-    );
+    ).ok())).await?;
 
     let profile = match profile {
-        Ok(x) => x,
-        Err(_) => return not_found(&req)
+        Some(x) => x,
+        None => return not_found(&req)
     };
 
-    match req.is_ap_req() {
-        true => serve_json_profile(req, profile),
+    vary_on_accept(match req.is_ap_req() {
+        true => serve_json_profile(req, profile).await,
         false => serve_html_profile(req, profile).await
-    }
+    })
 }
 
 async fn serve_html_profile<Au: AuthState>(req: AnyRequest<'_, Au>, profile: Profile) -> ServerResult {
-    // let domain = req.domain;
-    let posts = get_posts_in_profile(&req.db, profile.profile_id, false)?;
+    let (profile_id, domain) = (profile.profile_id, req.domain.clone());
+    let posts = req.db.run(move |db| {
+        get_posts_in_profile(db, profile_id, &domain, false, &FeedQuery::default())
+    }).await?;
 
     let context = context! { profile => profile, posts => posts };
 
@@ -85,48 +93,39 @@ async fn serve_html_profile<Au: AuthState>(req: AnyRequest<'_, Au>, profile: Pro
     Ok(server_response::send(body))
 }
 
-fn serve_json_profile<Au: AuthState>(req: AnyRequest<'_, Au>, profile: Profile) -> ServerResult {
-    let domain = req.domain;
-    // let domain = query_row!(
-    //     req.db,
-    //     Domain { domain: String },
-    //     "FROM profiles WHERE profile_id = ?1",
-    //     (profile.profile_id, )
-    //     )?.domain;
-
-    let id = format!("https://{}/profiles/{}", domain, profile.profile_id);
-    let icon = Icon {
-        icon_type: "Image".to_owned(),
-        media_type: "image/jpeg".to_owned(),
-        url: format!("https://{}/static/images/pineapple.svg", domain)
+async fn serve_json_profile<Au: AuthState>(req: AnyRequest<'_, Au>, profile: Profile) -> ServerResult {
+    let (profile_id, domain) = (profile.profile_id, req.domain.clone());
+    let actor = req.db.run(move |db| get_local_actor(db, profile_id, &domain)).await?;
+
+    let body = json!(actor).to_string();
+    Ok(send_activity_json(body))
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    page: i64
+}
+
+/// Followers and following are public over ActivityPub, even though the HTML pages are not
+async fn serve_network_collection<Au: AuthState>(req: AnyRequest<'_, Au>, network: Network) -> ServerResult {
+    let profile_id = req.get_int_url_param(2, "Invalid Profile ID")?;
+    let is_hidden = match req.db.run(move |db| Ok(is_network_hidden(db, profile_id).ok())).await? {
+        Some(x) => x,
+        None => return not_found(&req)
     };
 
-    let inbox = format!("https://{}/inbox", domain);
-    let outbox = format!("https://{}/profiles/{}/outbox", domain, profile.profile_id);
-    let following = format!("https://{}/profiles/{}/following", domain, profile.profile_id);
-    let followers = format!("https://{}/profiles/{}/followers", domain, profile.profile_id);
-    let public_key = PublicKey::new(&id, &profile.private_key_pem);
-
-    let context = vec![Context::ActivityStreams, Context::SecurityV1];
-    let actor = Actor {
-        context,
-        id: id.to_owned(),
-        url: id.to_owned(),
-        name: profile.display_name,
-        actor_type: ActorType::Person,
-        summary: Some("We can't rewind, we've gone too far".to_owned()),
-        preferred_username: profile.preferred_username,
-        icon: Some(icon),
-        inbox,
-        outbox,
-        followers: Some(followers),
-        following: Some(following),
-        public_key,
+    let query = req.uri()
+        .query()
+        .map(serde_html_form::from_str::<PageQuery>)
+        .and_then(|r| r.ok());
+
+    let domain = req.domain.clone();
+    let body = match query {
+        None => json!(req.db.run(move |db| get_network_collection(db, profile_id, &domain, network, is_hidden)).await?),
+        Some(_) if is_hidden => return Err(forbidden()),
+        Some(q) if q.page < 1 => return Err(bad_request("Collection pages start at 1")),
+        Some(q) => json!(req.db.run(move |db| get_network_page(db, profile_id, &domain, network, q.page)).await?),
     };
 
-    let body = json!(actor).to_string();
-    let mut res = send(body);
-    let header_value = HeaderValue::from_static("application/activity+json");
-    res.headers_mut().append(CONTENT_TYPE, header_value);
-    Ok(res)
+    Ok(send_activity_json(body.to_string()))
 }
@@ -0,0 +1,99 @@
+use hyper::Uri;
+use minijinja::context;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::activitypub::delivery::enqueue_for_followers;
+use crate::activitypub::objects::actor::get_local_actor;
+use crate::query_row;
+use crate::server::error::{bad_request, not_found};
+use crate::server::server_request::AuthedRequest;
+use crate::server::server_response::{self, redirect, send, InternalResult, ServerResult};
+
+#[derive(Deserialize)]
+struct ProfileForm {
+    display_name: String,
+    summary: String,
+    avatar_url: String,
+    header_url: String,
+    hide_network: Option<String>,
+}
+
+pub async fn get(req: AuthedRequest<'_>) -> ServerResult {
+    let profile_id = req.get_int_url_param(2, "Missing profile ID")?;
+    let profile = req.db.run(move |db| {
+        let profile = query_row!(
+            db,
+            Profile {
+                profile_id: i64,
+                display_name: String,
+                preferred_username: String,
+                summary: Option<String>,
+                avatar_url: Option<String>,
+                header_url: Option<String>,
+                hide_network: bool
+            },
+            "FROM profiles WHERE profile_id = ?1",
+            [profile_id]
+        );
+        Ok(profile.ok().map(|profile| json!(profile)))
+    }).await?;
+
+    let profile = match profile {
+        Some(x) => x,
+        None => return server_response::not_found(&req)
+    };
+
+    let body = req.render("profiles/_profile_id/edit.html", context! { profile })?;
+    Ok(send(body))
+}
+
+pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
+    let profile_id = req.get_int_url_param(2, "Missing profile ID")?;
+    let req = req.into_text().await?;
+    let form: ProfileForm = req.get_form_data()?;
+
+    let display_name = form.display_name.trim().to_owned();
+    if display_name.is_empty() {
+        return Err(bad_request("Display name is required"));
+    }
+
+    let summary = form.summary.replace("\r\n", "\n").trim().to_owned();
+    let summary = if summary.is_empty() { None } else { Some(summary) };
+    let avatar_url = parse_image_url(&form.avatar_url)?;
+    let header_url = parse_image_url(&form.header_url)?;
+    let hide_network = form.hide_network.is_some();
+
+    let domain = req.domain.clone();
+    req.db.run(move |db| {
+        let updated = db.execute(
+            "UPDATE profiles
+            SET display_name = ?2, summary = ?3, avatar_url = ?4, header_url = ?5, hide_network = ?6
+            WHERE profile_id = ?1",
+            (profile_id, display_name, summary, avatar_url, header_url, hide_network),
+        )?;
+
+        if updated == 0 {
+            return Err(not_found());
+        }
+
+        // Let everyone who follows this profile know that it changed
+        let update_activity = get_local_actor(db, profile_id, &domain)?.into_update();
+        enqueue_for_followers(db, profile_id, &json!(update_activity).to_string())
+    }).await?;
+
+    redirect(&format!("/profiles/{}", profile_id))
+}
+
+fn parse_image_url(url: &str) -> InternalResult<Option<String>> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+
+    let uri: Uri = url.parse().map_err(|_| bad_request("Invalid image URL provided"))?;
+    match uri.scheme_str() {
+        Some("https") | Some("http") => Ok(Some(url.to_owned())),
+        _ => Err(bad_request("Image URLs must start with http:// or https://")),
+    }
+}
@@ -1,17 +1,33 @@
 use minijinja::context;
+use serde_json::json;
 use crate::query_map;
 
+use crate::activitypub::objects::collection::Network;
+use crate::router::Negotiated;
 use crate::server::server_request::AuthedRequest;
-use crate::server::server_response::{send, ServerResult};
+use crate::server::server_response::{send, vary_on_accept, ServerResult};
 
-pub async fn get(req: AuthedRequest<'_>) -> ServerResult {
-    let profile_id = req.get_url_param(2, "Invalid Profile ID")?;
-    let following = query_map!(
-        req.db,
-        Actor { url: String, name: String, preferred_username: String, icon_url: Option<String> },
-        "FROM followers LEFT JOIN known_actors USING (actor_id) WHERE profile_id = ?1",
-        [ profile_id ]
-    );
+use super::serve_network_collection;
+
+pub async fn get(req: Negotiated<'_>) -> ServerResult {
+    let res = match req {
+        Negotiated::ActivityPub(req) => serve_network_collection(req, Network::Followers).await,
+        Negotiated::Html(req) => get_html(req).await,
+    };
+    vary_on_accept(res)
+}
+
+async fn get_html(req: AuthedRequest<'_>) -> ServerResult {
+    let profile_id = req.get_url_param(2, "Invalid Profile ID")?.to_owned();
+    let following = req.db.run(move |db| {
+        let following = query_map!(
+            db,
+            Actor { url: String, name: String, preferred_username: String, icon_url: Option<String> },
+            "FROM followers LEFT JOIN known_actors USING (actor_id) WHERE profile_id = ?1",
+            [ profile_id ]
+        );
+        Ok(json!(following))
+    }).await?;
 
     let context = context! { following };
     let body = req.render("profiles/_profile_id/followers.html", context)?;
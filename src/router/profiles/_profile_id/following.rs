@@ -1,17 +1,54 @@
 use minijinja::context;
-use crate::query_map;
+use serde::Serialize;
 
+use crate::activitypub::objects::collection::Network;
+use crate::router::Negotiated;
 use crate::server::server_request::AuthedRequest;
-use crate::server::server_response::{send, ServerResult};
+use crate::server::server_response::{send, vary_on_accept, ServerResult};
+use crate::templates::_partials::follow_button::{FollowButton, FollowStatus};
 
-pub async fn get(req: AuthedRequest<'_>) -> ServerResult {
-    let profile_id = req.get_url_param(2, "Invalid Profile ID")?;
-    let following = query_map!(
-        req.db,
-        Actor { url: String, name: String, preferred_username: String, icon_url: Option<String> },
-        "FROM following LEFT JOIN known_actors USING (actor_id) WHERE profile_id = ?1",
-        [ profile_id ]
-    );
+use super::serve_network_collection;
+
+#[derive(Serialize)]
+struct FollowedActor {
+    icon_url: Option<String>,
+    follow_button: FollowButton,
+}
+
+pub async fn get(req: Negotiated<'_>) -> ServerResult {
+    let res = match req {
+        Negotiated::ActivityPub(req) => serve_network_collection(req, Network::Following).await,
+        Negotiated::Html(req) => get_html(req).await,
+    };
+    vary_on_accept(res)
+}
+
+async fn get_html(req: AuthedRequest<'_>) -> ServerResult {
+    let profile_id = req.get_url_param(2, "Invalid Profile ID")?.to_owned();
+    let following = req.db.run(move |db| {
+        let mut query = db.prepare(
+            "SELECT actor_id, url, name, preferred_username, inbox, outbox, summary, icon_url, is_accepted
+            FROM following
+            LEFT JOIN known_actors USING (actor_id)
+            WHERE profile_id = ?1",
+        )?;
+        let rows = query.query_map([profile_id], |row| {
+            let is_accepted: bool = row.get(8)?;
+            let follow_button = FollowButton {
+                id: row.get(0)?,
+                url: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                name: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                preferred_username: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                inbox: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                outbox: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                summary: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                status: if is_accepted { FollowStatus::Following } else { FollowStatus::Requested },
+            };
+            Ok(FollowedActor { icon_url: row.get(7)?, follow_button })
+        })?;
+        let following: Vec<FollowedActor> = rows.collect::<Result<_, _>>()?;
+        Ok(following)
+    }).await?;
 
     let context = context! { following };
     let body = req.render("profiles/_profile_id/following.html", context)?;
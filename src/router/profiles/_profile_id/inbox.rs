@@ -1,60 +1,97 @@
+use chrono::{DateTime, Utc};
 use hyper::{StatusCode, Uri};
 use rand::random;
 use serde::Deserialize;
 use serde_json::json;
-use tracing::warn;
 
-use crate::{activitypub::{objects::{outbox::{AcceptActivity, ActivityType, FollowActivity, UndoActivity}, AtContext, Context}, requests::{self, send_as}}, router::debug, server::{error::{bad_gateway, bad_request}, server_request::{AnyRequest, AuthState, CurrentProfile, ServerRequest}, server_response::{send_status, ServerResult}}};
+use crate::{activitypub::{objects::{outbox::{AcceptActivity, ActivityType, CreateActivity, DeleteActivity, FollowActivity, FollowOrLink, Object, TombstoneOrLink, UndoActivity, UpdateActivity}, AtContext, Context}, delivery}, router::debug, server::{error::{bad_gateway, bad_request, unauthorized}, server_request::{AnyRequest, AuthState, CurrentProfile, SignedRequest}, server_response::{send_status, ServerResult}}};
+use crate::activitypub::content::{is_http_url, sanitize_html};
+use crate::activitypub::objects::note::get_local_post_id;
+use crate::activitypub::actor_cache::{get_or_fetch_actor, update_known_actor};
+use crate::activitypub::objects::actor::Actor;
 use crate::queries::get_profile_id_from_url;
+use crate::templates::_partials::post::PostAttachment;
+use crate::server::server_response::InternalResult;
+use rusqlite::{Connection, OptionalExtension};
 
+// Just enough of an activity to decide what to do with it
 #[derive(Deserialize)]
-#[serde(untagged)]
-enum InboxPost {
-    Follow(FollowActivity),
-    Undo(UndoActivity<FollowActivity>)
+struct InboxPost {
+    #[serde(rename = "type")]
+    activity_type: ActivityType,
+    actor: String,
 }
 
 pub async fn post<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
-    let req = req.into_text().await?;
+    let req = req.get_body().await?.verify_signature().await?.into_text()?;
     let body: InboxPost = req.parse_json()?;
-    match body {
-        InboxPost::Follow(activity) => follow(req, activity).await,
-        InboxPost::Undo(activity) => undo_follow(req, activity)
+
+    // A valid signature only means something if it's from the actor the activity claims to be from
+    if body.actor != req.data.actor_id {
+        return Err(unauthorized("Activity actor does not match the signing key"));
+    }
+
+    match body.activity_type {
+        ActivityType::Follow => {
+            let activity = req.parse_json()?;
+            follow(req, activity).await
+        }
+        ActivityType::Accept => {
+            let activity = req.parse_json()?;
+            accept_follow(req, activity).await
+        }
+        ActivityType::Reject => {
+            let activity = req.parse_json()?;
+            reject_follow(req, activity).await
+        }
+        ActivityType::Undo => {
+            let activity: UndoActivity<FollowActivity> = req.parse_json()?;
+            match activity.object.activity_type {
+                ActivityType::Follow => undo_follow(req, activity).await,
+                _ => ignore(req),
+            }
+        }
+        ActivityType::Create => {
+            let activity = req.parse_json()?;
+            create(req, activity).await
+        }
+        ActivityType::Delete => {
+            let activity = req.parse_json()?;
+            delete(req, activity).await
+        }
+        ActivityType::Update => {
+            let activity: UpdateActivity<serde_json::Value> = req.parse_json()?;
+            update(req, activity).await
+        }
+        _ => ignore(req),
     }
 }
 
-async fn follow<Au: AuthState>(req: ServerRequest<'_, String, Au>, follow_activity: FollowActivity) -> ServerResult {
+fn ignore(req: SignedRequest<'_>) -> ServerResult {
+    debug!("Ignoring unsupported activity from {}", req.data.actor_id);
+    send_status(StatusCode::ACCEPTED)
+}
+
+async fn follow(req: SignedRequest<'_>, follow_activity: FollowActivity) -> ServerResult {
     let actor_uri: Uri = follow_activity.actor.parse()
         .map_err(|_| bad_request("Invalid actor URI provided"))?;
-    let profile_id = get_profile_id_from_url(&req.db, &follow_activity.object)?;
-
-    let profile = CurrentProfile::new(&req.db, profile_id, &req.domain).ok_or_else(|| {
-        bad_request(&format!("Feed {} not found", profile_id))
-    })?;
+    let (object, domain) = (follow_activity.object.clone(), req.domain.clone());
+    let (profile_id, profile) = req.db.run(move |db| {
+        let profile_id = get_profile_id_from_url(db, &object)?;
+        Ok((profile_id, CurrentProfile::new(db, profile_id, &domain)))
+    }).await?;
+    let profile = profile.ok_or_else(|| bad_request(&format!("Feed {} not found", profile_id)))?;
 
+    let actor = get_or_fetch_actor(&req.db, &actor_uri, &profile).await?;
 
-    let actor = requests::get_actor(&actor_uri, &profile).await?;
-
-    let inbox_uri = actor.inbox.parse::<Uri>()
+    // Make sure the inbox is valid before queuing anything to it
+    actor.inbox.parse::<Uri>()
         .map_err(|_| {
             let message = format!("{} is not a valid inbox URI", actor.inbox);
             bad_gateway(&message)
         })?;
 
-    let icon_url = actor.icon.map(|i| { i.url });
-    req.db.execute(
-        "INSERT OR REPLACE INTO known_actors
-            (actor_id, name, preferred_username, url, inbox, outbox, icon_url)
-        VALUES
-            (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-        (actor.id, &actor.name, &actor.preferred_username, &actor.url, &actor.inbox, &actor.outbox,
-         icon_url),
-    )?;
-
-    req.db.execute(
-        "INSERT OR REPLACE INTO followers (profile_id, actor_id) VALUES (?1, ?2)",
-        (profile_id, &follow_activity.actor))?;
-
+    let follower_id = follow_activity.actor.clone();
     let accept = AcceptActivity {
         context: AtContext::Context(Context::ActivityStreams),
         activity_type: ActivityType::Accept,
@@ -64,30 +101,221 @@ async fn follow<Au: AuthState>(req: ServerRequest<'_, String, Au>, follow_activi
     };
 
     let accept_body = json!(accept).to_string();
+    req.db.run(move |db| {
+        db.execute(
+            "INSERT OR REPLACE INTO followers (profile_id, actor_id) VALUES (?1, ?2)",
+            (profile_id, &follower_id))?;
+        delivery::enqueue(db, profile_id, &actor.inbox, &accept_body)
+    }).await?;
 
-    tokio::spawn(async move {
-        let res = send_as(&inbox_uri, &profile, accept_body).await;
-        let res = match res {
-            Ok(r) => r,
-            Err(e) => { return warn!("Failed to send confirmation to {}: {:?}", actor_uri, e) }
-        };
+    send_status(StatusCode::OK)
+}
 
-        let code = res.status();
-        let res_body = res.text().await;
-        match res_body {
-            Ok(body) => debug!("Received {} {}", code, body),
-            Err(e) => warn!("Unable to reach {}: {:?}", actor_uri, e)
-        };
+async fn accept_follow(req: SignedRequest<'_>, accept_activity: AcceptActivity<FollowOrLink>) -> ServerResult {
+    req.db.run(move |db| {
+        let follow_id = accept_activity.object.id();
+        let updated = db.execute(
+            "UPDATE following SET is_accepted = TRUE WHERE actor_id = ?1 AND follow_activity_id = ?2",
+            (&accept_activity.actor, follow_id))?;
+
+        if updated == 0 {
+            debug!("Received Accept from {} for unknown follow {}", accept_activity.actor, follow_id);
+        }
+        Ok(())
+    }).await?;
+
+    send_status(StatusCode::OK)
+}
 
-    });
+async fn reject_follow(req: SignedRequest<'_>, reject_activity: AcceptActivity<FollowOrLink>) -> ServerResult {
+    // This also covers the remote actor removing us as a follower after accepting
+    req.db.run(move |db| {
+        db.execute(
+            "DELETE FROM following WHERE actor_id = ?1 AND follow_activity_id = ?2",
+            (&reject_activity.actor, reject_activity.object.id()))?;
+        Ok(())
+    }).await?;
 
     send_status(StatusCode::OK)
 }
 
-fn undo_follow<Au: AuthState>(req: ServerRequest<'_, String, Au>, undo_activity: UndoActivity<FollowActivity>) -> ServerResult {
-    let profile_id = get_profile_id_from_url(&req.db, &undo_activity.object.object)?;
-    req.db.execute("DELETE FROM followers WHERE profile_id = ?1 AND actor_id = ?2",
+async fn undo_follow(req: SignedRequest<'_>, undo_activity: UndoActivity<FollowActivity>) -> ServerResult {
+    req.db.run(move |db| {
+        let profile_id = get_profile_id_from_url(db, &undo_activity.object.object)?;
+        db.execute("DELETE FROM followers WHERE profile_id = ?1 AND actor_id = ?2",
                    (profile_id, &undo_activity.actor))?;
+        Ok(())
+    }).await?;
 
     send_status(StatusCode::OK)
 }
+
+async fn update(req: SignedRequest<'_>, update_activity: UpdateActivity<serde_json::Value>) -> ServerResult {
+    let status = req.db.run(move |db| update_actor(db, update_activity)).await?;
+    send_status(status)
+}
+
+// Only profile updates are handled; anything else (e.g. an edited Note) is ignored
+fn update_actor(db: &Connection, update_activity: UpdateActivity<serde_json::Value>) -> InternalResult<StatusCode> {
+    let Ok(actor) = serde_json::from_value::<Actor>(update_activity.object) else {
+        debug!("Ignoring Update {} of an unsupported object", update_activity.id);
+        return Ok(StatusCode::ACCEPTED)
+    };
+
+    if actor.id != update_activity.actor {
+        return Err(unauthorized("Actors can only update themselves"));
+    }
+
+    // There's no reason to start caching someone just because they sent us their profile
+    let is_known: bool = db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM known_actors WHERE actor_id = ?1)",
+        [&actor.id],
+        |row| row.get(0))?;
+    if is_known {
+        update_known_actor(db, &actor)?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+async fn create(req: SignedRequest<'_>, create_activity: CreateActivity) -> ServerResult {
+    let note = match create_activity.object {
+        Object::Note(note) => note,
+        Object::Unknown(_) => {
+            debug!("Ignoring Create {} of an unsupported object", create_activity.id);
+            return send_status(StatusCode::ACCEPTED)
+        }
+    };
+
+    if note.attributed_to != create_activity.actor {
+        return Err(unauthorized("Note is not attributed to the activity's actor"));
+    }
+
+    // These all end up as links, so anything like javascript: is kept out of the database
+    if !is_http_url(&note.id) || !is_http_url(&note.url) {
+        return Err(bad_request("Note id and url must be http(s) URLs"));
+    }
+    let in_reply_to = note.in_reply_to.filter(|id| is_http_url(id));
+
+    let actor_id = create_activity.actor.clone();
+    let replied_to_post = in_reply_to.as_ref().and_then(|id| get_local_post_id(id, &req.domain));
+    let (is_followed, replied_to_profile, is_known) = req.db.run(move |db| {
+        let is_followed: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM following WHERE actor_id = ?1 AND is_accepted)",
+            [&actor_id],
+            |row| row.get(0))?;
+
+        // Anyone can reply to our posts, whether we follow them or not
+        let replied_to_profile: Option<i64> = match replied_to_post {
+            Some(post_id) => db.query_row(
+                "SELECT profile_id FROM posts WHERE post_id = ?1",
+                [post_id],
+                |row| row.get(0))
+                .optional()?,
+            None => None,
+        };
+
+        let is_known: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM known_actors WHERE actor_id = ?1)",
+            [&actor_id],
+            |row| row.get(0))?;
+
+        Ok((is_followed, replied_to_profile, is_known))
+    }).await?;
+
+    if !is_followed && replied_to_profile.is_none() {
+        debug!("Ignoring note {} from unfollowed actor {}", note.id, create_activity.actor);
+        return send_status(StatusCode::ACCEPTED)
+    }
+
+    // Anyone we follow is already known, so this is someone new replying to us
+    if let Some(profile_id) = replied_to_profile.filter(|_| !is_known) {
+        let actor_uri: Uri = create_activity.actor.parse()
+            .map_err(|_| bad_request("Invalid actor URI provided"))?;
+        let domain = req.domain.clone();
+        let profile = req.db.run(move |db| Ok(CurrentProfile::new(db, profile_id, &domain))).await?
+            .ok_or_else(|| bad_request(&format!("Feed {} not found", profile_id)))?;
+        get_or_fetch_actor(&req.db, &actor_uri, &profile).await?;
+    }
+
+    // Store everything in the same format as local posts, so that the timeline sorts correctly
+    let published = note.published
+        .and_then(|p| DateTime::parse_from_rfc3339(&p).ok())
+        .map(|p| p.with_timezone(&Utc).format("%FT%TZ").to_string());
+
+    // Attachments are kept in the same shape that the templates use
+    let attachments = json!(PostAttachment::from_remote(note.attachment)).to_string();
+
+    // Remote content is shown as-is, so it's cleaned up once on the way in
+    let content = sanitize_html(&note.content);
+
+    req.db.run(move |db| {
+        db.execute(
+            "INSERT OR IGNORE INTO remote_posts (object_id, actor_id, url, content, in_reply_to, attachments, published)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, COALESCE(?7, strftime('%FT%TZ', CURRENT_TIMESTAMP)))",
+            (&note.id, &create_activity.actor, &note.url, &content, &in_reply_to, attachments, published))?;
+        Ok(())
+    }).await?;
+
+    send_status(StatusCode::OK)
+}
+
+async fn delete(req: SignedRequest<'_>, delete_activity: DeleteActivity<TombstoneOrLink>) -> ServerResult {
+    // Matching on the actor means that nobody can delete posts that aren't theirs
+    req.db.run(move |db| {
+        db.execute(
+            "DELETE FROM remote_posts WHERE object_id = ?1 AND actor_id = ?2",
+            (delete_activity.object.id(), &delete_activity.actor))?;
+        Ok(())
+    }).await?;
+
+    send_status(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::open_test_db;
+
+    #[test]
+    fn sanitizes_updated_summaries() {
+        let db = open_test_db();
+        let actor_id = "https://other.example/users/alex";
+        db.execute(
+            "INSERT INTO known_actors (actor_id, name, preferred_username, host) VALUES (?1, 'Alex', 'alex', 'other.example')",
+            [actor_id],
+        ).unwrap();
+
+        let update = serde_json::from_value(json!({
+            "id": format!("{}#updates/1", actor_id),
+            "type": "Update",
+            "actor": actor_id,
+            "object": {
+                "@context": ["https://www.w3.org/ns/activitystreams"],
+                "id": actor_id,
+                "url": actor_id,
+                "summary": "<p>Hi</p><script>alert(1)</script><img src=x onerror=alert(1)>",
+                "name": "Alex",
+                "type": "Person",
+                "preferredUsername": "alex",
+                "inbox": format!("{}/inbox", actor_id),
+                "outbox": format!("{}/outbox", actor_id),
+                "publicKey": {
+                    "id": format!("{}#main-key", actor_id),
+                    "owner": actor_id,
+                    "publicKeyPem": "",
+                },
+            },
+        })).unwrap();
+        assert_eq!(update_actor(&db, update).unwrap(), StatusCode::OK);
+
+        let (summary, actor_json): (String, String) = db.query_row(
+            "SELECT summary, actor_json FROM known_actors WHERE actor_id = ?1",
+            [actor_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!(summary, "<p>Hi</p>");
+        let cached: Actor = serde_json::from_str(&actor_json).unwrap();
+        assert_eq!(cached.summary.as_deref(), Some("<p>Hi</p>"));
+    }
+}
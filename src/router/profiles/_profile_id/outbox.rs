@@ -1,12 +1,12 @@
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::{activitypub::objects::outbox::{get_outbox, get_outbox_page}, server::{server_request::{AnyRequest, AuthState}, server_response::{send, ServerResult}}};
+use crate::{activitypub::objects::outbox::{get_outbox, get_outbox_page}, server::{error::bad_request, server_request::{AnyRequest, AuthState}, server_response::{send_activity_json, ServerResult}}};
 
 
 #[derive(Debug, Deserialize)]
 struct Query {
-    page: usize
+    page: i64
 }
 
 pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
@@ -19,13 +19,18 @@ pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
 
     if let Some(q) = query {
         let page_num = q.page;
-        let outbox_page = get_outbox_page(&req.db, profile_id, &req.domain, page_num)?;
+        if page_num < 1 {
+            return Err(bad_request("Outbox pages start at 1"));
+        }
+        let domain = req.domain.clone();
+        let outbox_page = req.db.run(move |db| get_outbox_page(db, profile_id, &domain, page_num)).await?;
         let body = json!(outbox_page).to_string();
-        Ok(send(body))
+        Ok(send_activity_json(body))
     } else {
-        let outbox = get_outbox(&req.db, profile_id, &req.domain)?;
+        let domain = req.domain.clone();
+        let outbox = req.db.run(move |db| get_outbox(db, profile_id, &domain)).await?;
         let body = json!(outbox).to_string();
-        Ok(send(body))
+        Ok(send_activity_json(body))
     }
 
 }
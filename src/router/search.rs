@@ -1,4 +1,4 @@
-use crate::activitypub::get_full_handle;
+use crate::activitypub::{actor_cache, get_full_handle};
 use minijinja::context;
 use serde::Deserialize;
 
@@ -6,6 +6,7 @@ use crate::queries;
 use crate::server::server_request::AuthedRequest;
 use crate::server::server_response::send;
 use crate::server::server_response::ServerResult;
+use crate::templates::_partials::follow_button::FollowButton;
 
 #[derive(Deserialize)]
 struct Query {
@@ -22,7 +23,7 @@ pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
     let query: Query = req.get_form_data()?;
     let handle = get_full_handle(&query.q)?;
 
-    let actor = queries::get_or_search_for_actor(&handle, &req.data.current_profile).await?;
+    let actor = actor_cache::get_or_search_for_actor(&req.db, &handle, &req.data.current_profile).await?;
     let actor = match actor {
         None => return Ok(send("No account found")),
         Some(actor) => actor,
@@ -35,8 +36,13 @@ pub async fn post(req: AuthedRequest<'_>) -> ServerResult {
         .unwrap_or("".to_owned());
     let local_url = handle.get_local_url();
 
+    let profile_id = req.data.current_profile.profile_id;
+    let actor_id = actor.id.clone();
+    let follow_status = req.db.run(move |db| queries::get_follow_status(db, profile_id, &actor_id)).await?;
+    let follow_button = FollowButton::new(&actor, follow_status);
+
     let actor = context! { local_url, icon_url, ..actor };
-    let context = context! { actor };
+    let context = context! { actor, follow_button };
 
     let body = req.render("_partials/feed-search-result.html", context)?;
     Ok(send(body))
@@ -1,21 +1,13 @@
-use crate::server::server_request::{AnyRequest, AuthState};
+use hyper::StatusCode;
+
+use crate::server::context::GlobalContext;
 use crate::server::server_response;
 use crate::server::server_response::ServerResult;
 
-pub async fn get<Au: AuthState>(req: AnyRequest<'_, Au>) -> ServerResult {
-    let path = req.uri().path();
-    if path.len() <= 8 {
-        return server_response::not_found(&req);
-    }
-    let file = &path[8..];
-    serve(&req, file)
-}
-
-pub fn serve<Au: AuthState>(req: &AnyRequest<'_, Au>, file: &str) -> ServerResult {
-    let statics = &req.global.statics;
-    let contents = statics.get(file);
-    match contents {
+// Served before there's a database connection or a typed request, so misses get a bare 404
+pub fn get(g_ctx: &GlobalContext, file: &str) -> ServerResult {
+    match g_ctx.statics.get(file) {
         Some(body) => Ok(server_response::send(body.clone())),
-        None => server_response::not_found(req),
+        None => server_response::send_status(StatusCode::NOT_FOUND),
     }
 }
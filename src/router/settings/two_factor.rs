@@ -0,0 +1,98 @@
+use hyper::StatusCode;
+use minijinja::{context, Value};
+use serde::Deserialize;
+
+use crate::server::credentials::{
+    check_password, confirm_two_factor_enrollment, disable_two_factor, get_pending_two_factor_secret,
+    get_unused_recovery_code_count, has_two_factor, start_two_factor_enrollment, CredentialCheck,
+};
+use crate::server::error::bad_request;
+use crate::server::server_request::{AuthState, ServerRequest, SetupRequest};
+use crate::server::server_response::{send, ServerResult};
+use crate::server::totp::{get_otpauth_uri, get_qr_code_svg};
+
+#[derive(Deserialize)]
+struct CodeForm {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    password: String,
+}
+
+pub async fn get(req: SetupRequest<'_>) -> ServerResult {
+    render_status(&req, None, StatusCode::OK).await
+}
+
+/// Start enrolling a new authenticator, showing the secret to scan
+pub async fn post(req: SetupRequest<'_>) -> ServerResult {
+    let secret = req.db.run(|db| {
+        if has_two_factor(db)? {
+            return Err(bad_request("Two-factor authentication is already on"));
+        }
+        start_two_factor_enrollment(db)
+    }).await?;
+    render_enrollment(&req, &secret, None, StatusCode::OK)
+}
+
+pub async fn post_confirm(req: SetupRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: CodeForm = req.get_form_data()?;
+
+    match req.db.run(move |db| confirm_two_factor_enrollment(db, &form.code)).await? {
+        Some(recovery_codes) => {
+            let context = context! { is_enabled => true, recovery_codes };
+            render(&req, context, StatusCode::OK)
+        }
+        None => {
+            let secret = req.db.run(get_pending_two_factor_secret).await?
+                .ok_or_else(|| bad_request("Two-factor enrollment has not been started"))?;
+            render_enrollment(&req, &secret, Some("Incorrect code, please try again"), StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+// Turning it off needs the password, so a session left open somewhere isn't enough
+pub async fn post_disable(req: SetupRequest<'_>) -> ServerResult {
+    let req = req.into_text().await?;
+    let form: PasswordForm = req.get_form_data()?;
+
+    match req.db.run(move |db| check_password(db, &form.password)).await? {
+        CredentialCheck::Correct => {
+            req.db.run(disable_two_factor).await?;
+            render_status(&req, None, StatusCode::OK).await
+        }
+        CredentialCheck::Incorrect => render_status(&req, Some("Incorrect password"), StatusCode::UNAUTHORIZED).await,
+        CredentialCheck::LockedOut => {
+            let message = "Too many failed attempts, please try again later";
+            render_status(&req, Some(message), StatusCode::TOO_MANY_REQUESTS).await
+        }
+    }
+}
+
+async fn render_status<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>, error: Option<&str>, status: StatusCode) -> ServerResult {
+    let (is_enabled, recovery_code_count) = req.db.run(|db| {
+        Ok((has_two_factor(db)?, get_unused_recovery_code_count(db)?))
+    }).await?;
+    render(req, context! { is_enabled, recovery_code_count, error }, status)
+}
+
+fn render_enrollment<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    secret: &str,
+    error: Option<&str>,
+    status: StatusCode,
+) -> ServerResult {
+    let otpauth_uri = get_otpauth_uri(secret, &req.domain);
+    let qr_code = get_qr_code_svg(&otpauth_uri)?;
+    let context = context! { is_enrolling => true, secret, otpauth_uri, qr_code, error };
+    render(req, context, status)
+}
+
+fn render<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>, context: Value, status: StatusCode) -> ServerResult {
+    let body = req.render("settings/two_factor.html", context)?;
+    let mut res = send(body);
+    *res.status_mut() = status;
+    Ok(res)
+}
@@ -0,0 +1,142 @@
+use hyper::header::HOST;
+use hyper::StatusCode;
+use minijinja::context;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::activitypub::requests::get_webfinger;
+use crate::queries::create_profile;
+use crate::router::login::start_session;
+use crate::server::credentials::{set_password, validate_new_password, MIN_PASSWORD_LENGTH};
+use crate::server::error::bad_request;
+use crate::server::server_request::{AuthState, PlainRequest, ServerRequest};
+use crate::server::server_response::{self, send, InternalResult, ServerResult};
+use crate::server::setup::{
+    clear_domain_challenge, create_domain_challenge, is_setup_complete, is_setup_token,
+    mark_setup_complete, normalize_domain, save_domain,
+};
+
+#[derive(Deserialize)]
+struct SetupForm {
+    setup_token: String,
+    domain: String,
+    password: String,
+    confirm_password: String,
+    // Only asked for when there isn't a profile yet
+    preferred_username: Option<String>,
+    display_name: Option<String>,
+    nickname: Option<String>,
+}
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    // Once it's done, the wizard is gone for good; everything after goes through the settings pages
+    if req.db.run(is_setup_complete).await? {
+        return server_response::not_found(&req);
+    }
+
+    let domain = get_default_domain(&req);
+    render_setup(&req, &domain, None, StatusCode::OK).await
+}
+
+pub async fn post(req: PlainRequest<'_>) -> ServerResult {
+    if req.db.run(is_setup_complete).await? {
+        return server_response::not_found(&req);
+    }
+    let req = req.into_text().await?;
+
+    let form: SetupForm = req.get_form_data()?;
+    if !is_setup_token(req.global.get_setup_token(), &form.setup_token) {
+        let message = "The setup token doesn't match; copy it from the server's log";
+        return render_setup(&req, &form.domain, Some(message), StatusCode::FORBIDDEN).await;
+    }
+
+    let domain = match normalize_domain(&form.domain) {
+        Ok(domain) => domain,
+        Err(e) => return render_setup(&req, &form.domain, Some(&e.message), StatusCode::BAD_REQUEST).await,
+    };
+
+    if let Err(e) = validate_new_password(&form.password, &form.confirm_password) {
+        return render_setup(&req, &domain, Some(&e.message), StatusCode::BAD_REQUEST).await;
+    }
+
+    let new_profile = match (&form.preferred_username, &form.display_name, &form.nickname) {
+        _ if has_profile(&req).await? => None,
+        (Some(username), Some(display_name), Some(nickname)) if !username.trim().is_empty() => {
+            Some((username.trim().to_owned(), display_name.trim().to_owned(), nickname.trim().to_owned()))
+        }
+        _ => {
+            let message = "Choose a handle for your first profile";
+            return render_setup(&req, &domain, Some(message), StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    let challenge = req.db.run(create_domain_challenge).await?;
+    if let Err(e) = check_domain(&domain, &challenge).await {
+        warn!("Domain check for {} failed: {}", domain, e);
+        let message = format!(
+            "Couldn't reach this server at https://{}; check that the domain points here and serves HTTPS",
+            domain
+        );
+        return render_setup(&req, &domain, Some(&message), StatusCode::BAD_REQUEST).await;
+    }
+
+    // Everything is saved together, so a failure part way through leaves the wizard open
+    let (saved_domain, password) = (domain.clone(), form.password.clone());
+    req.db.run(move |db| {
+        let tx = db.unchecked_transaction()?;
+        save_domain(&tx, &saved_domain)?;
+        set_password(&tx, &password)?;
+        if let Some((username, display_name, nickname)) = new_profile {
+            create_profile(&tx, &username, &display_name, &nickname)?;
+        }
+        clear_domain_challenge(&tx)?;
+        mark_setup_complete(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }).await?;
+
+    req.global.set_saved_domain(domain);
+    req.global.mark_setup_complete();
+
+    start_session(&req, false).await
+}
+
+// Fetching our own webfinger through the domain proves it points at this server
+async fn check_domain(domain: &str, challenge: &str) -> InternalResult<()> {
+    let web_finger = get_webfinger(domain, challenge).await?;
+    let expected_subject = format!("acct:{}@{}", challenge, domain);
+    if web_finger.subject.as_deref() != Some(expected_subject.as_str()) {
+        return Err(bad_request("Webfinger response did not match the setup challenge"));
+    }
+    Ok(())
+}
+
+async fn has_profile<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>) -> InternalResult<bool> {
+    req.db.run(|db| {
+        let has_profile = db.query_row("SELECT EXISTS (SELECT 1 FROM profiles)", (), |row| row.get(0))?;
+        Ok(has_profile)
+    }).await
+}
+
+// Start with our best guess at where we're being served from
+fn get_default_domain<T, Au: AuthState>(req: &ServerRequest<'_, T, Au>) -> String {
+    let host = req.headers().get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or(req.uri().authority().map(|a| a.as_str()))
+        .unwrap_or_default();
+    req.global.get_domain().unwrap_or(host).to_owned()
+}
+
+async fn render_setup<T, Au: AuthState>(
+    req: &ServerRequest<'_, T, Au>,
+    domain: &str,
+    error: Option<&str>,
+    status: StatusCode,
+) -> ServerResult {
+    let needs_profile = !has_profile(req).await?;
+    let context = context! { domain, error, needs_profile, min_length => MIN_PASSWORD_LENGTH };
+    let body = req.render("setup.html", context)?;
+    let mut res = send(body);
+    *res.status_mut() = status;
+    Ok(res)
+}
@@ -0,0 +1,14 @@
+use minijinja::context;
+
+use crate::queries::get_posts_with_hashtag;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response::{send, ServerResult};
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let name = req.get_url_param(2, "Missing tag name")?;
+    let (tag_name, domain) = (name.to_owned(), req.domain.clone());
+    let posts = req.db.run(move |db| get_posts_with_hashtag(db, &tag_name, &domain)).await?;
+
+    let body = req.render("tags/_tag_name.html", context! { name, posts })?;
+    Ok(send(body))
+}
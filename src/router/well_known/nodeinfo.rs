@@ -0,0 +1,13 @@
+use crate::activitypub::objects::nodeinfo::get_discovery;
+use crate::server::server_request::PlainRequest;
+use crate::server::server_response;
+use crate::server::server_response::ServerResult;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use serde_json::json;
+
+pub async fn get(req: PlainRequest<'_>) -> ServerResult {
+    let body = json!(get_discovery(&req.domain)).to_string();
+    let mut res = server_response::send(body);
+    res.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
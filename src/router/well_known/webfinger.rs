@@ -9,6 +9,7 @@ use crate::server::error::not_found;
 use crate::server::server_request::PlainRequest;
 use crate::server::server_response;
 use crate::server::server_response::ServerResult;
+use crate::server::setup::is_domain_challenge;
 use rusqlite::Error::QueryReturnedNoRows;
 use rusqlite::Error::SqliteFailure;
 use serde::Deserialize;
@@ -47,16 +48,32 @@ pub async fn get(req: PlainRequest<'_>) -> ServerResult {
         .split_once('@')
         .ok_or_else(|| bad_request("Invalid handle resource provided"))?;
 
+    // The setup wizard looks up a one-off account to check that the domain points here
+    let account_name = handle.to_owned();
+    if !req.global.is_setup_complete() && req.db.run(move |db| is_domain_challenge(db, &account_name)).await? {
+        let challenge = WebFinger {
+            subject: Some(format!("acct:{}@{}", handle, domain)),
+            aliases: None,
+            properties: None,
+            links: None,
+        };
+        return Ok(server_response::send(json!(challenge).to_string()));
+    }
+
     debug!("Searching for user {} {}", handle, domain);
 
-    let profile = query_row!(
-        req.db,
-        Profile { profile_id: i64 },
-        "FROM profiles where preferred_username = ?1",
-        [handle]
-        );
+    let preferred_username = handle.to_owned();
+    let profile_id = req.db.run(move |db| {
+        let profile = query_row!(
+            db,
+            Profile { profile_id: i64 },
+            "FROM profiles where preferred_username = ?1",
+            [preferred_username]
+            );
+        Ok(profile.map(|p| p.profile_id))
+    }).await?;
 
-    let profile = match profile {
+    let profile_id = match profile_id {
         Ok(x) => Ok(x),
         Err(QueryReturnedNoRows) => Err(not_found()),
         Err(SqliteFailure(_, Some(m))) => Err(bad_gateway(&m)),
@@ -69,7 +86,7 @@ pub async fn get(req: PlainRequest<'_>) -> ServerResult {
         link_type: Some(LinkType::ActivityJson),
         href: Some(format!(
             "https://{}/profiles/{}",
-            domain, profile.profile_id
+            domain, profile_id
         )),
     };
 
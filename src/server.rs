@@ -1,5 +1,12 @@
+pub mod accept;
 pub mod context;
+pub mod credentials;
 pub mod error;
+pub mod multipart;
+pub mod oauth;
 pub mod server_request;
 pub mod server_response;
+pub mod sessions;
+pub mod setup;
+pub mod totp;
 pub mod utils;
@@ -0,0 +1,129 @@
+// Just enough of RFC 9110's Accept header to decide between ActivityPub JSON and HTML
+// https://www.rfc-editor.org/rfc/rfc9110#name-accept
+
+pub static ACTIVITY_JSON: &str = "application/activity+json";
+pub static ACTIVITYSTREAMS_PROFILE: &str = "https://www.w3.org/ns/activitystreams";
+
+#[derive(Debug, PartialEq)]
+struct MediaRange {
+    media_type: String,
+    subtype: String,
+    profile: Option<String>,
+    q: f32,
+}
+
+// A representation we're able to serve, which ranges are matched against
+struct Representation {
+    media_type: &'static str,
+    subtype: &'static str,
+    profile: Option<&'static str>,
+}
+
+static ACTIVITYPUB: [Representation; 2] = [
+    Representation { media_type: "application", subtype: "activity+json", profile: None },
+    Representation { media_type: "application", subtype: "ld+json", profile: Some(ACTIVITYSTREAMS_PROFILE) },
+];
+
+static HTML: Representation = Representation { media_type: "text", subtype: "html", profile: None };
+
+/// Whether the client would rather have ActivityPub JSON than HTML; ties go to HTML
+pub fn prefers_activitypub(accept: &str) -> bool {
+    let ranges = parse_accept(accept);
+    let ap_quality = ACTIVITYPUB.iter()
+        .map(|r| get_quality(&ranges, r))
+        .fold(0.0, f32::max);
+
+    ap_quality > 0.0 && ap_quality > get_quality(&ranges, &HTML)
+}
+
+fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept.split(',').filter_map(parse_media_range).collect()
+}
+
+fn parse_media_range(range: &str) -> Option<MediaRange> {
+    let mut parts = range.split(';');
+    let (media_type, subtype) = parts.next()?.trim().split_once('/')?;
+    if media_type.is_empty() || subtype.is_empty() {
+        return None;
+    }
+
+    let mut profile = None;
+    let mut q = 1.0;
+    for param in parts {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "q" => q = value.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?,
+            "profile" => profile = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    Some(MediaRange {
+        media_type: media_type.to_ascii_lowercase(),
+        subtype: subtype.to_ascii_lowercase(),
+        profile,
+        q,
+    })
+}
+
+// The most specific range that matches decides the quality, and anything unmatched is 0
+fn get_quality(ranges: &[MediaRange], representation: &Representation) -> f32 {
+    ranges.iter()
+        .filter_map(|range| get_specificity(range, representation).map(|s| (s, range.q)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, q)| q)
+        .unwrap_or(0.0)
+}
+
+fn get_specificity(range: &MediaRange, representation: &Representation) -> Option<u8> {
+    match (range.media_type.as_str(), range.subtype.as_str()) {
+        ("*", "*") => Some(0),
+        (t, "*") if t == representation.media_type => Some(1),
+        (t, s) if t == representation.media_type && s == representation.subtype => {
+            match (&range.profile, representation.profile) {
+                (None, _) => Some(2),
+                // The profile parameter is a space-separated list of URIs
+                (Some(profile), Some(expected)) if profile.split_whitespace().any(|p| p == expected) => Some(3),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activitypub_accept_headers() {
+        assert!(prefers_activitypub("application/activity+json"));
+        assert!(prefers_activitypub("application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\""));
+        assert!(prefers_activitypub("application/activity+json, application/ld+json"));
+        assert!(prefers_activitypub("text/html;q=0.5, application/activity+json"));
+    }
+
+    #[test]
+    fn html_accept_headers() {
+        assert!(!prefers_activitypub(""));
+        assert!(!prefers_activitypub("*/*"));
+        assert!(!prefers_activitypub("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!prefers_activitypub("application/activity+json;q=0.5, text/html"));
+        assert!(!prefers_activitypub("application/activity+json;q=0"));
+        assert!(!prefers_activitypub("application/ld+json; profile=\"https://example.com/other\""));
+    }
+
+    #[test]
+    fn parses_quoted_params() {
+        let range = parse_media_range(" Application/LD+JSON; q=0.7; profile=\"a b\"").unwrap();
+        assert_eq!(range, MediaRange {
+            media_type: "application".to_owned(),
+            subtype: "ld+json".to_owned(),
+            profile: Some("a b".to_owned()),
+            q: 0.7,
+        });
+    }
+}
@@ -1,20 +1,34 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use minijinja::Environment;
 
-#[derive(Clone)]
+use crate::config::Config;
+use crate::sqlite::pool::Pool;
+
 pub struct GlobalContext<'a> {
     pub env: Arc<Environment<'a>>,
     pub statics: Arc<HashMap<String, Vec<u8>>>,
     pub startup_time: u128,
-    pub domain: Option<String>,
+    pub config: Config,
+    pub pool: Pool,
+    // Both of these are set once by the setup wizard, so they're cached rather than queried per request
+    saved_domain: OnceLock<String>,
+    setup_complete: AtomicBool,
+    // Made at startup when setup isn't finished, and only ever written to the log
+    setup_token: OnceLock<String>,
 }
 
 impl<'a> GlobalContext<'a> {
-    pub fn new(env: Arc<Environment<'a>>, statics: Arc<HashMap<String, Vec<u8>>>) -> GlobalContext {
+    pub fn new(
+        env: Arc<Environment<'a>>,
+        statics: Arc<HashMap<String, Vec<u8>>>,
+        config: Config,
+        pool: Pool,
+    ) -> GlobalContext<'a> {
         let startup_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -23,7 +37,37 @@ impl<'a> GlobalContext<'a> {
             env,
             statics,
             startup_time,
-            domain: None,
+            config,
+            pool,
+            saved_domain: OnceLock::new(),
+            setup_complete: AtomicBool::new(false),
+            setup_token: OnceLock::new(),
         }
     }
+
+    /// The domain from the config if there is one, or else the one chosen during setup
+    pub fn get_domain(&self) -> Option<&str> {
+        self.config.domain.as_deref().or(self.saved_domain.get().map(String::as_str))
+    }
+
+    pub fn set_saved_domain(&self, domain: String) {
+        // The domain can't be changed once it's been chosen, so there's only ever one to set
+        let _ = self.saved_domain.set(domain);
+    }
+
+    pub fn is_setup_complete(&self) -> bool {
+        self.setup_complete.load(Ordering::Acquire)
+    }
+
+    pub fn mark_setup_complete(&self) {
+        self.setup_complete.store(true, Ordering::Release);
+    }
+
+    pub fn set_setup_token(&self, token: String) {
+        let _ = self.setup_token.set(token);
+    }
+
+    pub fn get_setup_token(&self) -> Option<&str> {
+        self.setup_token.get().map(String::as_str)
+    }
 }
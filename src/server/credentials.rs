@@ -0,0 +1,309 @@
+use openssl::{base64, memcmp, pkcs5::scrypt, rand::rand_bytes, sha::sha256};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::server::error::bad_request;
+use crate::server::totp;
+use crate::server::server_response::InternalResult;
+
+// https://words.filippo.io/the-scrypt-parameters/
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 1;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_FAILED_ATTEMPTS: i64 = 5;
+const LOCKOUT_SECS: i64 = 60 * 15;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 12;
+// No 0/o or 1/l, since these get written down
+const RECOVERY_CODE_ALPHABET: &[u8] = b"23456789abcdefghijkmnpqrstuvwxyz";
+
+pub enum CredentialCheck {
+    Correct,
+    Incorrect,
+    LockedOut,
+}
+
+/// Hash a password into a self-describing string, so the parameters can change later
+pub fn hash_password(password: &str) -> InternalResult<String> {
+    let mut salt = [0; SALT_LENGTH];
+    rand_bytes(&mut salt)?;
+    let hash = derive_key(password, &salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+    Ok(format!(
+        "$scrypt$ln={},r={},p={}${}${}",
+        SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P,
+        base64::encode_block(&salt),
+        base64::encode_block(&hash),
+    ))
+}
+
+pub fn verify_password(password: &str, stored: &str) -> InternalResult<bool> {
+    let invalid = || bad_request("Stored password hash is invalid");
+
+    let mut parts = stored.split('$').skip(1);
+    let (Some("scrypt"), Some(params), Some(salt), Some(expected), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+
+    let mut log_n = None;
+    let mut r = None;
+    let mut p = None;
+    for param in params.split(',') {
+        match param.split_once('=') {
+            Some(("ln", v)) => log_n = v.parse::<u8>().ok(),
+            Some(("r", v)) => r = v.parse::<u64>().ok(),
+            Some(("p", v)) => p = v.parse::<u64>().ok(),
+            _ => return Err(invalid()),
+        }
+    }
+    let (Some(log_n), Some(r), Some(p)) = (log_n, r, p) else {
+        return Err(invalid());
+    };
+
+    let salt = base64::decode_block(salt).map_err(|_| invalid())?;
+    let expected = base64::decode_block(expected).map_err(|_| invalid())?;
+    let hash = derive_key(password, &salt, log_n, r, p)?;
+
+    Ok(hash.len() == expected.len() && memcmp::eq(&hash, &expected))
+}
+
+fn derive_key(password: &str, salt: &[u8], log_n: u8, r: u64, p: u64) -> InternalResult<Vec<u8>> {
+    let n = 1u64.checked_shl(log_n.into()).ok_or_else(|| bad_request("Invalid scrypt parameters"))?;
+    // scrypt needs 128 * N * r * p bytes, plus some headroom
+    let max_mem = 128 * n * r * p + 1024 * 1024;
+    let mut key = vec![0; HASH_LENGTH];
+    scrypt(password.as_bytes(), salt, n, r, p, max_mem, &mut key)?;
+    Ok(key)
+}
+
+pub fn validate_new_password(password: &str, confirmation: &str) -> InternalResult<()> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(bad_request(&format!("Passwords must be at least {} characters", MIN_PASSWORD_LENGTH)));
+    }
+    if password != confirmation {
+        return Err(bad_request("Passwords do not match"));
+    }
+    Ok(())
+}
+
+pub fn set_password(db: &Connection, password: &str) -> InternalResult<()> {
+    let password_hash = hash_password(password)?;
+    db.execute(
+        "INSERT INTO credentials (credential_id, password_hash) VALUES (1, ?1)
+        ON CONFLICT (credential_id) DO UPDATE SET
+            password_hash = excluded.password_hash,
+            failed_attempts = 0,
+            locked_until = NULL,
+            updated_at = excluded.updated_at",
+        [password_hash],
+    )?;
+    Ok(())
+}
+
+/// Check the admin password, locking logins for a while after too many wrong guesses
+pub fn check_password(db: &Connection, password: &str) -> InternalResult<CredentialCheck> {
+    let password_hash: Option<String> = db.query_row(
+        "SELECT password_hash FROM credentials WHERE credential_id = 1",
+        (),
+        |row| row.get(0),
+    ).optional()?;
+
+    let password_hash = match password_hash {
+        Some(h) => h,
+        None => return Ok(CredentialCheck::Incorrect),
+    };
+
+    if is_locked_out(db)? {
+        return Ok(CredentialCheck::LockedOut);
+    }
+
+    let is_correct = verify_password(password, &password_hash)?;
+    record_attempt(db, is_correct)
+}
+
+pub fn has_two_factor(db: &Connection) -> InternalResult<bool> {
+    let has_secret: Option<bool> = db.query_row(
+        "SELECT totp_secret IS NOT NULL FROM credentials WHERE credential_id = 1",
+        (),
+        |row| row.get(0),
+    ).optional()?;
+    Ok(has_secret.unwrap_or(false))
+}
+
+/// Generate a new secret to enroll, which doesn't take effect until a code from it is confirmed
+pub fn start_two_factor_enrollment(db: &Connection) -> InternalResult<String> {
+    let secret = totp::generate_secret()?;
+    db.execute("UPDATE credentials SET totp_pending_secret = ?1 WHERE credential_id = 1", [&secret])?;
+    Ok(secret)
+}
+
+pub fn get_pending_two_factor_secret(db: &Connection) -> InternalResult<Option<String>> {
+    let secret = db.query_row(
+        "SELECT totp_pending_secret FROM credentials WHERE credential_id = 1",
+        (),
+        |row| row.get(0),
+    ).optional()?;
+    Ok(secret.flatten())
+}
+
+/// Turn on two-factor if the code matches the pending secret, returning a fresh set of recovery codes
+pub fn confirm_two_factor_enrollment(db: &Connection, code: &str) -> InternalResult<Option<Vec<String>>> {
+    let secret = match get_pending_two_factor_secret(db)? {
+        Some(s) => s,
+        None => return Err(bad_request("Two-factor enrollment has not been started")),
+    };
+
+    let step = match totp::verify_code(&secret, code, totp::get_current_step())? {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+
+    let recovery_codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| make_recovery_code())
+        .collect::<InternalResult<Vec<String>>>()?;
+
+    // Two-factor is never left enabled without the recovery codes the user is about to be shown
+    let tx = db.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE credentials
+        SET totp_secret = totp_pending_secret, totp_pending_secret = NULL, totp_last_step = ?1
+        WHERE credential_id = 1",
+        [step],
+    )?;
+    tx.execute("DELETE FROM recovery_codes", ())?;
+    for code in &recovery_codes {
+        tx.execute("INSERT INTO recovery_codes (code_hash) VALUES (?1)", [hash_recovery_code(code)])?;
+    }
+    tx.commit()?;
+
+    Ok(Some(recovery_codes))
+}
+
+pub fn disable_two_factor(db: &Connection) -> InternalResult<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE credentials
+        SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL
+        WHERE credential_id = 1",
+        (),
+    )?;
+    tx.execute("DELETE FROM recovery_codes", ())?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Check a TOTP code or an unused recovery code, sharing the password's lockout
+pub fn check_second_factor(db: &Connection, code: &str) -> InternalResult<CredentialCheck> {
+    if is_locked_out(db)? {
+        return Ok(CredentialCheck::LockedOut);
+    }
+
+    let credentials: Option<(Option<String>, Option<i64>)> = db.query_row(
+        "SELECT totp_secret, totp_last_step FROM credentials WHERE credential_id = 1",
+        (),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    let (secret, last_step) = match credentials {
+        Some((Some(secret), last_step)) => (secret, last_step),
+        _ => return Ok(CredentialCheck::Incorrect),
+    };
+
+    let step = totp::verify_code(&secret, code, totp::get_current_step())?
+        // A code that was already used (or an older one) could have been seen over someone's shoulder
+        .filter(|step| last_step.is_none_or(|last| *step > last));
+    if let Some(step) = step {
+        db.execute("UPDATE credentials SET totp_last_step = ?1 WHERE credential_id = 1", [step])?;
+        return record_attempt(db, true);
+    }
+
+    let used_recovery_code = db.execute(
+        "UPDATE recovery_codes SET used_at = strftime('%FT%TZ', CURRENT_TIMESTAMP)
+        WHERE code_hash = ?1 AND used_at IS NULL",
+        [hash_recovery_code(code)],
+    )?;
+    record_attempt(db, used_recovery_code > 0)
+}
+
+pub fn get_unused_recovery_code_count(db: &Connection) -> InternalResult<i64> {
+    let count = db.query_row("SELECT COUNT(*) FROM recovery_codes WHERE used_at IS NULL", (), |row| row.get(0))?;
+    Ok(count)
+}
+
+fn is_locked_out(db: &Connection) -> InternalResult<bool> {
+    let is_locked: Option<bool> = db.query_row(
+        "SELECT COALESCE(locked_until > strftime('%FT%TZ', 'now'), FALSE)
+        FROM credentials
+        WHERE credential_id = 1",
+        (),
+        |row| row.get(0),
+    ).optional()?;
+    Ok(is_locked.unwrap_or(false))
+}
+
+fn record_attempt(db: &Connection, is_correct: bool) -> InternalResult<CredentialCheck> {
+    if is_correct {
+        db.execute("UPDATE credentials SET failed_attempts = 0 WHERE credential_id = 1", ())?;
+        return Ok(CredentialCheck::Correct);
+    }
+
+    db.execute(
+        "UPDATE credentials SET
+            failed_attempts = CASE WHEN failed_attempts + 1 >= ?1 THEN 0 ELSE failed_attempts + 1 END,
+            locked_until = CASE
+                WHEN failed_attempts + 1 >= ?1 THEN strftime('%FT%TZ', 'now', ?2)
+                ELSE locked_until
+            END
+        WHERE credential_id = 1",
+        (MAX_FAILED_ATTEMPTS, format!("+{} seconds", LOCKOUT_SECS)),
+    )?;
+    Ok(CredentialCheck::Incorrect)
+}
+
+// Formatted like "k3x9-2mfq-8ad7" so they're easy to copy down
+fn make_recovery_code() -> InternalResult<String> {
+    let mut bytes = [0; RECOVERY_CODE_LENGTH];
+    rand_bytes(&mut bytes)?;
+    let chars: Vec<char> = bytes.iter()
+        .map(|b| RECOVERY_CODE_ALPHABET[*b as usize % RECOVERY_CODE_ALPHABET.len()] as char)
+        .collect();
+    Ok(chars.chunks(4).map(|c| c.iter().collect::<String>()).collect::<Vec<String>>().join("-"))
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    sha256(normalized.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_hashed_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$scrypt$ln=15,r=8,p=1$"));
+        assert!(verify_password("correct horse", &hash).unwrap());
+        assert!(!verify_password("correct horsf", &hash).unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_hash() {
+        assert!(verify_password("password", "plaintext").is_err());
+        assert!(verify_password("password", "$scrypt$ln=15,r=8$c2FsdA==$aGFzaA==").is_err());
+        assert!(verify_password("password", "$bcrypt$ln=15,r=8,p=1$c2FsdA==$aGFzaA==").is_err());
+    }
+
+    #[test]
+    fn recovery_codes_ignore_formatting() {
+        let code = make_recovery_code().unwrap();
+        assert_eq!(code.len(), RECOVERY_CODE_LENGTH + 2);
+        assert_eq!(hash_recovery_code(&code), hash_recovery_code(&code.replace('-', " ").to_uppercase()));
+    }
+}
@@ -1,6 +1,7 @@
 use hyper::header::InvalidHeaderValue;
 use openssl::error::ErrorStack;
 use std::{error::Error, fmt::Display};
+use tokio::task::JoinError;
 
 use hyper::StatusCode;
 
@@ -62,6 +63,16 @@ impl From<hyper::Error> for ServerError {
     }
 }
 
+impl From<JoinError> for ServerError {
+    fn from(err: JoinError) -> Self {
+        ServerError {
+            prefix: "[TASK ERROR]",
+            message: err.to_string(),
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl From<ErrorStack> for ServerError {
     fn from(err: ErrorStack) -> Self {
         ServerError {
@@ -80,6 +91,14 @@ pub fn map_bad_request(e: impl Error) -> ServerError {
     }
 }
 
+pub fn map_internal_error(e: impl Error) -> ServerError {
+    ServerError {
+        prefix: "[INTERNAL ERROR]",
+        message: format!("{:?}", e),
+        status_code: StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 pub fn map_bad_gateway(e: impl Error) -> ServerError {
     ServerError {
         prefix: "[BAD GATEWAY]",
@@ -104,6 +123,22 @@ pub fn forbidden() -> ServerError {
     }
 }
 
+pub fn forbidden_scope() -> ServerError {
+    ServerError {
+        prefix: "[FORBIDDEN]",
+        message: "This action is outside the authorized scopes".to_owned(),
+        status_code: StatusCode::FORBIDDEN,
+    }
+}
+
+pub fn unauthorized(message: &str) -> ServerError {
+    ServerError {
+        prefix: "[UNAUTHORIZED]",
+        message: message.to_owned(),
+        status_code: StatusCode::UNAUTHORIZED,
+    }
+}
+
 pub fn bad_request(message: &str) -> ServerError {
     ServerError {
         prefix: "[BAD REQUEST]",
@@ -135,3 +170,11 @@ pub fn bad_gateway(message: &str) -> ServerError {
         status_code: StatusCode::BAD_REQUEST,
     }
 }
+
+pub fn service_unavailable(message: &str) -> ServerError {
+    ServerError {
+        prefix: "[UNAVAILABLE]",
+        message: message.to_owned(),
+        status_code: StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
@@ -0,0 +1,106 @@
+use hyper::body::Bytes;
+use serde::de::DeserializeOwned;
+
+use super::error::{bad_request, body_too_large, map_bad_request};
+use super::server_response::InternalResult;
+
+pub struct Field {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub data: Bytes,
+}
+
+/// A parsed multipart/form-data body, with its fields kept in order
+pub struct MultipartForm {
+    fields: Vec<Field>,
+}
+
+/// Get the boundary out of a Content-Type header, if it's a multipart one
+pub fn get_boundary(content_type: &str) -> Option<String> {
+    multer::parse_boundary(content_type).ok()
+}
+
+pub async fn parse(body: Bytes, boundary: String) -> InternalResult<MultipartForm> {
+    let mut multipart = multer::Multipart::with_reader(&body[..], boundary);
+    let mut fields = Vec::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(map_multer_error)? {
+        let name = field.name().unwrap_or_default().to_owned();
+        let file_name = field.file_name().map(str::to_owned);
+        let data = field.bytes().await.map_err(map_multer_error)?;
+        fields.push(Field { name, file_name, data });
+    }
+
+    Ok(MultipartForm { fields })
+}
+
+impl MultipartForm {
+    /// Deserialize the text fields the same way that a urlencoded form would be
+    pub fn get_form_data<T: DeserializeOwned>(&self) -> InternalResult<T> {
+        let pairs: Vec<(&str, &str)> = self.fields.iter()
+            .filter(|field| field.file_name.is_none())
+            .map(|field| {
+                let value = std::str::from_utf8(&field.data).map_err(|_| bad_request("Form field is not UTF-8"))?;
+                Ok((field.name.as_str(), value))
+            })
+            .collect::<InternalResult<_>>()?;
+
+        let encoded = serde_html_form::to_string(pairs).map_err(map_bad_request)?;
+        serde_html_form::from_str(&encoded).map_err(map_bad_request)
+    }
+
+    /// Get every uploaded file with the given field name, skipping empty file inputs
+    pub fn get_files(&self, name: &str) -> Vec<&Field> {
+        self.fields.iter()
+            .filter(|field| field.name == name && field.file_name.is_some() && !field.data.is_empty())
+            .collect()
+    }
+}
+
+fn map_multer_error(e: multer::Error) -> crate::server::error::ServerError {
+    match e {
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. } => body_too_large(),
+        e => map_bad_request(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Form {
+        content: String,
+        alt: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn parses_fields_and_files() {
+        let body = concat!(
+            "--XYZ\r\n",
+            "Content-Disposition: form-data; name=\"content\"\r\n\r\n",
+            "hello there\r\n",
+            "--XYZ\r\n",
+            "Content-Disposition: form-data; name=\"media\"; filename=\"boat.png\"\r\n",
+            "Content-Type: image/png\r\n\r\n",
+            "PNGDATA\r\n",
+            "--XYZ\r\n",
+            "Content-Disposition: form-data; name=\"alt\"\r\n\r\n",
+            "A boat\r\n",
+            "--XYZ--\r\n",
+        );
+
+        let boundary = get_boundary("multipart/form-data; boundary=XYZ").unwrap();
+        let form = parse(Bytes::from(body), boundary).await.unwrap();
+
+        let fields: Form = form.get_form_data().unwrap();
+        assert_eq!(fields.content, "hello there");
+        assert_eq!(fields.alt, vec!["A boat"]);
+
+        let files = form.get_files("media");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file_name.as_deref(), Some("boat.png"));
+        assert_eq!(&files[0].data[..], b"PNGDATA");
+    }
+}
@@ -0,0 +1,323 @@
+use hyper::Uri;
+use openssl::{memcmp, sha::sha256};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::server::error::bad_request;
+use crate::server::server_response::InternalResult;
+use crate::server::sessions::make_token;
+
+// Apps that can't catch a redirect get the code shown to the user to paste in instead
+pub const OUT_OF_BAND_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+const DEFAULT_SCOPES: &str = "read";
+// Codes are only meant to survive the trip back to the app
+const CODE_TTL_SECS: i64 = 60 * 10;
+// The legacy "follow" scope predates granular scopes, and covers these
+const FOLLOW_SCOPES: &[&str] = &["read:follows", "write:follows", "read:blocks", "write:blocks", "read:mutes", "write:mutes"];
+// Schemes that run something in the browser rather than taking it somewhere
+const SCRIPT_SCHEMES: &[&str] = &["javascript", "data", "vbscript"];
+
+#[derive(Debug, Serialize)]
+pub struct OAuthApp {
+    pub client_id: String,
+    pub client_secret: String,
+    pub name: String,
+    pub website: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub scopes: String,
+}
+
+/// What an access token is allowed to do, and as whom
+pub struct TokenGrant {
+    pub profile_id: i64,
+    pub scopes: String,
+}
+
+pub struct AccessToken {
+    pub token: String,
+    pub scopes: String,
+    pub created_at: i64,
+}
+
+pub fn create_app(
+    db: &Connection,
+    name: &str,
+    website: Option<&str>,
+    redirect_uris: &str,
+    scopes: Option<&str>,
+) -> InternalResult<OAuthApp> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(bad_request("Apps need a name"));
+    }
+
+    // Clients send several redirect URIs as one newline-separated string
+    let redirect_uris: Vec<String> = redirect_uris.split_whitespace().map(str::to_owned).collect();
+    if redirect_uris.is_empty() {
+        return Err(bad_request("Apps need at least one redirect URI"));
+    }
+    if let Some(uri) = redirect_uris.iter().find(|uri| !is_valid_redirect_uri(uri)) {
+        return Err(bad_request(&format!("{} is not a valid redirect URI", uri)));
+    }
+
+    let website = website.map(str::trim).filter(|w| !w.is_empty());
+    if let Some(website) = website.filter(|w| !is_web_url(w)) {
+        return Err(bad_request(&format!("{} is not a valid website", website)));
+    }
+
+    let scopes = normalize_scopes(scopes.unwrap_or(DEFAULT_SCOPES))?;
+    let app = OAuthApp {
+        client_id: make_token(),
+        client_secret: make_token(),
+        name: name.to_owned(),
+        website: website.map(str::to_owned),
+        redirect_uris,
+        scopes,
+    };
+
+    db.execute(
+        "INSERT INTO oauth_apps (client_id, client_secret, name, website, redirect_uris, scopes)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        (&app.client_id, &app.client_secret, &app.name, &app.website, app.redirect_uris.join("\n"), &app.scopes),
+    )?;
+    Ok(app)
+}
+
+pub fn get_app(db: &Connection, client_id: &str) -> InternalResult<Option<OAuthApp>> {
+    let app = db.query_row(
+        "SELECT client_id, client_secret, name, website, redirect_uris, scopes FROM oauth_apps WHERE client_id = ?1",
+        [client_id],
+        |row| {
+            let redirect_uris: String = row.get(4)?;
+            let app = OAuthApp {
+                client_id: row.get(0)?,
+                client_secret: row.get(1)?,
+                name: row.get(2)?,
+                website: row.get(3)?,
+                redirect_uris: redirect_uris.lines().map(str::to_owned).collect(),
+                scopes: row.get(5)?,
+            };
+            Ok(app)
+        },
+    ).optional()?;
+    Ok(app)
+}
+
+impl OAuthApp {
+    pub fn has_secret(&self, secret: &str) -> bool {
+        is_same_secret(&self.client_secret, secret)
+    }
+
+    /// Check that the scopes being asked for are ones the app registered for
+    pub fn get_requested_scopes(&self, requested: Option<&str>) -> InternalResult<String> {
+        let requested = match requested.map(str::trim) {
+            Some(requested) if !requested.is_empty() => normalize_scopes(requested)?,
+            _ => return Ok(self.scopes.clone()),
+        };
+
+        let registered: Vec<&str> = self.scopes.split(' ').collect();
+        match requested.split(' ').find(|scope| !registered.contains(scope)) {
+            Some(scope) => Err(bad_request(&format!("The app didn't register for the {} scope", scope))),
+            None => Ok(requested),
+        }
+    }
+}
+
+/// Authorize an app to act as a profile, returning the code that the app exchanges for a token
+pub fn create_authorization_code(
+    db: &Connection,
+    client_id: &str,
+    profile_id: i64,
+    redirect_uri: &str,
+    scopes: &str,
+) -> InternalResult<String> {
+    db.execute(
+        "DELETE FROM oauth_codes WHERE created_at <= strftime('%FT%TZ', 'now', ?1)",
+        [format!("-{} seconds", CODE_TTL_SECS)],
+    )?;
+
+    let code = make_token();
+    db.execute(
+        "INSERT INTO oauth_codes (code_hash, client_id, profile_id, redirect_uri, scopes) VALUES (?1, ?2, ?3, ?4, ?5)",
+        (hash_token(&code), client_id, profile_id, redirect_uri, scopes),
+    )?;
+    Ok(code)
+}
+
+/// Swap an authorization code for an access token; each code only works once
+pub fn exchange_authorization_code(
+    db: &Connection,
+    client_id: &str,
+    code: &str,
+    redirect_uri: &str,
+) -> InternalResult<Option<AccessToken>> {
+    let grant = db.query_row(
+        "DELETE FROM oauth_codes
+        WHERE code_hash = ?1 AND client_id = ?2 AND redirect_uri = ?3
+            AND created_at > strftime('%FT%TZ', 'now', ?4)
+        RETURNING profile_id, scopes",
+        (hash_token(code), client_id, redirect_uri, format!("-{} seconds", CODE_TTL_SECS)),
+        |row| Ok(TokenGrant { profile_id: row.get(0)?, scopes: row.get(1)? }),
+    ).optional()?;
+
+    let Some(grant) = grant else {
+        return Ok(None);
+    };
+
+    let token = make_token();
+    let created_at = db.query_row(
+        "INSERT INTO access_tokens (token_hash, client_id, profile_id, scopes) VALUES (?1, ?2, ?3, ?4)
+        RETURNING unixepoch(created_at)",
+        (hash_token(&token), client_id, grant.profile_id, &grant.scopes),
+        |row| row.get(0),
+    )?;
+    Ok(Some(AccessToken { token, scopes: grant.scopes, created_at }))
+}
+
+pub fn get_token_grant(db: &Connection, token: &str) -> InternalResult<Option<TokenGrant>> {
+    let grant = db.query_row(
+        "SELECT profile_id, scopes FROM access_tokens WHERE token_hash = ?1",
+        [hash_token(token)],
+        |row| Ok(TokenGrant { profile_id: row.get(0)?, scopes: row.get(1)? }),
+    ).optional()?;
+    Ok(grant)
+}
+
+pub fn revoke_token(db: &Connection, client_id: &str, token: &str) -> InternalResult<()> {
+    db.execute(
+        "DELETE FROM access_tokens WHERE token_hash = ?1 AND client_id = ?2",
+        (hash_token(token), client_id),
+    )?;
+    Ok(())
+}
+
+pub fn revoke_all_tokens(db: &Connection) -> InternalResult<()> {
+    db.execute("DELETE FROM oauth_codes", ())?;
+    db.execute("DELETE FROM access_tokens", ())?;
+    Ok(())
+}
+
+/// Whether the granted scopes cover the one needed, either directly or through a broader scope
+pub fn has_scope(granted: &str, needed: &str) -> bool {
+    let parent = needed.split_once(':').map(|(parent, _)| parent);
+    granted.split(' ').any(|scope| {
+        scope == needed || Some(scope) == parent || (scope == "follow" && FOLLOW_SCOPES.contains(&needed))
+    })
+}
+
+// Admin scopes are left out, since there's nothing for them to do here
+fn normalize_scopes(scopes: &str) -> InternalResult<String> {
+    let mut normalized: Vec<&str> = Vec::new();
+    for scope in scopes.split([' ', '+', ',']).filter(|s| !s.is_empty()) {
+        let is_known = matches!(scope, "read" | "write" | "follow" | "push" | "profile")
+            || scope.starts_with("read:")
+            || scope.starts_with("write:");
+        if !is_known {
+            return Err(bad_request(&format!("Unknown scope: {}", scope)));
+        }
+        if !normalized.contains(&scope) {
+            normalized.push(scope);
+        }
+    }
+
+    if normalized.is_empty() {
+        return Err(bad_request("At least one scope is required"));
+    }
+    Ok(normalized.join(" "))
+}
+
+// Tokens are random enough not to need a slow hash, and a leaked database can't be used to log in
+fn hash_token(token: &str) -> String {
+    sha256(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_same_secret(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len() && memcmp::eq(expected.as_bytes(), provided.as_bytes())
+}
+
+/// Redirects can go to a web page, back into a native app through its own scheme, or nowhere
+fn is_valid_redirect_uri(uri: &str) -> bool {
+    if uri == OUT_OF_BAND_URI {
+        return true;
+    }
+    // Codes are sent back in the query, and fragments aren't allowed in redirect URIs at all
+    if uri.contains('#') || uri.chars().any(|c| c.is_control()) {
+        return false;
+    }
+
+    let Some((scheme, rest)) = uri.split_once(':') else {
+        return false;
+    };
+    let is_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !is_scheme || rest.is_empty() {
+        return false;
+    }
+
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "https" => is_web_url(uri),
+        "urn" => false,
+        scheme => !SCRIPT_SCHEMES.contains(&scheme),
+    }
+}
+
+fn is_web_url(url: &str) -> bool {
+    match url.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some_and(|h| !h.is_empty()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::open_test_db;
+
+    #[test]
+    fn broader_scopes_cover_narrower_ones() {
+        assert!(has_scope("read write", "read:statuses"));
+        assert!(has_scope("read:statuses", "read:statuses"));
+        assert!(has_scope("read follow", "write:follows"));
+        assert!(!has_scope("read", "write:statuses"));
+        assert!(!has_scope("read:accounts", "read:statuses"));
+        assert!(!has_scope("follow", "write:statuses"));
+    }
+
+    #[test]
+    fn normalizes_requested_scopes() {
+        assert_eq!(normalize_scopes("read+write  follow read").unwrap(), "read write follow");
+        assert!(normalize_scopes("admin:read").is_err());
+        assert!(normalize_scopes(" ").is_err());
+    }
+
+    #[test]
+    fn validates_redirect_uris() {
+        assert!(is_valid_redirect_uri("https://app.example/callback?x=y"));
+        assert!(is_valid_redirect_uri("http://localhost:8080/callback"));
+        assert!(is_valid_redirect_uri("com.example.app:/oauth"));
+        assert!(is_valid_redirect_uri("myapp://callback"));
+        assert!(is_valid_redirect_uri(OUT_OF_BAND_URI));
+
+        assert!(!is_valid_redirect_uri("javascript:alert(1)"));
+        assert!(!is_valid_redirect_uri("JavaScript:alert(1)"));
+        assert!(!is_valid_redirect_uri("data:text/html,<script>alert(1)</script>"));
+        assert!(!is_valid_redirect_uri("vbscript:msgbox"));
+        assert!(!is_valid_redirect_uri("urn:example:other"));
+        assert!(!is_valid_redirect_uri("/relative/callback"));
+        assert!(!is_valid_redirect_uri("https:///no-host"));
+        assert!(!is_valid_redirect_uri("https://app.example/callback#token"));
+        assert!(!is_valid_redirect_uri("1app://callback"));
+    }
+
+    #[test]
+    fn rejects_apps_with_script_urls() {
+        let db = open_test_db();
+        assert!(create_app(&db, "App", None, "javascript:alert(1)", None).is_err());
+        assert!(create_app(&db, "App", None, "https://app.example/cb\njavascript:alert(1)", None).is_err());
+        assert!(create_app(&db, "App", Some("javascript:alert(1)"), "https://app.example/cb", None).is_err());
+        assert!(create_app(&db, "App", Some("app.example"), "https://app.example/cb", None).is_err());
+
+        let app = create_app(&db, "App", Some(" https://app.example "), "myapp://cb", None).unwrap();
+        assert_eq!(app.website.as_deref(), Some("https://app.example"));
+    }
+}
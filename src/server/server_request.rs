@@ -1,9 +1,12 @@
+use crate::activitypub::{requests, signature};
+use crate::queries::{cache_public_key, get_cached_public_key};
 use crate::server::context::GlobalContext;
 use crate::server::error;
-use crate::server::error::{map_bad_gateway, map_bad_request, ServerError};
+use crate::server::error::{bad_gateway, map_bad_gateway, map_bad_request, unauthorized, ServerError};
 use http_body_util::BodyExt;
 use hyper::body::{Bytes, Incoming};
-use hyper::header::{ACCEPT, COOKIE};
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use hyper::Uri;
 use minijinja::{context, Value};
 use openssl::pkey::{PKey, Private};
 use rusqlite::{Connection, OptionalExtension};
@@ -11,13 +14,21 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::Arc;
-use tracing::log::warn;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::warn;
 
-pub static LONG_ACCEPT_HEADER: &str = "application/ld+json;profile=“https://www.w3.org/ns/activitystreams";
-pub static SHORT_ACCEPT_HEADER: &str = "application/activity+json";
-
-use super::error::{bad_request, body_not_utf8, body_too_large};
+use super::accept::prefers_activitypub;
+use super::error::{bad_request, body_not_utf8, body_too_large, forbidden_scope};
+use super::oauth::{get_token_grant, has_scope};
 use super::server_response::InternalResult;
+use super::sessions::renew_session;
+use crate::sqlite::pool::Pool;
+
+const MAX_BODY_SIZE: usize = 1024 * 64;
+
+// The sender is waiting on us, so a slow key host shouldn't be able to hold up the inbox
+const KEY_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
 
 const ENV: &str = if cfg!(debug_assertions) { "debug" } else { "prod" };
 
@@ -53,39 +64,76 @@ impl CurrentProfile {
 }
 
 pub struct NoAuth;
-pub struct SetupPhase;
+pub struct SetupPhase {
+    pub csrf_token: String,
+}
+
+/// A request whose HTTP signature was verified against the public key of `actor_id`
+pub struct Signed {
+    pub actor_id: String,
+}
+
+/// A request from a client app, acting as the single profile that its access token was issued for
+pub struct TokenAuth {
+    pub current_profile: CurrentProfile,
+    pub scopes: String,
+}
+
+impl TokenAuth {
+    pub fn require_scope(&self, scope: &str) -> InternalResult<()> {
+        if has_scope(&self.scopes, scope) {
+            Ok(())
+        } else {
+            Err(forbidden_scope())
+        }
+    }
+}
 
 #[derive(Serialize)]
 pub struct SessionData {
     pub profiles: Vec<Profile>,
     pub current_profile: CurrentProfile,
+    pub csrf_token: String,
 }
 
 pub trait AuthState {
     fn get(&self) -> Option<&SessionData>;
+    fn csrf_token(&self) -> Option<&str> { None }
 }
 
 impl AuthState for SetupPhase {
     fn get(&self) -> Option<&SessionData> { None }
+    fn csrf_token(&self) -> Option<&str> { Some(&self.csrf_token) }
 }
 
 impl AuthState for NoAuth {
     fn get(&self) -> Option<&SessionData> { None }
 }
 
+impl AuthState for Signed {
+    fn get(&self) -> Option<&SessionData> { None }
+}
+
+impl AuthState for TokenAuth {
+    fn get(&self) -> Option<&SessionData> { None }
+}
+
 impl AuthState for SessionData {
     fn get(&self) -> Option<&SessionData> { Some(self) }
+    fn csrf_token(&self) -> Option<&str> { Some(&self.csrf_token) }
 }
 
 pub type AuthedRequest<'a> = ServerRequest<'a, Incoming, SessionData>;
 pub type SetupRequest<'a> = ServerRequest<'a, Incoming, SetupPhase>;
 pub type PlainRequest<'a> = ServerRequest<'a, Incoming, NoAuth>;
 pub type AnyRequest<'a, Au> = ServerRequest<'a, Incoming, Au>;
+pub type SignedRequest<'a> = ServerRequest<'a, String, Signed>;
+pub type ApiRequest<'a> = ServerRequest<'a, Incoming, TokenAuth>;
 
 pub struct ServerRequest<'a, T, Au: AuthState> {
     pub request: hyper::Request<T>,
     pub global: Arc<GlobalContext<'a>>,
-    pub db: Connection,
+    pub db: Pool,
     pub cookies: HashMap<String, String>,
     pub data: Au,
     pub domain: String,
@@ -94,7 +142,6 @@ pub struct ServerRequest<'a, T, Au: AuthState> {
 pub fn new_request<T>(
     request: hyper::Request<T>,
     global: Arc<GlobalContext>,
-    db: Connection,
     domain: String
 ) -> Result<ServerRequest<T, NoAuth>, ServerError> {
     let cookie_string = request
@@ -110,6 +157,7 @@ pub fn new_request<T>(
         .map(|(key, value)| (key.to_owned(), value.to_owned()))
         .collect::<HashMap<String, String>>();
 
+    let db = global.pool.clone();
     Ok(ServerRequest { request, global, db, domain, cookies, data: NoAuth })
 }
 
@@ -131,12 +179,12 @@ impl<'a, T, Au: AuthState> ServerRequest<'a, T, Au> {
         self.uri()
             .path()
             .split('/')
-            .last()
+            .next_back()
             .ok_or(error::bad_request(message))
     }
 
     fn make_context(&self, local_values: Value) -> Value {
-        let global_values = context! { env => ENV };
+        let global_values = context! { env => ENV, csrf_token => self.data.csrf_token() };
         if let Some(locals) = self.data.get() {
             let request_values = context! { profiles => locals.profiles };
             context! { ..local_values, ..request_values, ..global_values }
@@ -153,17 +201,13 @@ impl<'a, T, Au: AuthState> ServerRequest<'a, T, Au> {
             .map_err(map_bad_gateway)
     }
 
-    // Check whether the header is asking for (AcvitiyPub) JSON
-    // TODO actually parse the header properly
+    // Check whether the header is asking for (ActivityPub) JSON rather than HTML
+    // Responses that depend on this should be sent through server_response::vary_on_accept
     pub fn is_ap_req(&self) -> bool {
-        let request_header = self.headers().get(ACCEPT);
-        match request_header {
-            None => false,
-            Some(h) => {
-                let h = h.to_str().unwrap_or("");
-                h.contains(LONG_ACCEPT_HEADER) || h.contains(SHORT_ACCEPT_HEADER)
-            }
-        }
+        self.headers()
+            .get(ACCEPT)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(prefers_activitypub)
     }
 
 // pub fn render_block(&self, path: &str, block_name: &str, local_values: Value) -> Vec<u8> {
@@ -186,43 +230,88 @@ pub enum AuthStatus<'a, T> {
 }
 
 impl<'a, T> ServerRequest<'a, T, NoAuth> {
-    pub fn authenticate(self) -> AuthStatus<'a, T> {
+    pub async fn authenticate(self) -> AuthStatus<'a, T> {
         let cookie_token = match self.cookies.get("token") {
-            Some(token) => token,
+            Some(token) => token.clone(),
             None => return AuthStatus::Failure(self)
         };
 
-        let token_exists = self.db
-            .query_row("SELECT token FROM sessions WHERE token = ?1", (cookie_token, ), |_| { Ok(true) })
-            .optional()
+        // Every authenticated request pushes the session's expiry back
+        let ttl_secs = self.global.config.session_ttl_secs;
+        let csrf_token = self.db.run(move |db| renew_session(db, &cookie_token, ttl_secs))
+            .await
             .ok()
-            .flatten()
-            .is_some();
+            .flatten();
 
-        if !token_exists {
-            return AuthStatus::Failure(self)
-        }
+        let csrf_token = match csrf_token {
+            Some(t) => t,
+            None => return AuthStatus::Failure(self)
+        };
 
         let request = self.request;
         let global = self.global;
         let db = self.db;
         let domain = self.domain;
         let cookies = self.cookies;
-        let data = SetupPhase;
+        let data = SetupPhase { csrf_token };
 
         AuthStatus::Success(ServerRequest { request, global, db, domain, cookies, data })
     }
 }
 
+pub enum TokenStatus<'a, T> {
+    Success(ServerRequest<'a, T, TokenAuth>),
+    Failure(ServerRequest<'a, T, NoAuth>),
+}
+
+impl<'a, T> ServerRequest<'a, T, NoAuth> {
+    /// Check the request's bearer token, which client apps send instead of a session cookie
+    pub async fn authenticate_token(self) -> Result<TokenStatus<'a, T>, ServerError> {
+        let token = self.headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        let Some(token) = token else {
+            return Ok(TokenStatus::Failure(self));
+        };
+
+        let domain = self.domain.clone();
+        let authed = self.db.run(move |db| {
+            let Some(grant) = get_token_grant(db, &token)? else {
+                return Ok(None);
+            };
+            let current_profile = CurrentProfile::new(db, grant.profile_id, &domain);
+            Ok(current_profile.map(|profile| (grant, profile)))
+        }).await?;
+        let Some((grant, current_profile)) = authed else {
+            return Ok(TokenStatus::Failure(self));
+        };
+
+        let request = self.request;
+        let global = self.global;
+        let db = self.db;
+        let domain = self.domain;
+        let cookies = self.cookies;
+        let data = TokenAuth { current_profile, scopes: grant.scopes };
+
+        Ok(TokenStatus::Success(ServerRequest { request, global, db, domain, cookies, data }))
+    }
+}
+
 pub enum SetupStatus<'a, T> {
     Complete(ServerRequest<'a, T, SessionData>),
     Incomplete(ServerRequest<'a, T, SetupPhase>),
 }
 
 impl<'a, T> ServerRequest<'a, T, SetupPhase> {
-    pub fn has_passed_setup(self) -> Result<SetupStatus<'a, T>, ServerError> {
-        let profiles = {
-            let mut query = self.db.prepare("SELECT profile_id, nickname FROM profiles")?;
+    pub async fn has_passed_setup(self) -> Result<SetupStatus<'a, T>, ServerError> {
+        let cookie_profile_id = self.cookies
+            .get("current_profile")
+            .and_then(|id| id.parse::<i64>().ok());
+        let profile_domain = self.domain.clone();
+        let (profiles, current_profile) = self.db.run(move |db| {
+            let mut query = db.prepare("SELECT profile_id, nickname FROM profiles")?;
             let rows = query.query_map((), |row| {
                 let profiles = Profile { profile_id: row.get(0)?, nickname: row.get(1)? };
                 Ok(profiles)
@@ -231,8 +320,16 @@ impl<'a, T> ServerRequest<'a, T, SetupPhase> {
             let mut profiles = Vec::new();
             for profile in rows { profiles.push(profile?); }
 
-            profiles
-        };
+            let current_profile_id = cookie_profile_id.or_else(|| {
+                db.query_row("SELECT profile_id FROM profiles", (), |row| { row.get(0) }).ok()
+            });
+
+            let current_profile = current_profile_id.and_then(|profile_id| {
+                CurrentProfile::new(db, profile_id, &profile_domain)
+            });
+
+            Ok((profiles, current_profile))
+        }).await?;
 
         let request = self.request;
         let global = self.global;
@@ -240,26 +337,16 @@ impl<'a, T> ServerRequest<'a, T, SetupPhase> {
         let domain = self.domain;
         let cookies = self.cookies;
 
-        let current_profile_id = cookies
-            .get("current_profile")
-            .and_then(|id| id.parse::<i64>().ok())
-            .or_else(|| {
-                db.query_row("SELECT profile_id FROM profiles", (), |row| { row.get(0) }).ok()
-            });
-
-        let current_profile = current_profile_id.map(|profile_id| {
-            CurrentProfile::new(&db, profile_id, &domain)
-        }).flatten();
-
         let current_profile = match current_profile {
             Some(p) => p,
             None => {
-                let req = ServerRequest { request, global, db, domain, cookies, data: SetupPhase };
+                let data = SetupPhase { csrf_token: self.data.csrf_token };
+                let req = ServerRequest { request, global, db, domain, cookies, data };
                 return Ok(SetupStatus::Incomplete(req))
             }
         };
 
-        let data = SessionData { profiles, current_profile };
+        let data = SessionData { profiles, current_profile, csrf_token: self.data.csrf_token };
 
         let req = ServerRequest { request, global, db, domain, cookies, data };
         Ok(SetupStatus::Complete(req))
@@ -268,8 +355,13 @@ impl<'a, T> ServerRequest<'a, T, SetupPhase> {
 
 impl<'a, Au: AuthState> ServerRequest<'a, Incoming, Au> {
     pub async fn get_body(self) -> Result<ServerRequest<'a, Bytes, Au>, ServerError> {
+        self.get_body_with_limit(MAX_BODY_SIZE).await
+    }
+
+    /// Like get_body, but for the few routes that take uploads
+    pub async fn get_body_with_limit(self, limit: usize) -> Result<ServerRequest<'a, Bytes, Au>, ServerError> {
         let (parts, body) = self.request.into_parts();
-        let body_bytes = http_body_util::Limited::new(body, 1024 * 64);
+        let body_bytes = http_body_util::Limited::new(body, limit);
 
         let bytes = body_bytes
             .collect()
@@ -292,8 +384,6 @@ impl<'a, Au: AuthState> ServerRequest<'a, Incoming, Au> {
     }
 }
 
-// Not sure that I even need this intermediate state at all right now
-// But I think it will become relevant for uploading images
 impl<'a, Au: AuthState> ServerRequest<'a, Bytes, Au> {
     pub fn into_text(self) -> Result<ServerRequest<'a, String, Au>, ServerError> {
         let (parts, body) = self.request.into_parts();
@@ -308,6 +398,79 @@ impl<'a, Au: AuthState> ServerRequest<'a, Bytes, Au> {
 
         Ok(ServerRequest { request, global, db, domain, cookies, data })
     }
+
+    pub async fn verify_signature(self) -> Result<ServerRequest<'a, Bytes, Signed>, ServerError> {
+        let pending = signature::prepare_verification(self.method(), self.uri(), self.headers(), self.body())?;
+
+        let key_id = pending.key_id.clone();
+        let cached_key = self.db.run(move |db| get_cached_public_key(db, &key_id)).await?;
+        let is_cached_key_valid = match &cached_key {
+            Some(key) => signature::verify_signature(&pending.signing_string, &pending.signature, &key.public_key_pem)?,
+            None => false
+        };
+
+        // If the cached key doesn't work, the sender might have rotated it, so always refetch
+        let key = match cached_key {
+            Some(key) if is_cached_key_valid => key,
+            _ => {
+                // Mastodon's secure mode requires that we sign the fetch, so borrow any local profile
+                let domain = self.domain.clone();
+                let profile = self.db.run(move |db| {
+                    let profile = db
+                        .query_row("SELECT profile_id FROM profiles ORDER BY profile_id", (), |row| row.get(0))
+                        .optional()?
+                        .and_then(|profile_id| CurrentProfile::new(db, profile_id, &domain));
+                    Ok(profile)
+                }).await?
+                .ok_or_else(|| unauthorized("No local profile available to fetch public keys"))?;
+
+                let key_uri: Uri = pending.key_id
+                    .split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .parse()
+                    .map_err(|_| unauthorized("Invalid keyId provided"))?;
+                let key = timeout(KEY_FETCH_TIMEOUT, requests::get_public_key(&key_uri, &profile))
+                    .await
+                    .map_err(|_| bad_gateway("Timed out fetching the public key"))??;
+
+                if key.id != pending.key_id {
+                    return Err(unauthorized("Fetched public key does not match keyId"))
+                }
+                if !signature::verify_signature(&pending.signing_string, &pending.signature, &key.public_key_pem)? {
+                    return Err(unauthorized("Signature does not match"))
+                }
+                if !signature::is_same_origin(&key.id, &key.owner) {
+                    return Err(unauthorized("Public key owner is on a different host"))
+                }
+
+                // A key in its own document has to be claimed by its owner as well
+                if key_uri.to_string() != key.owner {
+                    let owner_uri: Uri = key.owner.parse().map_err(|_| unauthorized("Invalid key owner provided"))?;
+                    let owner = timeout(KEY_FETCH_TIMEOUT, requests::get_actor(&owner_uri, &profile))
+                        .await
+                        .map_err(|_| bad_gateway("Timed out fetching the public key owner"))??;
+                    if owner.public_key.id != key.id {
+                        return Err(unauthorized("Public key is not claimed by its owner"))
+                    }
+                }
+
+                self.db.run(move |db| {
+                    cache_public_key(db, &key)?;
+                    Ok(key)
+                }).await?
+            }
+        };
+
+        let request = self.request;
+        let global = self.global;
+        let db = self.db;
+        let domain = self.domain;
+        let cookies = self.cookies;
+        let data = Signed { actor_id: key.owner };
+
+        Ok(ServerRequest { request, global, db, domain, cookies, data })
+    }
 }
 
 impl<'a, Au: AuthState> ServerRequest<'a, String, Au> {
@@ -326,4 +489,13 @@ impl<'a, Au: AuthState> ServerRequest<'a, String, Au> {
             map_bad_request(e)
         })
     }
+
+    /// Client apps send either JSON or a form, so go by the content type
+    pub fn parse_form_or_json<T: Deserialize<'a>>(&'a self) -> InternalResult<T> {
+        let is_json = self.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|t| t.starts_with("application/json"));
+        if is_json { self.parse_json() } else { self.get_form_data() }
+    }
 }
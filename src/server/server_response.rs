@@ -1,10 +1,12 @@
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::Bytes;
-use hyper::header::{HeaderValue, LOCATION};
+use hyper::header::{HeaderValue, CONTENT_TYPE, LOCATION, VARY};
 use hyper::{Response, StatusCode};
 use minijinja::context;
+use serde_json::json;
 
+use crate::server::accept::ACTIVITY_JSON;
 use crate::server::error::ServerError;
 
 use super::server_request::{AnyRequest, AuthState};
@@ -30,6 +32,37 @@ pub fn send<T: Into<Bytes>>(body: T) -> Response<BoxBody<hyper::body::Bytes, hyp
     Response::new(full(body))
 }
 
+pub fn send_activity_json<T: Into<Bytes>>(body: T) -> ServerResponse {
+    let mut res = send(body);
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(ACTIVITY_JSON));
+    res
+}
+
+pub fn send_json<T: Into<Bytes>>(body: T) -> ServerResponse {
+    let mut res = send(body);
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+/// Client apps expect errors as JSON, in the shape that Mastodon sends them
+pub fn send_json_error(status: StatusCode, message: &str) -> ServerResult {
+    let message = match message {
+        "" => status.canonical_reason().unwrap_or("Error"),
+        message => message,
+    };
+    let mut res = send_json(json!({ "error": message }).to_string());
+    *res.status_mut() = status;
+    Ok(res)
+}
+
+/// Responses that pick between HTML and ActivityPub need to tell caches that they did
+pub fn vary_on_accept(result: ServerResult) -> ServerResult {
+    result.map(|mut res| {
+        res.headers_mut().append(VARY, HeaderValue::from_static("Accept"));
+        res
+    })
+}
+
 pub fn redirect(path: &str) -> ServerResult {
     let mut res = Response::new(empty());
     let location_val = HeaderValue::from_str(path).map_err(|_| ServerError {
@@ -62,6 +95,13 @@ pub fn not_found<Au: AuthState>(req: &AnyRequest<Au>) -> ServerResult {
     Ok(res)
 }
 
+pub fn gone<Au: AuthState>(req: &AnyRequest<Au>) -> ServerResult {
+    let page = req.render("410.html", context! {})?;
+    let mut res = send(page);
+    *res.status_mut() = StatusCode::GONE;
+    Ok(res)
+}
+
 pub fn ok() -> ServerResult {
     Ok(send("OK".to_string()))
 }
@@ -0,0 +1,95 @@
+use openssl::memcmp;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::server::server_response::InternalResult;
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const TOKEN_LENGTH: usize = 32;
+// How long someone has to enter their TOTP code after getting the password right
+const SECOND_FACTOR_TIMEOUT_SECS: i64 = 60 * 5;
+
+pub fn make_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn get_ttl_modifier(ttl_secs: i64) -> String {
+    format!("-{} seconds", ttl_secs)
+}
+
+/// Start a new session, returning its token; expired sessions are cleared out while we're here
+/// A session that needs a second factor can't be used for anything until it's completed
+pub fn create_session(db: &Connection, ttl_secs: i64, needs_second_factor: bool) -> InternalResult<String> {
+    db.execute(
+        "DELETE FROM sessions
+        WHERE last_seen <= strftime('%FT%TZ', 'now', ?1)
+            OR (needs_second_factor AND timestamp <= strftime('%FT%TZ', 'now', ?2))",
+        (get_ttl_modifier(ttl_secs), get_ttl_modifier(SECOND_FACTOR_TIMEOUT_SECS)),
+    )?;
+
+    let token = make_token();
+    db.execute(
+        "INSERT INTO sessions (token, csrf_token, needs_second_factor) VALUES (?1, ?2, ?3)",
+        (&token, make_token(), needs_second_factor),
+    )?;
+    Ok(token)
+}
+
+pub fn is_awaiting_second_factor(db: &Connection, token: &str) -> InternalResult<bool> {
+    let is_awaiting = db.query_row(
+        "SELECT EXISTS (
+            SELECT 1 FROM sessions
+            WHERE token = ?1 AND needs_second_factor AND timestamp > strftime('%FT%TZ', 'now', ?2)
+        )",
+        (token, get_ttl_modifier(SECOND_FACTOR_TIMEOUT_SECS)),
+        |row| row.get(0),
+    )?;
+    Ok(is_awaiting)
+}
+
+/// Look up a session that hasn't expired, renewing it and returning its CSRF token
+pub fn renew_session(db: &Connection, token: &str, ttl_secs: i64) -> InternalResult<Option<String>> {
+    let csrf_token = db.query_row(
+        "UPDATE sessions SET last_seen = strftime('%FT%TZ', CURRENT_TIMESTAMP)
+        WHERE token = ?1 AND NOT needs_second_factor AND last_seen > strftime('%FT%TZ', 'now', ?2)
+        RETURNING csrf_token",
+        (token, get_ttl_modifier(ttl_secs)),
+        |row| row.get(0),
+    ).optional()?;
+    Ok(csrf_token)
+}
+
+pub fn delete_session(db: &Connection, token: &str) -> InternalResult<()> {
+    db.execute("DELETE FROM sessions WHERE token = ?1", [token])?;
+    Ok(())
+}
+
+pub fn delete_all_sessions(db: &Connection) -> InternalResult<()> {
+    db.execute("DELETE FROM sessions", ())?;
+    Ok(())
+}
+
+pub fn is_valid_csrf_token(expected: &str, provided: Option<&str>) -> bool {
+    match provided {
+        Some(provided) => expected.len() == provided.len() && memcmp::eq(expected.as_bytes(), provided.as_bytes()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_csrf_tokens() {
+        let token = make_token();
+        assert!(is_valid_csrf_token(&token, Some(&token)));
+        assert!(!is_valid_csrf_token(&token, Some(&make_token())));
+        assert!(!is_valid_csrf_token(&token, Some("")));
+        assert!(!is_valid_csrf_token(&token, None));
+    }
+}
@@ -0,0 +1,129 @@
+use openssl::memcmp;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::server::error::bad_request;
+use crate::server::server_response::InternalResult;
+
+const CHALLENGE_LENGTH: usize = 24;
+const SETUP_TOKEN_LENGTH: usize = 24;
+
+/// Setup is complete once the wizard has saved a domain, the admin password and a profile
+pub fn is_setup_complete(db: &Connection) -> InternalResult<bool> {
+    let is_complete = db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM globals WHERE key = 'setup_completed_at')",
+        (),
+        |row| row.get(0),
+    )?;
+    Ok(is_complete)
+}
+
+pub fn mark_setup_complete(db: &Connection) -> InternalResult<()> {
+    db.execute(
+        "INSERT INTO globals (key, value) VALUES ('setup_completed_at', strftime('%FT%TZ', CURRENT_TIMESTAMP))",
+        (),
+    )?;
+    Ok(())
+}
+
+pub fn get_saved_domain(db: &Connection) -> InternalResult<Option<String>> {
+    let domain = db.query_row("SELECT value FROM globals WHERE key = 'domain'", (), |row| row.get(0))
+        .optional()?;
+    Ok(domain)
+}
+
+pub fn save_domain(db: &Connection, domain: &str) -> InternalResult<()> {
+    db.execute(
+        "INSERT INTO globals (key, value) VALUES ('domain', ?1)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        [domain],
+    )?;
+    Ok(())
+}
+
+/// Accept the domain the way people tend to paste it, but only keep the host (and port)
+pub fn normalize_domain(domain: &str) -> InternalResult<String> {
+    let domain = domain.trim().trim_end_matches('/');
+    let domain = domain.strip_prefix("https://").unwrap_or(domain).to_ascii_lowercase();
+
+    let is_valid = !domain.is_empty()
+        && !domain.starts_with(['.', '-', ':'])
+        && domain.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'));
+    if !is_valid {
+        return Err(bad_request("Enter a domain like example.com, without a path"));
+    }
+    Ok(domain)
+}
+
+/// The wizard proves the domain points at this server by looking this account up with webfinger
+pub fn create_domain_challenge(db: &Connection) -> InternalResult<String> {
+    let challenge = make_random_string(CHALLENGE_LENGTH);
+    db.execute(
+        "INSERT INTO globals (key, value) VALUES ('setup_challenge', ?1)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        [&challenge],
+    )?;
+    Ok(challenge)
+}
+
+pub fn is_domain_challenge(db: &Connection, account_name: &str) -> InternalResult<bool> {
+    let is_challenge = db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM globals WHERE key = 'setup_challenge' AND value = ?1)",
+        [account_name],
+        |row| row.get(0),
+    )?;
+    Ok(is_challenge)
+}
+
+pub fn clear_domain_challenge(db: &Connection) -> InternalResult<()> {
+    db.execute("DELETE FROM globals WHERE key = 'setup_challenge'", ())?;
+    Ok(())
+}
+
+/// Only someone who can read the server's log can finish the wizard, rather than whoever finds it first
+pub fn create_setup_token() -> String {
+    make_random_string(SETUP_TOKEN_LENGTH)
+}
+
+pub fn is_setup_token(expected: Option<&str>, token: &str) -> bool {
+    let token = token.trim();
+    match expected {
+        Some(expected) => expected.len() == token.len() && memcmp::eq(expected.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn make_random_string(length: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_domains() {
+        assert_eq!(normalize_domain("example.com").unwrap(), "example.com");
+        assert_eq!(normalize_domain(" https://Example.com/ ").unwrap(), "example.com");
+        assert_eq!(normalize_domain("localhost:8080").unwrap(), "localhost:8080");
+        assert!(normalize_domain("").is_err());
+        assert!(normalize_domain("http://example.com").is_err());
+        assert!(normalize_domain("example.com/profiles").is_err());
+        assert!(normalize_domain(".example.com").is_err());
+    }
+
+    #[test]
+    fn checks_setup_tokens() {
+        let token = create_setup_token();
+        assert!(is_setup_token(Some(&token), &token));
+        assert!(is_setup_token(Some(&token), &format!(" {}\n", token)));
+        assert!(!is_setup_token(Some(&token), &token[1..]));
+        assert!(!is_setup_token(Some(&token), ""));
+        assert!(!is_setup_token(None, ""));
+        assert!(!is_setup_token(Some(&token), &create_setup_token()));
+    }
+}
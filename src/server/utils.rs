@@ -1,6 +1,6 @@
 use crate::server::error::{map_bad_gateway, ServerError};
 use serde::Deserialize;
-use tracing::log::warn;
+use tracing::warn;
 
 pub fn deserialize_json<'a, T>(text: &'a str) -> Result<T, ServerError>
 where
@@ -1,17 +1,134 @@
 use rusqlite::{Connection, Error};
+use std::fmt::Display;
+use std::time::Duration;
+use tracing::{info, warn};
 
-pub fn initliaze_db(path: &str) -> Result<(), Error> {
-    let conn = get_conn(path)?;
-    let sql = include_str!("./db/migrations/0-init.sql");
-    conn.execute_batch(sql)?;
+pub mod pool;
+
+// Requests and background work each hold their own pooled connection, so writers need to wait their turn
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Every file in src/db/migrations, in order (see build.rs)
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(Error),
+    /// The database was migrated by a newer build, which this one can't safely run against
+    TooNew { db_version: usize, latest_version: usize },
+    /// A migration left rows that refer to rows which don't exist
+    ForeignKeyViolation { migration: String },
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Sqlite(e) => write!(f, "{}", e),
+            MigrationError::TooNew { db_version, latest_version } => write!(
+                f,
+                "Database is at schema version {}, but this build only knows up to version {}",
+                db_version, latest_version
+            ),
+            MigrationError::ForeignKeyViolation { migration } => {
+                write!(f, "Migration {} left rows that break their foreign keys", migration)
+            }
+        }
+    }
+}
+
+impl From<Error> for MigrationError {
+    fn from(err: Error) -> Self {
+        MigrationError::Sqlite(err)
+    }
+}
+
+/// Bring the database up to date, creating it if it doesn't exist
+pub fn migrate_db(path: &str) -> Result<(), MigrationError> {
+    let mut conn = get_conn(path)?;
+    run_migrations(&mut conn, MIGRATIONS)?;
     conn.close().map_err(|e| e.1)?;
     Ok(())
 }
 
+// user_version is the number of migrations that have been applied
+fn run_migrations(conn: &mut Connection, migrations: &[(&str, &str)]) -> Result<(), MigrationError> {
+    let mut version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    // Databases from before versioning have the initial schema, but no version to show for it
+    if version == 0 && has_table(conn, "profiles")? {
+        info!("Found an unversioned database; assuming it has the initial schema");
+        conn.pragma_update(None, "user_version", 1)?;
+        version = 1;
+    }
+
+    if version > migrations.len() {
+        return Err(MigrationError::TooNew { db_version: version, latest_version: migrations.len() });
+    }
+
+    // Rebuilding a table means dropping it, which would otherwise cascade to everything that refers to it.
+    // This can't be changed inside a transaction, so the keys are checked before each migration commits instead
+    let foreign_keys: bool = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let result = apply_migrations(conn, migrations, version);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    result
+}
+
+fn apply_migrations(conn: &mut Connection, migrations: &[(&str, &str)], version: usize) -> Result<(), MigrationError> {
+    for (index, (name, sql)) in migrations.iter().enumerate().skip(version) {
+        info!("Applying migration {}", name);
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        if has_foreign_key_violations(&tx)? {
+            return Err(MigrationError::ForeignKeyViolation { migration: name.to_string() });
+        }
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn has_foreign_key_violations(conn: &Connection) -> Result<bool, Error> {
+    let mut check = conn.prepare("PRAGMA foreign_key_check")?;
+    let has_violations = check.exists(())?;
+    Ok(has_violations)
+}
+
+/// An in-memory database with the full schema, for tests that run real queries
+#[cfg(test)]
+pub fn open_test_db() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "foreign_keys", "ON").unwrap();
+    run_migrations(&mut conn, MIGRATIONS).unwrap();
+    conn
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool, Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+}
+
+/// Fold the WAL back into the main database file, so it's complete on its own once we've exited
+pub fn checkpoint_wal(path: &str) -> Result<(), Error> {
+    let conn = get_conn(path)?;
+    // Returns (busy, pages in the log, pages checkpointed); busy means a connection was still open
+    let (busy, log_pages, checkpointed_pages): (bool, i64, i64) =
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    if busy {
+        warn!("Only checkpointed {} of {} WAL pages, since the database was still in use", checkpointed_pages, log_pages);
+    }
+    Ok(())
+}
+
 pub fn get_conn(path: &str) -> Result<Connection, Error> {
     let conn = Connection::open(path)?;
     conn.pragma_update(None, "journal_mode", "WAL")?;
     conn.pragma_update(None, "foreign_keys", "ON")?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
     Ok(conn)
 }
 
@@ -148,4 +265,101 @@ macro_rules! join_with_commas {
     };
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[(&str, &str)] = &[
+        ("0-init.sql", "CREATE TABLE profiles (profile_id INTEGER PRIMARY KEY);"),
+        ("1-add-name.sql", "ALTER TABLE profiles ADD COLUMN name TEXT;"),
+    ];
+
+    fn get_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
 
+    #[test]
+    fn applies_embedded_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn, MIGRATIONS).unwrap();
+        assert_eq!(get_version(&conn), MIGRATIONS.len());
+        assert!(has_table(&conn, "profiles").unwrap());
+    }
+
+    #[test]
+    fn only_applies_new_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn, &TEST_MIGRATIONS[..1]).unwrap();
+        assert_eq!(get_version(&conn), 1);
+
+        // Re-running 0-init.sql would fail, since the table already exists
+        run_migrations(&mut conn, TEST_MIGRATIONS).unwrap();
+        assert_eq!(get_version(&conn), 2);
+        run_migrations(&mut conn, TEST_MIGRATIONS).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_failed_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [TEST_MIGRATIONS[0], ("1-broken.sql", "CREATE TABLE posts (id); NOT SQL;")];
+        assert!(run_migrations(&mut conn, &migrations).is_err());
+        assert_eq!(get_version(&conn), 1);
+        assert!(!has_table(&conn, "posts").unwrap());
+    }
+
+    #[test]
+    fn rejects_migrations_that_break_foreign_keys() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            TEST_MIGRATIONS[0],
+            ("1-posts.sql", "CREATE TABLE posts (profile_id INTEGER REFERENCES profiles);
+                INSERT INTO posts (profile_id) VALUES (1);"),
+        ];
+        let result = run_migrations(&mut conn, &migrations);
+        assert!(matches!(result, Err(MigrationError::ForeignKeyViolation { .. })));
+        assert_eq!(get_version(&conn), 1);
+    }
+
+    #[test]
+    fn upgrades_the_initial_schema_with_its_data() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", "ON").unwrap();
+        // A database from before versioning, created straight from 0-init.sql
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        conn.execute_batch(
+            "INSERT INTO profiles (display_name, preferred_username, private_key_pem) VALUES ('Sailor', 'sailor', 'pem');
+            INSERT INTO posts (profile_id, content) VALUES (1, 'first'), (1, 'second');
+            INSERT INTO known_actors (actor_id, name, preferred_username) VALUES ('https://other.example/a', 'A', 'a');
+            INSERT INTO followers (profile_id, actor_id) VALUES (1, 'https://other.example/a'), (1, 'https://other.example/a');
+            INSERT INTO following (profile_id, actor_id) VALUES (1, 'https://other.example/a');
+            INSERT INTO sessions (token) VALUES ('old');",
+        ).unwrap();
+
+        run_migrations(&mut conn, MIGRATIONS).unwrap();
+        assert_eq!(get_version(&conn), MIGRATIONS.len());
+        let foreign_keys: bool = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0)).unwrap();
+        assert!(foreign_keys);
+
+        let count = |query: &str| -> i64 { conn.query_row(query, (), |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM posts"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM followers"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM following WHERE is_accepted"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM known_actors WHERE actor_json IS NULL"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM sessions"), 0);
+        // The domain from 0-init.sql is kept, since the server was already in use
+        assert_eq!(count("SELECT COUNT(*) FROM globals WHERE key = 'domain'"), 1);
+
+        // Deleted post ids aren't handed out again
+        conn.execute("DELETE FROM posts WHERE post_id = 2", ()).unwrap();
+        conn.execute("INSERT INTO posts (profile_id, content) VALUES (1, 'third')", ()).unwrap();
+        assert_eq!(count("SELECT MAX(post_id) FROM posts"), 3);
+    }
+
+    #[test]
+    fn refuses_newer_databases() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn, TEST_MIGRATIONS).unwrap();
+        let result = run_migrations(&mut conn, &TEST_MIGRATIONS[..1]);
+        assert!(matches!(result, Err(MigrationError::TooNew { db_version: 2, latest_version: 1 })));
+    }
+}
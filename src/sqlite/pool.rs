@@ -0,0 +1,133 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::Connection;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::server::error::service_unavailable;
+use crate::server::server_response::InternalResult;
+use crate::sqlite::get_conn;
+
+// Background work gets its own connections, so a backlog of deliveries can't keep requests waiting
+const BACKGROUND_CONNECTIONS: usize = 4;
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bounded set of connections to the database, opened as they're needed and reused after
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    path: String,
+    idle: Mutex<Vec<Connection>>,
+    requests: Arc<Semaphore>,
+    background: Arc<Semaphore>,
+}
+
+/// A connection borrowed from the pool, which goes back to it when dropped
+struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    pub fn new(path: &str, size: usize) -> Pool {
+        let inner = PoolInner {
+            path: path.to_owned(),
+            idle: Mutex::new(Vec::new()),
+            requests: Arc::new(Semaphore::new(size)),
+            background: Arc::new(Semaphore::new(BACKGROUND_CONNECTIONS)),
+        };
+        Pool { inner: Arc::new(inner) }
+    }
+
+    /// Run a request's database work on the blocking thread pool, so it doesn't hold up the executor
+    ///
+    /// The connection goes back to the pool as soon as the work is done, so nothing holds one
+    /// while it waits on the network.
+    pub async fn run<F, T>(&self, work: F) -> InternalResult<T>
+    where
+        F: FnOnce(&Connection) -> InternalResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_with(&self.inner.requests, work).await
+    }
+
+    /// Like run, but for work that isn't serving a request, such as deliveries
+    pub async fn run_background<F, T>(&self, work: F) -> InternalResult<T>
+    where
+        F: FnOnce(&Connection) -> InternalResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_with(&self.inner.background, work).await
+    }
+
+    async fn run_with<F, T>(&self, semaphore: &Arc<Semaphore>, work: F) -> InternalResult<T>
+    where
+        F: FnOnce(&Connection) -> InternalResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.acquire(semaphore).await?;
+        tokio::task::spawn_blocking(move || work(&conn)).await?
+    }
+
+    async fn acquire(&self, semaphore: &Arc<Semaphore>) -> InternalResult<PooledConnection> {
+        let permit = tokio::time::timeout(ACQUIRE_TIMEOUT, semaphore.clone().acquire_owned())
+            .await
+            .map_err(|_| service_unavailable("Timed out waiting for a database connection"))?
+            .map_err(|_| service_unavailable("The database connection pool was closed"))?;
+
+        let idle = self.inner.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => get_conn(&self.inner.path)?,
+        };
+
+        Ok(PooledConnection { conn: Some(conn), pool: self.inner.clone(), _permit: permit })
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("Pooled connection used after being returned")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+
+        // A connection left mid-transaction (e.g. by a panic) isn't safe to hand out again
+        if conn.is_autocommit() {
+            self.pool.idle.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reuses_returned_connections() {
+        let pool = Pool::new(":memory:", 1);
+        pool.run(|db| Ok(db.execute("CREATE TABLE marker (id INTEGER)", ())?)).await.unwrap();
+
+        // Every in-memory connection is its own database, so this only works on the same one
+        pool.run(|db| Ok(db.execute("INSERT INTO marker VALUES (1)", ())?)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_background_work_with_its_own_connections() {
+        let pool = Pool::new(":memory:", 1);
+        let _request_permit = pool.inner.requests.clone().acquire_owned().await.unwrap();
+        let answer = pool.run_background(|db| Ok(db.query_row("SELECT 6 * 7", (), |row| row.get::<_, i64>(0))?)).await;
+        assert_eq!(answer.unwrap(), 42);
+    }
+}
@@ -4,12 +4,15 @@ use chrono::{DateTime, Local};
 use minijinja::{Environment, Error};
 use tracing::warn;
 
+use crate::activitypub::content::is_http_url;
+
 pub mod _partials;
 
 pub fn load_env() -> Environment<'static> {
     let mut env = Environment::new();
     minijinja_embed::load_templates!(&mut env);
     env.add_function("iso_to_local", iso_to_local);
+    env.add_test("http_url", is_http_url);
     env
 }
 
@@ -29,3 +32,39 @@ fn iso_to_local(iso_time: String) -> Result<String, Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use minijinja::context;
+
+    use super::*;
+
+    #[test]
+    fn only_links_http_post_urls() {
+        let env = load_env();
+        let template = env.get_template("_partials/post.html").unwrap();
+        let post = context! {
+            object_id => "javascript:alert(1)",
+            in_reply_to => "javascript:alert(2)",
+            content => "",
+            created_at => "2024-01-01T00:00:00Z",
+            actor_name => "Alex",
+            actor_handle => "@alex@other.example",
+            attachments => Vec::<()>::new(),
+        };
+        let html = template.render(context! { post }).unwrap();
+        assert_eq!(html.matches("<a href=").count(), 0);
+
+        let post = context! {
+            object_id => "https://other.example/notes/1",
+            in_reply_to => "https://example.com/posts/1",
+            content => "",
+            created_at => "2024-01-01T00:00:00Z",
+            actor_name => "Alex",
+            actor_handle => "@alex@other.example",
+            attachments => Vec::<()>::new(),
+        };
+        let html = template.render(context! { post }).unwrap();
+        assert_eq!(html.matches("<a href=").count(), 2);
+    }
+}
@@ -1 +1,2 @@
+pub mod follow_button;
 pub mod post;
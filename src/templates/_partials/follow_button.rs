@@ -0,0 +1,38 @@
+use serde::Serialize;
+
+use crate::activitypub::objects::actor::Actor;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FollowStatus {
+    None,
+    Requested,
+    Following,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FollowButton {
+    pub id: String,
+    pub url: String,
+    pub name: String,
+    pub preferred_username: String,
+    pub inbox: String,
+    pub outbox: String,
+    pub summary: String,
+    pub status: FollowStatus,
+}
+
+impl FollowButton {
+    pub fn new(actor: &Actor, status: FollowStatus) -> FollowButton {
+        FollowButton {
+            id: actor.id.clone(),
+            url: actor.url.clone(),
+            name: actor.name.clone(),
+            preferred_username: actor.preferred_username.clone(),
+            inbox: actor.inbox.clone(),
+            outbox: actor.outbox.clone(),
+            summary: actor.summary.clone().unwrap_or_default(),
+            status,
+        }
+    }
+}
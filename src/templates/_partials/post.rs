@@ -1,11 +1,56 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::activitypub::content::is_http_url;
+use crate::activitypub::objects::note::Attachment;
 
 #[derive(Debug, Serialize)]
 pub struct Post {
-    pub post_id: i64,
+    pub post_id: Option<i64>, // Remote posts don't have a local ID
+    pub object_id: String,
+    pub in_reply_to: Option<String>,
     pub content: String,
     pub created_at: String,
     pub actor_name: String,
     pub actor_handle: String,
+    pub avi_url: Option<String>,
+    pub attachments: Vec<PostAttachment>,
+    pub profile_id: Option<i64>, // Set for local posts, where actor_id isn't
+    pub actor_id: Option<String>,
     pub is_owner: bool
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PostAttachment {
+    pub url: String,
+    pub media_type: Option<String>,
+    pub alt: Option<String>,
+}
+
+impl PostAttachment {
+    /// Keep the attachments from a remote note that are plain http(s) links
+    pub fn from_remote(attachments: Vec<Attachment>) -> Vec<PostAttachment> {
+        attachments.into_iter()
+            .filter(|a| is_http_url(&a.url))
+            .map(|a| PostAttachment { url: a.url, media_type: a.media_type, alt: a.name })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_non_http_remote_attachments() {
+        let attachments = serde_json::from_str(r#"[
+            { "type": "Document", "url": "javascript:alert(1)" },
+            { "type": "Document", "url": "JavaScript:alert(1)" },
+            { "type": "Document", "url": "data:image/png;base64,AAAA" },
+            { "type": "Document", "url": "HTTPS://example.com/a.png", "name": "a" }
+        ]"#).unwrap();
+        let attachments = PostAttachment::from_remote(attachments);
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].url, "HTTPS://example.com/a.png");
+        assert_eq!(attachments[0].alt.as_deref(), Some("a"));
+    }
+}
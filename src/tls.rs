@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use http_body_util::Empty;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HOST, LOCATION};
+use hyper::{Request, Response, StatusCode};
+use openssl::error::ErrorStack;
+use openssl::ssl::{self, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use tracing::{error, info};
+
+use crate::shutdown::Shutdown;
+
+// Offer HTTP/2 first, in ALPN's length-prefixed wire format
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Terminates TLS with the configured certificate, which can be swapped out while running
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    cert_path: String,
+    key_path: String,
+    acceptor: Arc<RwLock<Arc<SslAcceptor>>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, ErrorStack> {
+        let acceptor = build_acceptor(cert_path, key_path)?;
+        Ok(TlsAcceptor {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            acceptor: Arc::new(RwLock::new(Arc::new(acceptor))),
+        })
+    }
+
+    /// Read the certificate and key again, e.g. after they've been renewed; connections that are
+    /// already open keep the old ones
+    pub fn reload(&self) -> Result<(), ErrorStack> {
+        let acceptor = build_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(acceptor);
+        Ok(())
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>, Box<dyn Error + Send + Sync>> {
+        let acceptor = self.acceptor.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let ssl = Ssl::new(acceptor.context())?;
+        let mut stream = SslStream::new(ssl, stream)?;
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept()).await??;
+        Ok(stream)
+    }
+}
+
+fn build_acceptor(cert_path: &str, key_path: &str) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_alpn_select_callback(|_, client_protocols| {
+        ssl::select_next_proto(ALPN_PROTOCOLS, client_protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder.build())
+}
+
+/// Reload the certificate whenever we get a SIGHUP, which is how certbot & co. ask for it
+pub fn start_reloader(shutdown: &Shutdown, tls: TlsAcceptor) {
+    #[cfg(unix)]
+    shutdown.spawn(run_reloader(shutdown.clone(), tls));
+
+    #[cfg(not(unix))]
+    let _ = (shutdown, tls);
+}
+
+#[cfg(unix)]
+async fn run_reloader(shutdown: Shutdown, tls: TlsAcceptor) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => return error!("Unable to listen for SIGHUP, so certificates won't be reloaded: {}", e),
+    };
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => match tls.reload() {
+                Ok(()) => info!("Reloaded TLS certificate from {}", tls.cert_path),
+                // The old certificate stays in use, so a bad renewal doesn't take the server down
+                Err(e) => error!("Failed to reload TLS certificate, keeping the old one: {}", e),
+            },
+            _ = shutdown.requested() => return,
+        }
+    }
+}
+
+/// Send plain HTTP requests to the same path over HTTPS, on our own domain
+pub fn redirect_to_https(req: &Request<Incoming>, domain: Option<&str>, https_port: u16) -> Response<Empty<Bytes>> {
+    let host = req.headers().get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or(req.uri().host())
+        .unwrap_or_default();
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str());
+    let location = get_https_location(domain, host, https_port, path_and_query);
+
+    let mut res = Response::new(Empty::new());
+    match location.parse() {
+        Ok(location) => {
+            *res.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            res.headers_mut().insert(LOCATION, location);
+        }
+        Err(_) => *res.status_mut() = StatusCode::BAD_REQUEST,
+    }
+    res
+}
+
+fn get_https_location(domain: Option<&str>, host: &str, https_port: u16, path_and_query: Option<&str>) -> String {
+    // The domain is already where we're served over HTTPS, port and all
+    if let Some(domain) = domain {
+        return format!("https://{}{}", domain, path_and_query.unwrap_or("/"));
+    }
+
+    // There's no domain until setup is finished, and the wizard still has to be reachable
+    // Drop the plain HTTP port, but keep an IPv6 address's brackets
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => hostname,
+        _ => host,
+    };
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    format!("https://{}{}{}", hostname, port, path_and_query.unwrap_or("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_https_locations() {
+        assert_eq!(get_https_location(None, "example.com", 443, Some("/posts/1?x=y")), "https://example.com/posts/1?x=y");
+        assert_eq!(get_https_location(None, "example.com:80", 443, None), "https://example.com/");
+        assert_eq!(get_https_location(None, "example.com:8080", 8443, Some("/")), "https://example.com:8443/");
+        assert_eq!(get_https_location(None, "[::1]:80", 8443, Some("/")), "https://[::1]:8443/");
+        assert_eq!(get_https_location(None, "[::1]", 443, Some("/")), "https://[::1]/");
+    }
+
+    #[test]
+    fn redirects_to_our_own_domain() {
+        let location = get_https_location(Some("example.com"), "evil.example", 8443, Some("/posts/1"));
+        assert_eq!(location, "https://example.com/posts/1");
+        assert_eq!(get_https_location(Some("localhost:8443"), "127.0.0.1:80", 443, None), "https://localhost:8443/");
+    }
+}